@@ -1,41 +1,78 @@
+use clap::{CommandFactory, Parser};
 use log::info;
-use rbxlx_to_rojo::{filesystem::FileSystem, process_instructions};
+use rbxlx_to_rojo::{
+    check_tree, csv_field,
+    decode::{decode_file, without_gz_extension, DecodedFile, PlaceFormat},
+    filesystem::{DryRun, FileSystem, Memory, ParallelBackend, Zip},
+    plan_instructions, process_instructions_parallel_with_progress,
+    process_instructions_with_progress, sanitize_instance_name,
+    structures::{
+        to_string_indented, ClassStrategy, ConversionSummary, ConvertOptions, Instruction,
+        InstructionReader, NameNormalization, ProgressEvent, Timings,
+    },
+    tree_listing, ConvertError,
+};
+use serde::Serialize;
 use std::{
-    borrow::Cow,
+    collections::BTreeMap,
     fmt, fs,
-    io::{self, BufReader, Read, Write},
-    path::PathBuf,
-    sync::{Arc, RwLock},
+    io::{self, BufReader, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant},
 };
-use regex::Regex;
+
+const GITIGNORE: &str = "*.rbxl\n*.rbxlx\nrbxlx-to-rojo.log\n";
+
+const SELENE_TOML: &str = "std = \"roblox\"\n";
+
+// How many times a file or folder dialog is opened before cancelling it gives up
+const DIALOG_ATTEMPTS: usize = 3;
+
+// Logged to for lines that only show how far along something is, see `WrappedLogger`
+const PROGRESS_TARGET: &str = concat!(module_path!(), "::progress");
 
 #[derive(Debug)]
-enum Problem {
-    BinaryDecodeError(rbx_binary::DecodeError),
-    InvalidFile,
+enum Problem {
+    ArgumentError(clap::Error),
+    ConvertError(ConvertError),
     IoError(&'static str, io::Error),
+    MissingArgument(&'static str),
     NFDCancel,
     NFDError(String),
-    XMLDecodeError(rbx_xml::DecodeError),
+    OutputExists(PathBuf),
+    SymlinkedOutput(PathBuf),
+    DownloadError { url: String, reason: String },
+    Declined,
+    BatchFailed { failed: usize, total: usize },
 }
 
 impl fmt::Display for Problem {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Problem::BinaryDecodeError(error) => write!(
+            Problem::ArgumentError(error) => write!(formatter, "{}", error),
+
+            Problem::ConvertError(error @ ConvertError::EmptyTree { .. }) => write!(
                 formatter,
-                "While attempting to decode the place file, at {} rbx_binary didn't know what to do",
+                "{}. Pass a lower --min-instances if that's intended",
                 error,
             ),
 
-            Problem::InvalidFile => {
-                write!(formatter, "The file provided does not have a recognized file extension")
-            }
+            Problem::ConvertError(error) => write!(formatter, "{}", error),
 
             Problem::IoError(doing_what, error) => {
                 write!(formatter, "While attempting to {}, {}", doing_what, error)
             }
 
+            Problem::MissingArgument(argument) => {
+                write!(formatter, "Expected a value for {}", argument)
+            }
+
             Problem::NFDCancel => write!(formatter, "Didn't choose a file."),
 
             Problem::NFDError(error) => write!(
@@ -44,18 +81,120 @@ impl fmt::Display for Problem {
                 error,
             ),
 
-            Problem::XMLDecodeError(error) => write!(
+            Problem::OutputExists(path) => write!(
                 formatter,
-                "While attempting to decode the place file, at {} rbx_xml didn't know what to do",
-                error,
+                "{} already exists and isn't empty, pass --force to overwrite it",
+                path.display(),
+            ),
+
+            Problem::SymlinkedOutput(path) => write!(
+                formatter,
+                "{} is a symlink leading outside of the output folder, pass --follow-symlinks to \
+                 write through it anyway",
+                path.display(),
+            ),
+
+            Problem::DownloadError { url, reason } => {
+                write!(formatter, "Couldn't download {}: {}", url, reason)
+            }
+
+            Problem::Declined => write!(formatter, "Didn't go ahead with the conversion."),
+
+            Problem::BatchFailed { failed, total } => write!(
+                formatter,
+                "{} of {} files couldn't be converted, the log says why",
+                failed, total,
             ),
         }
     }
 }
 
+impl From<ConvertError> for Problem {
+    fn from(error: ConvertError) -> Self {
+        Problem::ConvertError(error)
+    }
+}
+
+impl Problem {
+    /// The code the process exits with for this problem:
+    ///
+    /// - 0: the file dialog was cancelled, the plan wasn't confirmed, or --help/--version was
+    ///   asked for
+    /// - 2: the file isn't recognizable as a place or model
+    /// - 3: reading or writing a file failed
+    /// - 4: rbx_xml couldn't decode the file, or its text encoding couldn't be read
+    /// - 5: rbx_binary couldn't decode the file
+    /// - 6: the arguments were invalid or incomplete, or nothing was at --root-path
+    /// - 7: the file dialog failed
+    /// - 8: the project folder already exists
+    /// - 9: the file had fewer instances than --min-instances
+    /// - 10: some of the files given couldn't be converted
+    /// - 11: the output folder is or has a symlink leading outside of it
+    /// - 12: the place file couldn't be downloaded
+    fn exit_code(&self) -> i32 {
+        match self {
+            Problem::ArgumentError(error) if !error.use_stderr() => 0,
+            Problem::NFDCancel | Problem::Declined => 0,
+            Problem::ConvertError(ConvertError::UnrecognizedFormat) => 2,
+            Problem::IoError(_, _) | Problem::ConvertError(ConvertError::Io(_)) => 3,
+            Problem::ConvertError(ConvertError::XmlDecode(..))
+            | Problem::ConvertError(ConvertError::UnsupportedEncoding(_)) => 4,
+            Problem::ConvertError(ConvertError::BinaryDecode(_)) => 5,
+            Problem::ArgumentError(_)
+            | Problem::MissingArgument(_)
+            | Problem::ConvertError(ConvertError::InvalidRename { .. })
+            | Problem::ConvertError(ConvertError::NameCollision { .. })
+            | Problem::ConvertError(ConvertError::RootPathNotFound { .. }) => 6,
+            Problem::NFDError(_) => 7,
+            Problem::OutputExists(_) => 8,
+            Problem::ConvertError(ConvertError::EmptyTree { .. }) => 9,
+            Problem::BatchFailed { .. } => 10,
+            Problem::SymlinkedOutput(_) => 11,
+            Problem::DownloadError { .. } => 12,
+        }
+    }
+}
+
+/// One file's entry in what --stdout-summary prints.
+#[derive(Serialize)]
+struct FileReport {
+    file: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<ConversionSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl FileReport {
+    fn new(file: String, result: &Result<ConversionSummary, Problem>) -> Self {
+        match result {
+            Ok(summary) => Self {
+                file,
+                summary: Some(summary.clone()),
+                error: None,
+            },
+            Err(error) => Self {
+                file,
+                summary: None,
+                error: Some(error.to_string()),
+            },
+        }
+    }
+}
+
 struct WrappedLogger {
     log: env_logger::Logger,
-    log_file: Arc<RwLock<Option<fs::File>>>,
+    log_file: Arc<Mutex<Option<fs::File>>>,
+    // Whether anything was worth keeping the log for, see --keep-log-on-success
+    warned: Arc<AtomicBool>,
+    // Whether the last thing on stderr is a progress line that hasn't been ended yet
+    progress_shown: Mutex<bool>,
+}
+
+// A thread that panicked while logging can't have left the file in a worse state than a
+// half-written line, so the log keeps going
+fn lock_log_file(log_file: &Mutex<Option<fs::File>>) -> MutexGuard<'_, Option<fs::File>> {
+    log_file.lock().unwrap_or_else(PoisonError::into_inner)
 }
 
 impl log::Log for WrappedLogger {
@@ -65,268 +204,1497 @@ impl log::Log for WrappedLogger {
 
     fn log(&self, record: &log::Record) {
         if self.enabled(record.metadata()) {
+            // Progress lines are written over each other on a terminal and left out of the log
+            // file, and anything else starts on a line of its own after them
+            let mut progress_shown = self
+                .progress_shown
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if record.target() == PROGRESS_TARGET {
+                if io::stderr().is_terminal() {
+                    eprint!("\r{}", record.args());
+                    *progress_shown = true;
+                }
+                return;
+            }
+
+            if std::mem::take(&mut *progress_shown) {
+                eprintln!();
+            }
             self.log.log(record);
 
-            if let Some(ref mut log_file) = &mut *self.log_file.write().unwrap() {
-                log_file
-                    .write(format!("{}\r\n", record.args()).as_bytes())
-                    .ok();
+            if record.level() <= log::Level::Warn {
+                self.warned.store(true, Ordering::Relaxed);
+            }
+
+            // Formatted before locking and written in one go, so lines logged from several
+            // threads at once don't end up interleaved
+            let line = format!("{}\r\n", record.args());
+            if let Some(log_file) = &mut *lock_log_file(&self.log_file) {
+                log_file.write_all(line.as_bytes()).ok();
             }
         }
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        let mut progress_shown = self
+            .progress_shown
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if std::mem::take(&mut *progress_shown) {
+            eprintln!();
+        }
+
+        if let Some(log_file) = &mut *lock_log_file(&self.log_file) {
+            log_file.flush().ok();
+        }
+    }
 }
 
-fn is_valid_xml_codepoint(code: u32) -> bool {
-    match code {
-        0x9 | 0xA | 0xD => true,
-        0x20..=0xD7FF => true,
-        0xE000..=0xFFFD => true,
-        0x10000..=0x10FFFF => true,
-        _ => false,
-    }
+/// Logs how far into the place file decoding has gotten.
+struct ProgressReader<R> {
+    inner: R,
+    read: u64,
+    total: u64,
+    last_logged: u64,
 }
 
-fn sanitize_xml(text: &mut String) -> bool {
-    if text.chars().all(|c| is_valid_xml_codepoint(c as u32)) {
-        return false;
+impl<R> ProgressReader<R> {
+    fn new(inner: R, total: u64) -> Self {
+        Self {
+            inner,
+            read: 0,
+            total,
+            last_logged: 0,
+        }
     }
+}
 
-    let mut cleaned = String::with_capacity(text.len());
-    for ch in text.chars() {
-        if is_valid_xml_codepoint(ch as u32) {
-            cleaned.push(ch);
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buffer)?;
+        self.read += read as u64;
+
+        if self.total > 0 {
+            let percent = self.read * 100 / self.total;
+            if percent >= self.last_logged + 10 {
+                self.last_logged = percent - percent % 10;
+                info!("Read {}% of the place file", self.last_logged);
+            }
         }
+
+        Ok(read)
     }
+}
+
+/// Converts a Roblox place or model file into a Rojo project.
+#[derive(Parser)]
+#[command(version)]
+struct Arguments {
+    /// The place or model files to convert, each into its own project. Without --output, a last
+    /// path that looks like a folder is the folder to put them in. Picked with a file dialog if
+    /// left out.
+    #[arg(value_name = "PATHS")]
+    paths: Vec<PathBuf>,
+
+    /// The folder to put the Rojo projects in, picked with a folder dialog if left out.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// The name of the project, and of its folder. Defaults to the input's file name.
+    #[arg(long, value_name = "NAME")]
+    project_name: Option<String>,
+
+    /// Never open a file dialog, erroring out on missing paths instead.
+    #[arg(long)]
+    no_gui: bool,
+
+    /// Don't ask before writing, which otherwise happens when the paths came from dialogs.
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Hold every file back until the end and write them all at once, which is much quicker on
+    /// slow file systems like network drives.
+    #[arg(long)]
+    buffered: bool,
+
+    /// Log what would be written without touching the disk.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Print the instances in the file as an indented tree instead of converting it, down to
+    /// --max-depth if given. Every path is taken as an input. The listing goes to stdout, so it
+    /// can't be used with --stdout-summary.
+    #[arg(long, conflicts_with = "stdout_summary")]
+    list: bool,
+
+    /// Write the project into this zip archive instead of a folder, laid out the same way. The
+    /// log and repairs.json still go in the output folder.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["dry_run", "git"])]
+    zip: Option<PathBuf>,
+
+    /// Write into the project folder even if it isn't empty.
+    #[arg(long)]
+    force: bool,
+
+    /// Write through symlinks in the output folder that lead outside of it, which is refused
+    /// otherwise.
+    #[arg(long)]
+    follow_symlinks: bool,
+
+    /// Convert into an existing project, only writing the files that changed, and report which
+    /// files the place doesn't have anymore.
+    #[arg(long, conflicts_with_all = ["dry_run", "zip"])]
+    merge: bool,
+
+    /// Read every file back after writing it and fail if its hash doesn't match, for unreliable
+    /// storage like network drives. Slower, since everything written is read again.
+    #[arg(long, conflicts_with_all = ["dry_run", "zip"])]
+    verify: bool,
+
+    /// With --merge, delete the files the place doesn't have anymore.
+    #[arg(long, requires = "merge")]
+    prune: bool,
+
+    /// Keep running after converting, and convert the file again with --merge whenever it
+    /// changes. Ctrl-C stops watching.
+    #[arg(long, conflicts_with_all = ["dry_run", "zip"])]
+    watch: bool,
+
+    /// How many threads to convert with.
+    #[arg(long, default_value_t = 1, value_parser = parse_jobs)]
+    jobs: usize,
+
+    /// Write scripts as .luau instead of .lua.
+    #[arg(long)]
+    luau: bool,
+
+    /// What to replace characters that can't be in file names with.
+    #[arg(long, default_value_t = '_')]
+    name_substitute: char,
+
+    /// Put instance names in this Unicode normalization form before they become paths, so
+    /// accented names match across OSes.
+    #[arg(long, default_value = "none", value_parser = ["nfc", "nfd", "none"])]
+    normalize_names: String,
+
+    /// Write StringValues as .txt files.
+    #[arg(long = "stringvalue-as-txt")]
+    string_values_as_txt: bool,
+
+    /// Write plain value instances like IntValues as .model.json files.
+    #[arg(long)]
+    json_models: bool,
+
+    /// Write the contents of a model's only top level instance straight into the source folder.
+    #[arg(long)]
+    flatten_services: bool,
+
+    /// Write an assets.json listing every asset the file uses.
+    #[arg(long)]
+    assets_manifest: bool,
+
+    /// Add a row about this conversion to a CSV file, creating it if it doesn't exist. Left
+    /// alone on a dry run.
+    #[arg(long, value_name = "PATH")]
+    csv_summary: Option<PathBuf>,
+
+    /// Stop this many levels below the top of the tree, writing what's left as .rbxmx models.
+    /// 0 only walks the top level.
+    #[arg(long, value_name = "N")]
+    max_depth: Option<usize>,
+
+    /// Only convert the instance at this full name, like ReplicatedStorage.Shared, and what's
+    /// inside it, making it the root of the project.
+    #[arg(long, value_name = "INSTANCE_PATH")]
+    root_path: Option<String>,
+
+    /// Write every distinct SharedString in a model file, like mesh and texture data, once into a
+    /// shared folder along with an index of which instances use which. The models hold the path
+    /// to it in place of the data.
+    #[arg(long)]
+    dedupe_shared_strings: bool,
+
+    /// Write ModuleScripts that only return a JSON literal as .json files.
+    #[arg(long)]
+    detect_json_modules: bool,
+
+    /// Write anything that has to be a model as a binary .rbxm file instead of .rbxmx.
+    #[arg(long)]
+    binary_fallback: bool,
+
+    /// What to name .rbxmx models with.
+    #[arg(long, default_value = ".rbxmx", value_parser = [".rbxmx", ".model.rbxmx"])]
+    model_extension: String,
+
+    /// Write an empty instance of the class instead of any model bigger than this many bytes,
+    /// so huge models don't end up in the project by surprise.
+    #[arg(long, value_name = "BYTES")]
+    max_file_bytes: Option<usize>,
+
+    /// Only make a folder with an init.meta.json for each service, as a skeleton to start a
+    /// project from. Unlike --max-depth 0, nothing inside the services is kept and empty services
+    /// get folders too.
+    #[arg(long, conflicts_with = "max_depth")]
+    services_only: bool,
+
+    /// Write a starter wally.toml if ReplicatedStorage has packages installed by Wally.
+    #[arg(long)]
+    wally: bool,
+
+    /// Keep identifiers like ScriptGuid and UniqueId in meta files so they survive a round trip.
+    #[arg(long)]
+    preserve_guids: bool,
+
+    /// How many spaces to indent the JSON files written with, or `tab` for tabs.
+    #[arg(long, default_value = "2", value_name = "SPACES|tab", value_parser = parse_indent)]
+    indent: String,
+
+    /// Write a .gitignore for the project and run `git init` in it.
+    #[arg(long)]
+    git: bool,
+
+    /// Write a selene.toml using the Roblox standard library and a .luaurc turning on strict type
+    /// checking, unless the project already has them.
+    #[arg(long)]
+    lint_config: bool,
+
+    /// Write an unsupported.json listing the classes that had to be written as models or left
+    /// out, with how many of each and a few examples.
+    #[arg(long)]
+    report_unsupported: bool,
+
+    /// Write a sourcemap.json for tools like luau-lsp.
+    #[arg(long)]
+    sourcemap: bool,
+
+    /// Turn CRLF and lone CR line endings in scripts into LF.
+    #[arg(long)]
+    normalize_newlines: bool,
+
+    /// End every non-empty script with a newline.
+    #[arg(long)]
+    trailing_newline: bool,
+
+    /// Only write instances of this class, along with the folders needed to reach them.
+    /// Can be given more than once.
+    #[arg(long = "include-class", value_name = "CLASS")]
+    include_classes: Vec<String>,
+
+    /// Only write scripts, along with the folders needed to reach them, same as including
+    /// Script, LocalScript, and ModuleScript.
+    #[arg(long, conflicts_with = "include_classes")]
+    only_scripts: bool,
+
+    /// Never write instances of this class or anything inside them, even if they're included.
+    /// Can be given more than once.
+    #[arg(long = "exclude-class", value_name = "CLASS")]
+    exclude_classes: Vec<String>,
 
-    *text = cleaned;
-    true
+    /// Only leave out the excluded instances themselves, still writing what's inside them.
+    #[arg(long)]
+    keep_excluded_descendants: bool,
+
+    /// Write instances of this class even though Rojo projects usually leave them out, which
+    /// Camera, Terrain, and PackageLink are by default. Can be given more than once.
+    #[arg(long = "keep-class", value_name = "CLASS")]
+    keep_classes: Vec<String>,
+
+    /// A JSON file of instances to write somewhere else, mapping full names like
+    /// `ReplicatedStorage.Foo` to paths in the source folder like `Shared/Foo`.
+    #[arg(long, value_name = "FILE")]
+    rename_map: Option<PathBuf>,
+
+    // Read from --rename-map once the arguments are parsed
+    #[arg(skip)]
+    renames: BTreeMap<String, PathBuf>,
+
+    /// A JSON file of how to write instances of particular classes, mapping class names to
+    /// `txt`, `json-model`, `rbxmx`, `csv`, or `lua`.
+    #[arg(long, value_name = "FILE")]
+    class_extension_map: Option<PathBuf>,
+
+    // Read from --class-extension-map once the arguments are parsed
+    #[arg(skip)]
+    class_strategies: BTreeMap<String, ClassStrategy>,
+
+    /// Don't replace NaN and infinity literals rbx_xml can't read.
+    #[arg(long)]
+    no_sanitize_floats: bool,
+
+    /// Don't strip numeric character references to characters XML doesn't allow.
+    #[arg(long)]
+    no_sanitize_char_refs: bool,
+
+    /// Don't strip characters XML doesn't allow.
+    #[arg(long)]
+    no_sanitize_codepoints: bool,
+
+    /// Don't cut off junk before the first <roblox or after the last </roblox>.
+    #[arg(long)]
+    no_sanitize_outside_root: bool,
+
+    /// Leave null bytes and byte order marks in script sources as they are.
+    #[arg(long)]
+    no_sanitize_scripts: bool,
+
+    /// Hand the file to rbx_xml exactly as it is, same as passing every --no-sanitize-* flag.
+    #[arg(long)]
+    no_sanitize: bool,
+
+    /// Log more detail, pass twice for even more. RUST_LOG takes precedence if it's set.
+    #[arg(short, long, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only log warnings, pass twice for only errors.
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    quiet: u8,
+
+    /// Where to write the log, rbxlx-to-rojo.log in the output folder by default.
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Pass false to delete the log once everything's converted without a single warning.
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set, value_name = "BOOL")]
+    keep_log_on_success: bool,
+
+    /// Error out if the file has fewer instances than this, since it's probably corrupt.
+    #[arg(long, default_value_t = 1, value_name = "COUNT")]
+    min_instances: usize,
+
+    /// Print a summary of the conversion to stdout in this format, keeping logs on stderr.
+    #[arg(long, value_name = "FORMAT", value_parser = ["json"])]
+    stdout_summary: Option<String>,
+
+    /// Log how long decoding, sanitizing, and writing each took, to see which is slow.
+    #[arg(long)]
+    timing: bool,
+
+    /// Read the file from stdin in this format, used when the input is `-` or left out.
+    #[arg(long, value_parser = ["rbxlx", "rbxmx", "rbxl", "rbxm"])]
+    stdin_format: Option<String>,
 }
 
-fn strip_invalid_numeric_char_refs(text: &mut String) -> bool {
-    // Matches both decimal and hex numeric character references.
-    lazy_static::lazy_static! {
-        static ref NUMERIC_CHAR_REF_RE: Regex = Regex::new(r"&#(x[0-9A-Fa-f]+|[0-9]+);").unwrap();
+impl Arguments {
+    fn log_level(&self) -> log::LevelFilter {
+        match (self.verbose, self.quiet) {
+            (0, 0) => log::LevelFilter::Info,
+            (1, _) => log::LevelFilter::Debug,
+            (_, 0) => log::LevelFilter::Trace,
+            (_, 1) => log::LevelFilter::Warn,
+            _ => log::LevelFilter::Error,
+        }
     }
 
-    let mut changed = false;
-    let replaced = NUMERIC_CHAR_REF_RE.replace_all(text, |caps: &regex::Captures| {
-        let raw = &caps[1];
-        let value = if raw.starts_with('x') || raw.starts_with('X') {
-            u32::from_str_radix(&raw[1..], 16).ok()
-        } else {
-            raw.parse::<u32>().ok()
-        };
+    fn options(&self) -> ConvertOptions {
+        ConvertOptions {
+            luau: self.luau,
+            name_substitute: self.name_substitute,
+            normalize_names: match self.normalize_names.as_str() {
+                "nfc" => NameNormalization::Nfc,
+                "nfd" => NameNormalization::Nfd,
+                _ => NameNormalization::None,
+            },
+            string_values_as_txt: self.string_values_as_txt,
+            sourcemap: self.sourcemap,
+            normalize_newlines: self.normalize_newlines,
+            trailing_newline: self.trailing_newline,
+            include_classes: if self.only_scripts {
+                ["Script", "LocalScript", "ModuleScript"]
+                    .iter()
+                    .map(|class| class.to_string())
+                    .collect()
+            } else {
+                self.include_classes.clone()
+            },
+            exclude_classes: self.exclude_classes.clone(),
+            exclude_descendants: !self.keep_excluded_descendants,
+            skip_classes: ConvertOptions::default()
+                .skip_classes
+                .into_iter()
+                .filter(|class| !self.keep_classes.contains(class))
+                .collect(),
+            sanitize_floats: !self.no_sanitize && !self.no_sanitize_floats,
+            sanitize_char_refs: !self.no_sanitize && !self.no_sanitize_char_refs,
+            sanitize_codepoints: !self.no_sanitize && !self.no_sanitize_codepoints,
+            sanitize_outside_root: !self.no_sanitize && !self.no_sanitize_outside_root,
+            sanitize_script_sources: !self.no_sanitize_scripts,
+            json_models: self.json_models,
+            flatten_services: self.flatten_services,
+            assets_manifest: self.assets_manifest,
+            max_depth: self.max_depth,
+            dedupe_shared_strings: self.dedupe_shared_strings,
+            detect_json_modules: self.detect_json_modules,
+            binary_fallback: self.binary_fallback,
+            model_extension: self.model_extension.clone(),
+            services_only: self.services_only,
+            json_indent: self.indent.clone(),
+            wally: self.wally,
+            preserve_guids: self.preserve_guids,
+            renames: self.renames.clone(),
+            min_instances: self.min_instances,
+            max_file_bytes: self.max_file_bytes,
+            class_strategies: self.class_strategies.clone(),
+            root_path: self.root_path.clone(),
+        }
+    }
+}
 
-        match value {
-            Some(code) if is_valid_xml_codepoint(code) => caps[0].to_string(),
-            _ => {
-                changed = true;
-                String::new()
+/// Opens a dialog again if it's cancelled, in case that was a misclick. Only gives up
+/// after it's been cancelled `DIALOG_ATTEMPTS` times.
+fn prompt(mut open: impl FnMut() -> nfd::Result<nfd::Response>) -> Result<String, Problem> {
+    for attempt in 1..=DIALOG_ATTEMPTS {
+        match open().map_err(|error| Problem::NFDError(error.to_string()))? {
+            nfd::Response::Okay(path) => return Ok(path),
+            nfd::Response::Cancel if attempt < DIALOG_ATTEMPTS => {
+                info!("Nothing was chosen, asking again.")
             }
+            nfd::Response::Cancel => {}
+            _ => unreachable!(),
         }
-    });
-
-    if changed {
-        *text = replaced.into_owned();
     }
 
-    changed
+    Err(Problem::NFDCancel)
 }
 
-fn replace_invalid_float_literals(text: &mut String) -> bool {
-    lazy_static::lazy_static! {
-        static ref INVALID_FLOAT_TOKEN_RE: Regex = Regex::new(
-            r"(?i)(-?nan(?:\\([^)]*\\))?|1\\.\\#(?:inf|ind|qnan|nan)|-?inf)"
-        )
-        .unwrap();
-        static ref FLOAT_FIELD_RE: Regex = Regex::new(
-            r"(>\\s*)(-?[0-9]+(?:\\.[0-9]+)?(?:[eE][+-]?[0-9]+)?|[^<\\s]+)(\\s*<)"
-        )
-        .unwrap();
-        static ref NUMBER_SEQUENCE_RE: Regex = Regex::new(
-            r"(<NumberSequence[^>]*>)([^<]+)(</NumberSequence>)"
-        )
-        .unwrap();
-        static ref NUMBER_RANGE_RE: Regex = Regex::new(
-            r"(<NumberRange[^>]*>)([^<]+)(</NumberRange>)"
-        )
-        .unwrap();
+fn parse_jobs(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(0) | Err(_) => Err(format!("{} isn't a positive number of threads", value)),
+        Ok(jobs) => Ok(jobs),
     }
+}
 
-    let mut changed = false;
+fn parse_indent(value: &str) -> Result<String, String> {
+    if value == "tab" {
+        return Ok(String::from("\t"));
+    }
 
-    // Replace obvious tokens first.
-    if INVALID_FLOAT_TOKEN_RE.is_match(text) {
-        let replaced = INVALID_FLOAT_TOKEN_RE.replace_all(text, "0");
-        *text = replaced.into_owned();
-        changed = true;
+    match value.parse() {
+        Ok(spaces) => Ok(" ".repeat(spaces)),
+        Err(_) => Err(format!("{} isn't a number of spaces or `tab`", value)),
     }
+}
 
-    // Normalize any non-parsable tokens inside NumberSequence/NumberRange elements to 0.
-    let normalize_list = |list: &str| -> String {
-        list.split_whitespace()
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-            .map(|s| s.parse::<f64>().ok().map(|v| v.to_string()).unwrap_or_else(|| "0".to_string()))
-            .collect::<Vec<_>>()
-            .join(" ")
-    };
+// Windows shells leave patterns like `models/*.rbxmx` for the program to expand
+fn expand_globs(inputs: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut expanded = Vec::with_capacity(inputs.len());
+
+    for input in inputs {
+        let pattern = input.to_string_lossy().into_owned();
+        if input.exists() || input_url(&input).is_some() || !pattern.contains(&['*', '?', '['][..])
+        {
+            expanded.push(input);
+            continue;
+        }
 
-    let replaced_ns = NUMBER_SEQUENCE_RE.replace_all(text, |caps: &regex::Captures| {
-        changed = true;
-        format!("{}{}{}", &caps[1], normalize_list(&caps[2]), &caps[3])
-    });
-    *text = replaced_ns.into_owned();
+        let matched: Vec<PathBuf> = match glob::glob(&pattern) {
+            Ok(paths) => paths.filter_map(Result::ok).collect(),
+            Err(error) => {
+                log::warn!(
+                    "{} isn't a valid pattern ({}), using it as a path",
+                    pattern,
+                    error
+                );
+                Vec::new()
+            }
+        };
 
-    let replaced_nr = NUMBER_RANGE_RE.replace_all(text, |caps: &regex::Captures| {
-        changed = true;
-        format!("{}{}{}", &caps[1], normalize_list(&caps[2]), &caps[3])
-    });
-    *text = replaced_nr.into_owned();
+        info!("{} matched {} files", pattern, matched.len());
+        if matched.is_empty() {
+            expanded.push(input);
+        } else {
+            expanded.extend(matched);
+        }
+    }
 
-    changed
+    expanded
 }
 
-fn protect_shared_sections(text: &str) -> (String, Vec<String>) {
-    lazy_static::lazy_static! {
-        static ref PROTECTED_RE: Regex =
-            Regex::new(r"(?is)<(?:SharedString|BinaryString)[^>]*>.*?</(?:SharedString|BinaryString)>")
-                .unwrap();
+/// Where the folder picker starts, which is the folder the file is in. A bare file name has an
+/// empty parent that the dialog won't take, so that's the working directory instead.
+fn picker_start(file_path: &Path) -> PathBuf {
+    match file_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() && input_url(file_path).is_none() => {
+            parent.to_path_buf()
+        }
+        _ => std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")),
     }
+}
 
-    let mut protected: Vec<String> = Vec::new();
-    let mut result = String::with_capacity(text.len());
-    let mut last = 0;
-    for (idx, mat) in PROTECTED_RE.find_iter(text).enumerate() {
-        result.push_str(&text[last..mat.start()]);
-        let placeholder = format!("__RBX_PROTECTED_CHUNK_{}__", idx);
-        protected.push(text[mat.start()..mat.end()].to_string());
-        result.push_str(&placeholder);
-        last = mat.end();
+// A folder, or a path that isn't there yet without a place file's extension or a glob pattern
+fn looks_like_folder(path: &Path) -> bool {
+    if path.as_os_str() == "-" || input_url(path).is_some() {
+        return false;
     }
-    result.push_str(&text[last..]);
 
-    (result, protected)
+    path.is_dir()
+        || (!path.exists()
+            && !path.to_string_lossy().contains(&['*', '?', '['][..])
+            && plain_input_path(path)
+                .extension()
+                .and_then(|extension| PlaceFormat::from_extension(&extension.to_string_lossy()))
+                .is_none())
 }
 
-fn restore_shared_sections(text: &mut String, protected: Vec<String>) {
-    lazy_static::lazy_static! {
-        static ref PLACEHOLDER_RE: Regex =
-            Regex::new(r"__RBX_PROTECTED_CHUNK_(\d+)__").unwrap();
-    }
+// Inputs starting with http:// or https:// are downloaded instead of opened
+fn input_url(path: &Path) -> Option<&str> {
+    path.to_str()
+        .filter(|path| path.starts_with("http://") || path.starts_with("https://"))
+}
 
-    let replaced = PLACEHOLDER_RE.replace_all(text, |caps: &regex::Captures| {
-        let idx: usize = caps[1].parse().unwrap_or(usize::MAX);
-        protected.get(idx).cloned().unwrap_or_default()
-    });
-    *text = replaced.into_owned();
+// Whether there's anything in `path` besides the log and repairs.json, which are already
+// there when the output folder is the project
+fn is_non_empty_dir(path: &Path, log_path: Option<&Path>) -> bool {
+    fs::read_dir(path)
+        .map(|entries| {
+            entries.filter_map(Result::ok).any(|entry| {
+                Some(entry.path().as_path()) != log_path && entry.file_name() != "repairs.json"
+            })
+        })
+        .unwrap_or(false)
 }
 
-fn routine() -> Result<(), Problem> {
-    let env_logger = env_logger::Builder::new()
-        .filter_level(log::LevelFilter::Info)
-        .build();
-
-    let log_file = Arc::new(RwLock::new(None));
-    let logger = WrappedLogger {
-        log: env_logger,
-        log_file: Arc::clone(&log_file),
-    };
-
-    log::set_boxed_logger(Box::new(logger)).unwrap();
-    log::set_max_level(log::LevelFilter::Info);
-
-    info!("rbxlx-to-rojo {}", env!("CARGO_PKG_VERSION"));
-
-    info!("Select a place file.");
-    let file_path = PathBuf::from(match std::env::args().nth(1) {
-        Some(text) => text,
-        None => match nfd::open_file_dialog(Some("rbxl,rbxm,rbxlx,rbxmx"), None)
-            .map_err(|error| Problem::NFDError(error.to_string()))?
-        {
-            nfd::Response::Okay(path) => path,
-            nfd::Response::Cancel => Err(Problem::NFDCancel)?,
-            _ => unreachable!(),
-        },
-    });
-
-    info!("Opening place file");
-    let file_source = BufReader::new(
-        fs::File::open(&file_path)
-            .map_err(|error| Problem::IoError("read the place file", error))?,
-    );
-    info!("Decoding place file, this is the longest part...");
+// Where the log goes, if anywhere. A dry run leaves the output folder alone, so it only logs to
+// a file when given one.
+fn log_file_path(arguments: &Arguments, root: &Path) -> Option<PathBuf> {
+    match (&arguments.log_file, arguments.dry_run) {
+        (Some(path), _) => Some(path.clone()),
+        (None, false) => Some(root.join("rbxlx-to-rojo.log")),
+        (None, true) => None,
+    }
+}
 
-    let tree = match file_path
-        .extension()
-        .map(|extension| extension.to_string_lossy())
-    {
-        Some(Cow::Borrowed("rbxmx")) | Some(Cow::Borrowed("rbxlx")) => {
-            let mut reader = file_source;
-            let mut bytes = Vec::new();
-            reader
-                .read_to_end(&mut bytes)
-                .map_err(|error| Problem::IoError("read the place file", error))?;
-
-            let contents = String::from_utf8_lossy(&bytes).into_owned();
-            if contents.len() != bytes.len() {
-                log::warn!("Replaced invalid UTF-8 bytes while reading XML; content was lossily decoded.");
+// The first symlink at or under `path` that leads outside of `root`, which must be canonical.
+// Symlinks aren't followed, so a loop of them can't keep this going forever.
+fn escaping_symlink(path: &Path, root: &Path) -> io::Result<Option<PathBuf>> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error),
+    };
+
+    if metadata.file_type().is_symlink() {
+        // A dangling symlink would still be written through
+        let target = match fs::canonicalize(path) {
+            Ok(target) => target,
+            Err(_) => return Ok(Some(path.to_path_buf())),
+        };
+        return Ok(if target.starts_with(root) {
+            None
+        } else {
+            Some(path.to_path_buf())
+        });
+    }
+
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            if let Some(link) = escaping_symlink(&entry?.path(), root)? {
+                return Ok(Some(link));
             }
+        }
+    }
+
+    Ok(None)
+}
+
+// How long the place file has to go without changing before it's converted again, since saving
+// can take more than one write
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
 
-            let (mut safe_contents, protected) = protect_shared_sections(&contents);
+enum WatchSignal {
+    Changed,
+    Stop,
+}
 
-            if replace_invalid_float_literals(&mut safe_contents) {
-                log::warn!("Replaced invalid float literals before decoding.");
+// Converts `file_path` again every time it changes until Ctrl-C is pressed
+fn watch(arguments: &Arguments, file_path: &Path, root: &Path) -> Result<(), Problem> {
+    let watch_error = |error: notify::Error| {
+        Problem::IoError(
+            "watch the place file",
+            io::Error::new(io::ErrorKind::Other, error),
+        )
+    };
+
+    let file_path = fs::canonicalize(file_path)
+        .map_err(|error| Problem::IoError("watch the place file", error))?;
+    let file_name = file_path.file_name().map(ToOwned::to_owned);
+    let (sender, receiver) = mpsc::channel();
+
+    // Saving often replaces the file rather than writing to it, so its folder is watched instead
+    let changes = sender.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            let is_place = event
+                .paths
+                .iter()
+                .any(|path| path.file_name() == file_name.as_deref());
+            if is_place && (event.kind.is_create() || event.kind.is_modify()) {
+                changes.send(WatchSignal::Changed).ok();
             }
+        }
+    })
+    .map_err(watch_error)?;
+    watcher
+        .watch(
+            file_path.parent().unwrap_or(&file_path),
+            notify::RecursiveMode::NonRecursive,
+        )
+        .map_err(watch_error)?;
 
-            if strip_invalid_numeric_char_refs(&mut safe_contents) {
-                log::warn!("Stripped invalid numeric character references before decoding.");
+    // A conversion that's underway is left to finish, so nothing's written halfway
+    ctrlc::set_handler(move || {
+        sender.send(WatchSignal::Stop).ok();
+    })
+    .map_err(|error| {
+        Problem::IoError("handle Ctrl-C", io::Error::new(io::ErrorKind::Other, error))
+    })?;
+
+    info!(
+        "Watching {} for changes, press Ctrl-C to stop",
+        file_path.display()
+    );
+
+    'watching: while let Ok(WatchSignal::Changed) = receiver.recv() {
+        loop {
+            match receiver.recv_timeout(WATCH_DEBOUNCE) {
+                Ok(WatchSignal::Changed) => continue,
+                Ok(WatchSignal::Stop) | Err(RecvTimeoutError::Disconnected) => break 'watching,
+                Err(RecvTimeoutError::Timeout) => break,
             }
+        }
 
-            if sanitize_xml(&mut safe_contents) {
-                log::warn!("Stripped invalid XML characters before decoding.");
+        info!("{} changed, converting it again", file_path.display());
+        // It might have been caught halfway through saving, the next save gets another go
+        if let Err(error) = convert_one(arguments, &file_path, None, root, false, false) {
+            log::error!("Couldn't convert {}: {}", file_path.display(), error);
+        }
+    }
+
+    info!("Stopped watching {}", file_path.display());
+    Ok(())
+}
+
+/// What's written and the summary of it, worked out ahead of time by `confirm_plan`.
+type Plan = (Vec<Instruction<'static>>, ConversionSummary);
+
+/// Converts `tree` into `filesystem`, carrying out `plan` if it was already worked out.
+fn write_project<B: ParallelBackend>(
+    tree: &rbx_dom_weak::WeakDom,
+    filesystem: &mut FileSystem<B>,
+    arguments: &Arguments,
+    plan: Option<Plan>,
+) -> Result<ConversionSummary, Problem> {
+    let options = arguments.options();
+
+    let summary = match plan {
+        Some((instructions, summary)) => {
+            filesystem.read_instructions(instructions);
+            filesystem.finish_instructions();
+            summary
+        }
+        None => convert_with_progress(tree, filesystem, &options, arguments.jobs),
+    };
+
+    if let Some(sourcemap) = &summary.sourcemap {
+        filesystem
+            .write_sourcemap(sourcemap)
+            .map_err(|error| Problem::IoError("write the sourcemap", error))?;
+    }
+
+    if let Some(assets) = &summary.assets {
+        filesystem
+            .write_assets_manifest(assets)
+            .map_err(|error| Problem::IoError("write the assets manifest", error))?;
+    }
+
+    if let Some(shared_strings) = &summary.shared_strings {
+        filesystem
+            .write_shared_strings(shared_strings)
+            .map_err(|error| Problem::IoError("write the shared strings", error))?;
+    }
+
+    if summary.wally_packages {
+        filesystem
+            .write_wally_manifest()
+            .map_err(|error| Problem::IoError("write wally.toml", error))?;
+        info!("Found packages installed by Wally, wrote a starter wally.toml");
+    }
+
+    if arguments.git {
+        filesystem
+            .write_root_file(".gitignore", GITIGNORE.as_bytes())
+            .map_err(|error| Problem::IoError("write the .gitignore", error))?;
+    }
+
+    if arguments.report_unsupported {
+        let report = summary.unsupported_report();
+        filesystem
+            .write_unsupported_report(&report)
+            .map_err(|error| Problem::IoError("write unsupported.json", error))?;
+        info!(
+            "Wrote {} unsupported classes to unsupported.json",
+            report.len()
+        );
+    }
+
+    if arguments.lint_config {
+        let luaurc = to_string_indented(
+            &serde_json::json!({ "languageMode": "strict" }),
+            &arguments.indent,
+        )
+        .expect("couldn't serialize .luaurc");
+
+        for (name, contents) in [("selene.toml", SELENE_TOML), (".luaurc", luaurc.as_str())] {
+            let written = filesystem
+                .write_root_file_if_missing(name, contents.as_bytes())
+                .map_err(|error| Problem::IoError("write the lint config", error))?;
+            if !written {
+                log::warn!("The project already has a {}, leaving it alone", name);
             }
+        }
+    }
+
+    filesystem
+        .flush()
+        .map_err(|error| Problem::IoError("write the buffered files", error))?;
 
-            restore_shared_sections(&mut safe_contents, protected);
+    Ok(summary)
+}
 
-            rbx_xml::from_str_default(&safe_contents).map_err(Problem::XMLDecodeError)
+// Not being able to make a repository shouldn't throw away the conversion
+fn git_init(project_root: &Path) {
+    match Command::new("git")
+        .arg("init")
+        .current_dir(project_root)
+        .output()
+    {
+        Ok(output) if output.status.success() => info!("Initialized a git repository"),
+        Ok(output) => log::warn!(
+            "git init failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            log::warn!("git wasn't found, skipping git init")
         }
-        Some(Cow::Borrowed("rbxm")) | Some(Cow::Borrowed("rbxl")) => {
-            rbx_binary::from_reader(file_source).map_err(Problem::BinaryDecodeError)
+        Err(error) => log::warn!("Couldn't run git init: {}", error),
+    }
+}
+
+const CSV_HEADER: &str = "file,scripts,models,folders,skipped,repairs";
+
+// Appended to a row at a time so batch runs can share one report
+fn append_csv_summary(path: &Path, file: &str, summary: &ConversionSummary) -> io::Result<()> {
+    let mut csv = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+
+    if csv.metadata()?.len() == 0 {
+        writeln!(csv, "{}", CSV_HEADER)?;
+    }
+
+    writeln!(
+        csv,
+        "{},{},{},{},{},{}",
+        csv_field(file),
+        summary.scripts_written,
+        summary.models_written,
+        summary.folders_created,
+        summary.skipped.len(),
+        summary.repairs.len(),
+    )?;
+    csv.flush()
+}
+
+fn convert_with_progress<B: ParallelBackend>(
+    tree: &rbx_dom_weak::WeakDom,
+    filesystem: &mut FileSystem<B>,
+    options: &ConvertOptions,
+    jobs: usize,
+) -> ConversionSummary {
+    // Counted from every thread when there's more than one job
+    let files_written = AtomicUsize::new(0);
+    let mut progress = |event: &ProgressEvent| {
+        if let ProgressEvent::WroteFile { .. } = event {
+            let written = files_written.fetch_add(1, Ordering::Relaxed) + 1;
+            info!(target: PROGRESS_TARGET, "Wrote {} files", written);
         }
-        _ => Err(Problem::InvalidFile),
-    }?;
-
-    info!("Select the path to put your Rojo project in.");
-    let root = PathBuf::from(match std::env::args().nth(2) {
-        Some(text) => text,
-        None => match nfd::open_pick_folder(Some(&file_path.parent().unwrap().to_string_lossy()))
-            .map_err(|error| Problem::NFDError(error.to_string()))?
-        {
-            nfd::Response::Okay(path) => path,
-            nfd::Response::Cancel => Err(Problem::NFDCancel)?,
-            _ => unreachable!(),
-        },
-    });
-
-    let mut filesystem = FileSystem::from_root(root.join(file_path.file_stem().unwrap()).into());
-
-    log_file.write().unwrap().replace(
-        fs::File::create(root.join("rbxlx-to-rojo.log"))
-            .map_err(|error| Problem::IoError("couldn't create log file", error))?,
+    };
+
+    let summary = if jobs > 1 {
+        process_instructions_parallel_with_progress(tree, filesystem, options, jobs, &progress)
+    } else {
+        process_instructions_with_progress(tree, filesystem, options, &mut progress)
+    };
+
+    // Ends the progress line
+    log::logger().flush();
+    summary
+}
+
+/// Works out what would be written without touching the disk, then asks on the console
+/// whether to go ahead, in case the wrong folder was picked.
+fn confirm_plan(
+    tree: &rbx_dom_weak::WeakDom,
+    project_root: &Path,
+    is_model: bool,
+    arguments: &Arguments,
+) -> Result<Option<Plan>, Problem> {
+    let (instructions, summary) = plan_instructions(tree, &arguments.options());
+
+    // Carried out in memory first to see which files already exist
+    let mut preview = FileSystem::with_backend(project_root.to_path_buf(), Memory::default());
+    preview.set_model(is_model);
+    preview.read_instructions(instructions.clone());
+    preview.finish_instructions();
+
+    let instances = summary.scripts_written + summary.models_written + summary.folders_created;
+    let overwriting = preview.files().keys().filter(|path| path.exists()).count();
+    // Stdout might be taken by --stdout-summary
+    eprint!(
+        "Will write {} instances to {}, overwriting {} existing files. Continue? [y/N] ",
+        instances,
+        project_root.display(),
+        overwriting,
     );
+    io::stderr().flush().ok();
+
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .map_err(|error| Problem::IoError("read the answer", error))?;
+    Ok(match answer.trim() {
+        "y" | "Y" | "yes" => Some((instructions, summary)),
+        _ => None,
+    })
+}
+
+// The input's path without a .gz extension. A URL goes by the end of its path, leaving the query
+// out.
+fn plain_input_path(file_path: &Path) -> PathBuf {
+    match input_url(file_path) {
+        Some(url) => {
+            without_gz_extension(Path::new(url.split(&['?', '#'][..]).next().unwrap_or(url)))
+        }
+        None => without_gz_extension(file_path),
+    }
+}
+
+fn input_extension(plain_path: &Path, stdin_format: Option<&str>) -> String {
+    match stdin_format {
+        Some(format) => format.to_string(),
+        None => plain_path
+            .extension()
+            .map(|extension| extension.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    }
+}
+
+// The input to decode and how big it is, or 0 if there's no telling
+fn open_input(
+    file_path: &Path,
+    stdin_format: Option<&str>,
+) -> Result<(Box<dyn Read>, u64), Problem> {
+    if stdin_format.is_some() {
+        info!("Reading place file from stdin");
+        return Ok((Box::new(io::stdin()), 0));
+    }
+
+    if let Some(url) = input_url(file_path) {
+        info!("Downloading {}", url);
+        let response = ureq::get(url)
+            .call()
+            .map_err(|error| Problem::DownloadError {
+                url: url.to_string(),
+                reason: match error {
+                    ureq::Error::Status(code, _) => format!("the server responded with {}", code),
+                    error => error.to_string(),
+                },
+            })?;
+        let file_size = response
+            .header("Content-Length")
+            .and_then(|length| length.parse().ok())
+            .unwrap_or(0);
+        return Ok((Box::new(response.into_reader()), file_size));
+    }
+
+    info!("Opening {}", file_path.display());
+    let file = fs::File::open(file_path)
+        .map_err(|error| Problem::IoError("read the place file", error))?;
+    let file_size = file
+        .metadata()
+        .map_err(|error| Problem::IoError("read the place file", error))?
+        .len();
+    Ok((Box::new(file), file_size))
+}
+
+/// Decodes one file and prints its tree to stdout, without writing anything.
+fn list_one(
+    arguments: &Arguments,
+    file_path: &Path,
+    stdin_format: Option<&str>,
+) -> Result<(), Problem> {
+    let extension = input_extension(&plain_input_path(file_path), stdin_format);
+    let (source, file_size) = open_input(file_path, stdin_format)?;
+    let decoded = decode_file(
+        BufReader::new(ProgressReader::new(source, file_size)),
+        &extension,
+        &arguments.options(),
+    )?;
+
+    print!("{}", tree_listing(&decoded.tree, arguments.max_depth));
+    Ok(())
+}
+
+/// Converts one file into a project under `root`. `file_path` is ignored when reading from
+/// stdin. With `confirm`, the plan is shown and has to be agreed to before anything's written.
+fn convert_one(
+    arguments: &Arguments,
+    file_path: &Path,
+    stdin_format: Option<&str>,
+    root: &Path,
+    batch: bool,
+    confirm: bool,
+) -> Result<ConversionSummary, Problem> {
+    let plain_path = plain_input_path(file_path);
+
+    // There's no file name to go off of with stdin, so the output folder is the project
+    let project_root = match (stdin_format, &arguments.project_name) {
+        (Some(_), _) => root.to_path_buf(),
+        (None, Some(name)) => root.join(sanitize_instance_name(name, arguments.name_substitute)),
+        (None, None) => root.join(plain_path.file_stem().unwrap()),
+    };
+    let project_name = match &arguments.project_name {
+        Some(name) => name.clone(),
+        None => project_root
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| String::from("place")),
+    };
+
+    let extension = input_extension(&plain_path, stdin_format);
+
+    // With --zip, it's the archive that mustn't be overwritten rather than the folder
+    let (output, taken) = match &arguments.zip {
+        Some(zip_path) => (
+            zip_path.clone(),
+            fs::metadata(zip_path).map_or(false, |file| file.len() > 0),
+        ),
+        None => (
+            project_root.clone(),
+            !arguments.dry_run
+                && !arguments.merge
+                && is_non_empty_dir(&project_root, log_file_path(arguments, root).as_deref()),
+        ),
+    };
+    if !arguments.force && taken {
+        return Err(Problem::OutputExists(output));
+    }
+
+    if !arguments.follow_symlinks && !arguments.dry_run && arguments.zip.is_none() {
+        let checked = fs::canonicalize(root)
+            .and_then(|canonical_root| escaping_symlink(&project_root, &canonical_root))
+            .map_err(|error| Problem::IoError("check the output folder for symlinks", error))?;
+        if let Some(link) = checked {
+            return Err(Problem::SymlinkedOutput(link));
+        }
+    }
+
+    let (source, file_size) = open_input(file_path, stdin_format)?;
+
+    info!("Decoding place file, this is the longest part...");
+    let decode_started = Instant::now();
+    let DecodedFile {
+        tree,
+        is_model,
+        repairs,
+        sanitize_time,
+    } = decode_file(
+        BufReader::new(ProgressReader::new(source, file_size)),
+        &extension,
+        &arguments.options(),
+    )?;
+    let decode_time = decode_started.elapsed();
+
+    check_tree(&tree, &arguments.options())?;
+
+    if !repairs.is_empty() {
+        // Every file in a batch shares the output root
+        let repairs_name = if batch {
+            format!("{}.repairs.json", project_name)
+        } else {
+            String::from("repairs.json")
+        };
+        if arguments.dry_run {
+            info!(
+                "would write the details of every repair to {}",
+                repairs_name
+            );
+        } else {
+            let contents = to_string_indented(&repairs, &arguments.indent)
+                .expect("couldn't serialize repairs");
+            fs::write(root.join(&repairs_name), contents)
+                .map_err(|error| Problem::IoError("write repairs.json", error))?;
+            info!("Wrote the details of every repair to {}", repairs_name);
+        }
+    }
+
+    // The plan that was agreed to is the one carried out, rather than converting all over again
+    let plan = if confirm && !arguments.dry_run {
+        match confirm_plan(&tree, &project_root, is_model, arguments)? {
+            Some(plan) => Some(plan),
+            None => return Err(Problem::Declined),
+        }
+    } else {
+        None
+    };
 
     info!("Starting processing, please wait a bit...");
-    process_instructions(&tree, &mut filesystem);
-    info!("Done! Check rbxlx-to-rojo.log for a full log.");
+    let process_started = Instant::now();
+    let mut summary = if arguments.dry_run {
+        let mut filesystem = FileSystem::with_backend(project_root.clone(), DryRun);
+        filesystem.set_project_name(project_name);
+        filesystem.set_model(is_model);
+        filesystem.set_json_indent(arguments.indent.clone());
+        let summary = write_project(&tree, &mut filesystem, arguments, plan)?;
+
+        if arguments.git {
+            info!("would run git init in {}", project_root.display());
+        }
+
+        summary
+    } else if let Some(zip_path) = &arguments.zip {
+        let archive = fs::File::create(zip_path)
+            .map_err(|error| Problem::IoError("create the zip archive", error))?;
+        // Dropping the archive on an error still finishes it, so it's never left unreadable
+        let mut filesystem = FileSystem::with_backend(PathBuf::new(), Zip::new(archive));
+        filesystem.set_project_name(project_name);
+        filesystem.set_model(is_model);
+        filesystem.set_json_indent(arguments.indent.clone());
+        filesystem.set_buffered(arguments.buffered);
+        let summary = write_project(&tree, &mut filesystem, arguments, plan)?;
+
+        filesystem
+            .into_backend()
+            .finish()
+            .map_err(|error| Problem::IoError("finish the zip archive", error))?;
+        info!("Wrote the project to {}", zip_path.display());
+
+        summary
+    } else {
+        let mut filesystem = FileSystem::from_root_atomic(project_root.clone());
+        filesystem.set_project_name(project_name);
+        filesystem.set_model(is_model);
+        filesystem.set_json_indent(arguments.indent.clone());
+        filesystem.set_buffered(arguments.buffered);
+        filesystem.set_merge(arguments.merge, arguments.prune);
+        filesystem
+            .set_verify(arguments.verify)
+            .map_err(|error| Problem::IoError("turn on --verify", error))?;
+        let summary = write_project(&tree, &mut filesystem, arguments, plan)?;
+
+        if let Some(report) = filesystem.merge_report() {
+            info!("Files added: {}", report.added);
+            info!("Files changed: {}", report.changed);
+            info!("Files unchanged: {}", report.unchanged);
+            for path in &report.removed {
+                if arguments.prune {
+                    info!("Removed {}", path.display());
+                } else {
+                    info!("No longer in the place: {}", path.display());
+                }
+            }
+        }
+
+        if arguments.git {
+            git_init(&project_root);
+        }
+
+        summary
+    };
+
+    summary.repairs = repairs;
+    if arguments.timing {
+        summary.timings = Some(Timings {
+            decode: decode_time - sanitize_time,
+            sanitize: sanitize_time,
+            process: process_started.elapsed(),
+        });
+    }
+
+    info!("Scripts written: {}", summary.scripts_written);
+    info!("Models written: {}", summary.models_written);
+    info!("Folders created: {}", summary.folders_created);
+    if !summary.skipped.is_empty() {
+        info!("Skipped: {}", summary.skipped.join(", "));
+    }
+    if summary.filtered > 0 {
+        info!("Left out by class filters: {}", summary.filtered);
+    }
+    if summary.collapsed > 0 {
+        info!("Collapsed by --max-depth: {}", summary.collapsed);
+    }
+    if !summary.cleaned_scripts.is_empty() {
+        info!(
+            "Scripts with null bytes or byte order marks stripped: {}",
+            summary.cleaned_scripts.len()
+        );
+    }
+    if !summary.too_large.is_empty() {
+        info!(
+            "Written as empty instances for being too large: {}",
+            summary.too_large.join(", ")
+        );
+    }
+    for (original, sanitized) in &summary.renamed {
+        info!("Renamed {:?} to {:?}", original, sanitized);
+    }
+    if let Some(timings) = &summary.timings {
+        info!("Decoding took {:.2?}", timings.decode);
+        info!("Sanitizing took {:.2?}", timings.sanitize);
+        info!("Processing took {:.2?}", timings.process);
+    }
+
+    if let Some(csv_path) = &arguments.csv_summary {
+        if arguments.dry_run {
+            info!("would add a row to {}", csv_path.display());
+        } else {
+            let file = match stdin_format {
+                Some(_) => String::from("-"),
+                None => file_path.display().to_string(),
+            };
+            append_csv_summary(csv_path, &file, &summary)
+                .map_err(|error| Problem::IoError("write the CSV summary", error))?;
+            info!("Added a row to {}", csv_path.display());
+        }
+    }
+
+    Ok(summary)
+}
+
+fn routine(report: &mut Option<Vec<FileReport>>) -> Result<(), Problem> {
+    let mut arguments = Arguments::try_parse().map_err(Problem::ArgumentError)?;
+    if arguments.stdout_summary.is_some() {
+        *report = Some(Vec::new());
+    }
+
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(arguments.log_level());
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    let env_logger = builder.build();
+    let max_level = env_logger.filter();
+
+    let log_file = Arc::new(Mutex::new(None));
+    let warned = Arc::new(AtomicBool::new(false));
+    let logger = WrappedLogger {
+        log: env_logger,
+        log_file: Arc::clone(&log_file),
+        warned: Arc::clone(&warned),
+        progress_shown: Mutex::new(false),
+    };
+
+    log::set_boxed_logger(Box::new(logger)).unwrap();
+    log::set_max_level(max_level);
+
+    info!("rbxlx-to-rojo {}", env!("CARGO_PKG_VERSION"));
+
+    if let Some(path) = &arguments.rename_map {
+        let contents = fs::read_to_string(path)
+            .map_err(|error| Problem::IoError("read the rename map", error))?;
+        arguments.renames = serde_json::from_str(&contents)
+            .map_err(|error| Problem::IoError("read the rename map", error.into()))?;
+
+        if let Some((name, path)) = arguments.options().invalid_rename() {
+            return Err(Problem::ArgumentError(Arguments::command().error(
+                clap::error::ErrorKind::ValueValidation,
+                format!(
+                    "{} is renamed to {} in the rename map, which isn't inside the source folder",
+                    name,
+                    path.display()
+                ),
+            )));
+        }
+
+        if let Some((first, second, path)) = arguments.options().rename_conflict() {
+            return Err(Problem::ArgumentError(Arguments::command().error(
+                clap::error::ErrorKind::ValueValidation,
+                format!(
+                    "{} and {} are both renamed to {} in the rename map",
+                    first,
+                    second,
+                    path.display()
+                ),
+            )));
+        }
+    }
+
+    if let Some(path) = &arguments.class_extension_map {
+        let contents = fs::read_to_string(path)
+            .map_err(|error| Problem::IoError("read the class extension map", error))?;
+        arguments.class_strategies = serde_json::from_str(&contents)
+            .map_err(|error| Problem::IoError("read the class extension map", error.into()))?;
+
+        if let Some((class, strategy)) = arguments.options().misfit_strategy() {
+            return Err(Problem::ArgumentError(Arguments::command().error(
+                clap::error::ErrorKind::ValueValidation,
+                format!(
+                    "{} can't be written as {:?} in the class extension map",
+                    class, strategy
+                ),
+            )));
+        }
+    }
+
+    // Without --output, a last path after the inputs that looks like a folder is the output
+    // folder, otherwise they're all inputs
+    let mut inputs = arguments.paths.clone();
+    let positional_output = match &arguments.output {
+        _ if inputs.len() < 2 || arguments.list => None,
+        _ if !inputs.last().map_or(false, |path| looks_like_folder(path)) => None,
+        None => inputs.pop(),
+        Some(_) => {
+            let positional = inputs.pop().unwrap();
+            log::warn!(
+                "Both --output and a positional output path ({}) were given, using --output.",
+                positional.display()
+            );
+
+            None
+        }
+    };
+    let mut inputs = expand_globs(inputs);
+
+    // `-` or a --stdin-format without an input reads the place from stdin
+    let stdin_format = match (inputs.as_slice(), &arguments.stdin_format) {
+        ([path], format) if path.as_os_str() == "-" => {
+            Some(format.clone().ok_or(Problem::MissingArgument("--stdin-format"))?)
+        }
+        ([], Some(format)) => Some(format.clone()),
+        _ => None,
+    };
+
+    if inputs.len() > 1 {
+        let conflict = if inputs.iter().any(|path| path.as_os_str() == "-") {
+            Some("Only one file can be read from stdin")
+        } else if arguments.project_name.is_some() {
+            Some("--project-name can't be used with more than one input")
+        } else if arguments.zip.is_some() {
+            Some("--zip can't be used with more than one input")
+        } else if arguments.watch {
+            Some("--watch can't be used with more than one input")
+        } else {
+            None
+        };
+
+        if let Some(message) = conflict {
+            return Err(Problem::ArgumentError(
+                Arguments::command().error(clap::error::ErrorKind::ArgumentConflict, message),
+            ));
+        }
+    }
+
+    if arguments.watch
+        && (stdin_format.is_some() || inputs.iter().any(|path| input_url(path).is_some()))
+    {
+        return Err(Problem::ArgumentError(Arguments::command().error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "--watch needs a file on disk to watch, not stdin or a URL",
+        )));
+    }
+
+    if inputs.is_empty() && stdin_format.is_none() {
+        if arguments.no_gui {
+            return Err(Problem::MissingArgument("the place file"));
+        }
+
+        info!("Select a place file.");
+        inputs.push(PathBuf::from(prompt(|| {
+            nfd::open_file_dialog(Some("rbxl,rbxm,rbxlx,rbxmx"), None)
+        })?));
+    }
+
+    // Listing only reads, so there's no output folder to pick
+    if arguments.list {
+        if stdin_format.is_some() {
+            return list_one(&arguments, Path::new("-"), stdin_format.as_deref());
+        }
+
+        for file_path in &inputs {
+            if inputs.len() > 1 {
+                println!("{}:", file_path.display());
+            }
+            list_one(&arguments, file_path, None)?;
+        }
+
+        return Ok(());
+    }
+
+    // Only asked about when the output came from a dialog
+    let mut picked_output = false;
+    let root = match (&arguments.output, positional_output) {
+        (Some(output), _) => output.clone(),
+
+        (None, Some(path)) => path,
+
+        (None, None) if arguments.no_gui || stdin_format.is_some() => {
+            Err(Problem::MissingArgument("--output"))?
+        }
+
+        (None, None) => {
+            info!("Select the path to put your Rojo project in.");
+            let default_path = picker_start(&inputs[0]).to_string_lossy().into_owned();
+            picked_output = true;
+            PathBuf::from(prompt(|| nfd::open_pick_folder(Some(&default_path)))?)
+        }
+    };
+
+    // Whatever the output folder leads to is where everything would end up
+    if !arguments.follow_symlinks
+        && fs::symlink_metadata(&root).map_or(false, |metadata| metadata.file_type().is_symlink())
+    {
+        return Err(Problem::SymlinkedOutput(root));
+    }
+
+    // Made before decoding so that decode errors end up in the log too
+    if !arguments.dry_run {
+        fs::create_dir_all(&root)
+            .map_err(|error| Problem::IoError("create the output folder", error))?;
+    }
+    let log_path = log_file_path(&arguments, &root);
+    if let Some(log_path) = &log_path {
+        lock_log_file(&log_file).replace(
+            fs::File::create(log_path)
+                .map_err(|error| Problem::IoError("create the log file", error))?,
+        );
+    }
+
+    if inputs.len() <= 1 {
+        let file_path = inputs.pop().unwrap_or_default();
+        let result = convert_one(
+            &arguments,
+            &file_path,
+            stdin_format.as_deref(),
+            &root,
+            false,
+            picked_output && !arguments.yes,
+        );
+
+        if let Some(report) = report {
+            let file = match &stdin_format {
+                Some(_) => String::from("-"),
+                None => file_path.display().to_string(),
+            };
+            report.push(FileReport::new(file, &result));
+        }
+
+        result?;
+
+        if arguments.watch {
+            // Everything after the first conversion only writes what changed
+            arguments.merge = true;
+            watch(&arguments, &file_path, &root)?;
+        }
+    } else {
+        // One bad file shouldn't throw away the rest of the batch
+        let mut results = Vec::with_capacity(inputs.len());
+        for file_path in &inputs {
+            info!("Converting {}", file_path.display());
+            let result = convert_one(&arguments, file_path, None, &root, true, false);
+            if let Err(error) = &result {
+                log::error!("Couldn't convert {}: {}", file_path.display(), error);
+            }
+            if let Some(report) = report {
+                report.push(FileReport::new(file_path.display().to_string(), &result));
+            }
+            results.push((file_path, result));
+        }
+
+        info!("Converted {} files:", inputs.len());
+        let mut failed = 0;
+        for (file_path, result) in &results {
+            match result {
+                Ok(summary) => info!(
+                    "{}: {} scripts, {} models, {} folders",
+                    file_path.display(),
+                    summary.scripts_written,
+                    summary.models_written,
+                    summary.folders_created,
+                ),
+                Err(error) => {
+                    failed += 1;
+                    info!("{}: failed, {}", file_path.display(), error);
+                }
+            }
+        }
+
+        if failed > 0 {
+            return Err(Problem::BatchFailed {
+                failed,
+                total: inputs.len(),
+            });
+        }
+    }
+
+    if arguments.dry_run {
+        info!("Dry run, nothing was written to the project.");
+    }
+
+    match &log_path {
+        Some(log_path) if arguments.keep_log_on_success || warned.load(Ordering::Relaxed) => {
+            info!("Done! Check {} for a full log.", log_path.display());
+        }
+        Some(log_path) => {
+            // Closed first, since Windows won't remove a file that's still open
+            lock_log_file(&log_file).take();
+            fs::remove_file(log_path).ok();
+            info!("Done!");
+        }
+        None => info!("Done!"),
+    }
     Ok(())
 }
 
 fn main() {
-    if let Err(error) = routine() {
-        eprintln!("An error occurred while using rbxlx-to-rojo.");
-        eprintln!("{}", error);
+    let mut report = None;
+    let result = routine(&mut report);
+
+    // Nothing else goes to stdout, so tools can read this as is
+    if let Some(files) = report {
+        let summary = match &result {
+            Ok(()) => serde_json::json!({ "files": files }),
+            Err(error) => serde_json::json!({
+                "files": files,
+                "error": error.to_string(),
+                "exitCode": error.exit_code(),
+            }),
+        };
+        println!("{}", summary);
     }
+
+    let error = match result {
+        Ok(()) => return,
+        Err(error) => error,
+    };
+
+    match &error {
+        // --help and --version come through here too
+        Problem::ArgumentError(clap_error) if !clap_error.use_stderr() => {
+            clap_error.print().ok();
+        }
+
+        // The logger isn't set up until the arguments are parsed
+        Problem::ArgumentError(_) => {
+            eprintln!("An error occurred while using rbxlx-to-rojo.");
+            eprintln!("{}", error);
+        }
+
+        _ => {
+            log::error!("An error occurred while using rbxlx-to-rojo.");
+            log::error!("{}", error);
+        }
+    }
+
+    std::process::exit(error.exit_code());
 }
@@ -0,0 +1,689 @@
+//! Reading place and model files into a tree, repairing XML ones on the way.
+use crate::{sanitize::*, structures::ConvertOptions};
+use log::{info, warn};
+use rbx_dom_weak::{InstanceBuilder, WeakDom};
+use regex::Regex;
+use std::{
+    borrow::Cow,
+    error::Error,
+    fmt,
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+// Every gzip stream starts with these
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+// And every binary place or model with these
+const BINARY_MAGIC: &[u8] = b"<roblox!";
+
+// The magic, a signature, the version, the class and instance counts, and 8 reserved bytes
+const BINARY_HEADER_LEN: usize = 32;
+
+// Chunks compressed with zstd start with these, otherwise they're LZ4
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Why a file couldn't be converted.
+#[derive(Debug)]
+pub enum ConvertError {
+    BinaryDecode(rbx_binary::DecodeError),
+    /// The file decoded, but had fewer instances than `ConvertOptions::min_instances`.
+    EmptyTree {
+        found: usize,
+        minimum: usize,
+    },
+    /// `ConvertOptions::renames` sends an instance somewhere outside the source folder.
+    InvalidRename {
+        name: String,
+        path: PathBuf,
+    },
+    Io(io::Error),
+    /// `ConvertOptions::renames` sends two instances to the same path.
+    NameCollision {
+        first: String,
+        second: String,
+        path: PathBuf,
+    },
+    /// Nothing in the file is at `ConvertOptions::root_path`. `parent` is the full name of the
+    /// last instance found on the way, which is empty for the root, and `available` the names
+    /// of its children.
+    RootPathNotFound {
+        parent: String,
+        missing: String,
+        available: Vec<String>,
+    },
+    /// The file doesn't look like a place or model, and its extension doesn't say otherwise.
+    UnrecognizedFormat,
+    UnsupportedEncoding(TextEncoding),
+    /// rbx_xml's error, and where in the text it happened if the error says.
+    XmlDecode(rbx_xml::DecodeError, Option<XmlErrorLocation>),
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConvertError::BinaryDecode(error) => write!(
+                formatter,
+                "While attempting to decode the place file, at {} rbx_binary didn't know what to do",
+                error,
+            ),
+
+            ConvertError::EmptyTree { found, minimum } => write!(
+                formatter,
+                "The file only had {} instances in it when at least {} were expected, it might be \
+                 corrupt",
+                found, minimum,
+            ),
+
+            ConvertError::InvalidRename { name, path } => write!(
+                formatter,
+                "{} is renamed to {}, which isn't inside the source folder",
+                name,
+                path.display(),
+            ),
+
+            ConvertError::Io(error) => {
+                write!(formatter, "While attempting to read or write a file, {}", error)
+            }
+
+            ConvertError::NameCollision {
+                first,
+                second,
+                path,
+            } => write!(
+                formatter,
+                "{} and {} are both renamed to {}",
+                first,
+                second,
+                path.display(),
+            ),
+
+            ConvertError::RootPathNotFound {
+                parent,
+                missing,
+                available,
+            } => {
+                let parent = if parent.is_empty() {
+                    "the top of the file"
+                } else {
+                    parent.as_str()
+                };
+
+                if available.is_empty() {
+                    write!(formatter, "There's no {} in {}, which is empty", missing, parent)
+                } else {
+                    write!(
+                        formatter,
+                        "There's no {} in {}, which only has {}",
+                        missing,
+                        parent,
+                        available.join(", "),
+                    )
+                }
+            }
+
+            ConvertError::UnrecognizedFormat => {
+                write!(formatter, "The file provided isn't a place or model file that can be recognized")
+            }
+
+            ConvertError::UnsupportedEncoding(encoding) => write!(
+                formatter,
+                "The place file looks like {}, but it couldn't be decoded as such",
+                encoding,
+            ),
+
+            ConvertError::XmlDecode(error, location) => {
+                write!(
+                    formatter,
+                    "While attempting to decode the place file, at {} rbx_xml didn't know what to do",
+                    error,
+                )?;
+
+                match location {
+                    Some(location) => write!(formatter, " ({})", location),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl Error for ConvertError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConvertError::BinaryDecode(error) => Some(error),
+            ConvertError::Io(error) => Some(error),
+            ConvertError::XmlDecode(error, _) => Some(error),
+            ConvertError::EmptyTree { .. }
+            | ConvertError::InvalidRename { .. }
+            | ConvertError::NameCollision { .. }
+            | ConvertError::RootPathNotFound { .. }
+            | ConvertError::UnrecognizedFormat
+            | ConvertError::UnsupportedEncoding(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ConvertError {
+    fn from(error: io::Error) -> Self {
+        ConvertError::Io(error)
+    }
+}
+
+/// Where rbx_xml gave up, in the text it was handed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XmlErrorLocation {
+    pub byte_offset: usize,
+    /// Counted from 1, like editors do.
+    pub line: usize,
+    /// Counted from 1, in characters rather than bytes.
+    pub column: usize,
+    /// The text around the error.
+    pub snippet: String,
+}
+
+impl XmlErrorLocation {
+    /// Finds the position rbx_xml gave in `error` within `text`, or `None` if it didn't give one
+    /// or it's past the end.
+    pub(crate) fn from_error(error: &impl fmt::Display, text: &str) -> Option<Self> {
+        lazy_static::lazy_static! {
+            // xml-rs writes positions as "row:column", wherever rbx_xml puts them in its
+            // message, and rbx_xml's own errors spell them out
+            static ref POSITION_RE: Regex =
+                Regex::new(r"\b(\d+):(\d+)\b|line (\d+), column (\d+)").unwrap();
+        }
+
+        let message = error.to_string();
+        let caps = POSITION_RE.captures(&message)?;
+        let number = |first: usize, second: usize| {
+            caps.get(first)
+                .or_else(|| caps.get(second))
+                .and_then(|number| number.as_str().parse().ok())
+        };
+
+        Self::new(text, number(1, 3)?, number(2, 4)?)
+    }
+
+    /// The location of `line` and `column` in `text`. A column past the end of its line is
+    /// taken to mean the end of the line.
+    pub(crate) fn new(text: &str, line: usize, column: usize) -> Option<Self> {
+        let line_start = if line <= 1 {
+            0
+        } else {
+            text.match_indices('\n').nth(line - 2)?.0 + 1
+        };
+        let line_text = text[line_start..].split('\n').next().unwrap_or_default();
+        let byte_offset = line_start
+            + line_text
+                .char_indices()
+                .nth(column.saturating_sub(1))
+                .map_or(line_text.len(), |(index, _)| index);
+
+        Some(Self {
+            byte_offset,
+            line,
+            column,
+            snippet: snippet(text, byte_offset, byte_offset).to_string(),
+        })
+    }
+}
+
+impl fmt::Display for XmlErrorLocation {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "line {}, column {}, byte {}, near {:?}",
+            self.line, self.column, self.byte_offset, self.snippet,
+        )
+    }
+}
+
+/// The kinds of file that can be converted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlaceFormat {
+    BinaryModel,
+    BinaryPlace,
+    XmlModel,
+    XmlPlace,
+}
+
+impl PlaceFormat {
+    /// The format a file extension stands for, with or without the dot and in any case.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+        match extension.as_str() {
+            "rbxm" => Some(PlaceFormat::BinaryModel),
+            "rbxl" => Some(PlaceFormat::BinaryPlace),
+            "rbxmx" => Some(PlaceFormat::XmlModel),
+            "rbxlx" => Some(PlaceFormat::XmlPlace),
+            _ => None,
+        }
+    }
+
+    /// Works out the format from a whole file's contents, or `None` if they're neither binary nor
+    /// XML. Places are told apart from models by having services at the top, going by the first
+    /// item in XML files and the instance chunks in binary ones, so nothing else is decoded.
+    /// Files too broken to tell are taken to be models.
+    pub fn sniff(bytes: &[u8]) -> Option<Self> {
+        Some(if sniff_binary(bytes)? {
+            if binary_has_services(bytes).unwrap_or(false) {
+                PlaceFormat::BinaryPlace
+            } else {
+                PlaceFormat::BinaryModel
+            }
+        } else if first_item_class(bytes).map_or(false, |class| crate::is_service(&class)) {
+            PlaceFormat::XmlPlace
+        } else {
+            PlaceFormat::XmlModel
+        })
+    }
+
+    pub fn is_binary(self) -> bool {
+        matches!(self, PlaceFormat::BinaryModel | PlaceFormat::BinaryPlace)
+    }
+
+    pub fn is_model(self) -> bool {
+        matches!(self, PlaceFormat::BinaryModel | PlaceFormat::XmlModel)
+    }
+}
+
+/// A place or model read by `decode_file` or `decode_bytes`.
+pub struct DecodedFile {
+    pub tree: WeakDom,
+    pub is_model: bool,
+    /// What the sanitizers changed before the file could be decoded.
+    pub repairs: Vec<Repair>,
+    /// How long the sanitizers took, which is zero for binary files.
+    pub sanitize_time: Duration,
+}
+
+/// `path` without a trailing `.gz`, so `place.rbxlx.gz` is named and read like `place.rbxlx`.
+pub fn without_gz_extension(path: &Path) -> PathBuf {
+    match path.extension() {
+        Some(extension) if extension == "gz" => path.with_extension(""),
+        _ => path.to_path_buf(),
+    }
+}
+
+// The class of the first item in an XML file, which is always at the top since items only nest
+// inside other items
+fn first_item_class(bytes: &[u8]) -> Option<String> {
+    let encoding = TextEncoding::detect(bytes);
+    let bytes = &bytes[encoding.bom_len()..];
+
+    let text = match encoding {
+        TextEncoding::Utf8 | TextEncoding::Utf8Bom => String::from_utf8_lossy(bytes),
+
+        // Only decoded up to the end of the first item's tag
+        TextEncoding::Utf16Le | TextEncoding::Utf16Be => {
+            let units = bytes.chunks_exact(2).map(|pair| {
+                if encoding == TextEncoding::Utf16Le {
+                    u16::from_le_bytes([pair[0], pair[1]])
+                } else {
+                    u16::from_be_bytes([pair[0], pair[1]])
+                }
+            });
+
+            let mut text = String::new();
+            for character in char::decode_utf16(units) {
+                let character = character.ok()?;
+                text.push(character);
+                if character == '>' && text.contains("<Item") {
+                    break;
+                }
+            }
+            Cow::Owned(text)
+        }
+    };
+
+    let tag = &text[text.find("<Item")?..];
+    let tag = &tag[..tag.find('>')?];
+    let class = &tag[tag.find("class=\"")? + "class=\"".len()..];
+    Some(class[..class.find('"')?].to_string())
+}
+
+// Whether any instance chunk in a binary file is for a service. They come before the chunks
+// that need the whole tree, so only those are decompressed.
+fn binary_has_services(bytes: &[u8]) -> Option<bool> {
+    let read_u32 = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+
+    // Past the file's header, each chunk starts with its name, compressed and uncompressed
+    // lengths, and 4 reserved bytes
+    let mut rest = bytes.get(BINARY_HEADER_LEN..)?;
+    while rest.len() >= 16 {
+        let compressed_len = read_u32(&rest[4..]) as usize;
+        let len = read_u32(&rest[8..]) as usize;
+        let stored_len = if compressed_len == 0 {
+            len
+        } else {
+            compressed_len
+        };
+        let (name, data) = (&rest[..4], rest.get(16..16 + stored_len)?);
+        rest = &rest[16 + stored_len..];
+
+        match name {
+            b"INST" => {}
+            b"PROP" | b"PRNT" | b"END\0" => return Some(false),
+            _ => continue,
+        }
+
+        let data = if compressed_len == 0 {
+            Cow::Borrowed(data)
+        } else if data.starts_with(ZSTD_MAGIC) {
+            Cow::Owned(zstd::bulk::decompress(data, len).ok()?)
+        } else {
+            Cow::Owned(lz4_flex::decompress(data, len).ok()?)
+        };
+
+        // The class's ID, then its name
+        let name_len = read_u32(data.get(4..8)?) as usize;
+        let class = data.get(8..8 + name_len)?;
+        if crate::is_service(&String::from_utf8_lossy(class)) {
+            return Some(true);
+        }
+    }
+
+    None
+}
+
+// Every place has a Workspace
+fn is_place(tree: &WeakDom) -> bool {
+    tree.root().children().iter().any(|child| {
+        tree.get_by_ref(*child)
+            .map_or(false, |child| child.class == "Workspace")
+    })
+}
+
+/// Whether the file is binary or XML, going by how it starts. `None` if it's neither.
+pub fn sniff_binary(header: &[u8]) -> Option<bool> {
+    if header.starts_with(BINARY_MAGIC) {
+        return Some(true);
+    }
+
+    let text = match TextEncoding::detect(header) {
+        TextEncoding::Utf16Le | TextEncoding::Utf16Be => return Some(false),
+        TextEncoding::Utf8Bom => &header[3..],
+        TextEncoding::Utf8 => header,
+    };
+
+    let start = text
+        .iter()
+        .position(|byte| !byte.is_ascii_whitespace())
+        .unwrap_or(text.len());
+    if text[start..].starts_with(b"<roblox") || text[start..].starts_with(b"<?xml") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Runs every sanitizer `options` leaves on over `contents`, leaving shared sections alone.
+pub(crate) fn sanitize_contents(
+    mut contents: String,
+    options: &ConvertOptions,
+    repairs: &mut Vec<Repair>,
+) -> String {
+    if !options.sanitize_floats
+        && !options.sanitize_char_refs
+        && !options.sanitize_codepoints
+        && !options.sanitize_outside_root
+    {
+        info!("Sanitizing is turned off, decoding the file as is");
+        return contents;
+    }
+
+    // How much each sanitizer changed, summed up in one warning once they have all run
+    let mut counts = Vec::new();
+
+    // What each step changed, so every repair's offset can be moved back to the original text
+    let mut steps: Vec<Vec<Edit>> = Vec::new();
+    let mut record = |new_repairs: Vec<Repair>, steps: &mut Vec<Vec<Edit>>| {
+        let edits = new_repairs.iter().map(Repair::edit).collect();
+        repairs.extend(new_repairs.into_iter().map(|mut repair| {
+            for edits in steps.iter().rev() {
+                repair.byte_offset = offset_before(repair.byte_offset, edits);
+            }
+            repair
+        }));
+        steps.push(edits);
+    };
+
+    // First, so that nothing the other sanitizers do is thrown away with the junk
+    if options.sanitize_outside_root {
+        let length = contents.len();
+        let outside_repairs = trim_outside_root(&mut contents);
+        counts.push(("bytes cut from outside <roblox>", length - contents.len()));
+        record(outside_repairs, &mut steps);
+    }
+
+    // Trimming can't reach inside the document, so shared sections only need protecting, and
+    // copying, for the rest
+    if options.sanitize_char_refs || options.sanitize_codepoints || options.sanitize_floats {
+        let (mut safe_contents, protected) = protect_shared_sections(&contents);
+        drop(contents);
+        steps.push(protected_edits(&safe_contents, &protected));
+
+        if options.sanitize_char_refs {
+            let char_ref_repairs = strip_invalid_numeric_char_refs(&mut safe_contents);
+            counts.push(("char-refs stripped", char_ref_repairs.len()));
+            record(char_ref_repairs, &mut steps);
+        }
+
+        if options.sanitize_codepoints {
+            let codepoint_repairs = sanitize_xml(&mut safe_contents);
+            counts.push(("codepoints removed", codepoint_repairs.len()));
+            record(codepoint_repairs, &mut steps);
+        }
+
+        // Last, since stripping characters out can leave a NaN or infinity behind
+        if options.sanitize_floats {
+            let float_repairs = replace_invalid_float_literals(&mut safe_contents);
+            counts.push(("floats replaced", float_repairs.len()));
+            record(float_repairs, &mut steps);
+        }
+
+        restore_shared_sections(&mut safe_contents, protected);
+        contents = safe_contents;
+    }
+
+    let report: Vec<String> = counts
+        .into_iter()
+        .filter(|(_, count)| *count > 0)
+        .map(|(name, count)| format!("{}: {}", name, count))
+        .collect();
+    if !report.is_empty() {
+        warn!("Repaired the file before decoding, {}", report.join(", "));
+    }
+
+    contents
+}
+
+fn decode_tree<R: BufRead>(
+    mut reader: R,
+    binary: bool,
+    options: &ConvertOptions,
+) -> Result<(WeakDom, Vec<Repair>, Duration), ConvertError> {
+    // Binary files are decoded as they're read, so the whole file is never held at once
+    if binary && reader.fill_buf()?.starts_with(BINARY_MAGIC) {
+        let tree = rbx_binary::from_reader(reader).map_err(ConvertError::BinaryDecode)?;
+        return Ok((tree, Vec::new(), Duration::default()));
+    }
+
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+
+    if !binary {
+        return decode_xml(bytes, options);
+    }
+
+    match rbx_binary::from_reader(&bytes[..]) {
+        Ok(tree) => Ok((tree, Vec::new(), Duration::default())),
+
+        // Old places can be XML even when they're named .rbxl
+        Err(error) if !bytes.starts_with(BINARY_MAGIC) => {
+            warn!(
+                "The file doesn't have a binary header, trying to decode it as XML instead ({})",
+                error
+            );
+            decode_xml(bytes, options).map_err(|xml_error| {
+                warn!("Decoding it as XML didn't work either: {}", xml_error);
+                ConvertError::BinaryDecode(error)
+            })
+        }
+
+        Err(error) => Err(ConvertError::BinaryDecode(error)),
+    }
+}
+
+fn decode_xml(
+    bytes: Vec<u8>,
+    options: &ConvertOptions,
+) -> Result<(WeakDom, Vec<Repair>, Duration), ConvertError> {
+    let encoding = TextEncoding::detect(&bytes);
+    info!("Place file is encoded as {}", encoding);
+    let contents =
+        decode_text(bytes, encoding).ok_or(ConvertError::UnsupportedEncoding(encoding))?;
+
+    let mut repairs = Vec::new();
+    let started = Instant::now();
+    let contents = sanitize_contents(contents, options, &mut repairs);
+    let sanitize_time = started.elapsed();
+
+    // The protected sections are already back in place, so rbx_xml's positions line up with
+    // `contents` as it is here
+    let tree = rbx_xml::from_str_default(&contents).map_err(|error| {
+        let location = XmlErrorLocation::from_error(&error, &contents);
+        ConvertError::XmlDecode(error, location)
+    })?;
+    Ok((tree, repairs, sanitize_time))
+}
+
+/// Decodes a place or model, binary or XML, and gzipped or not. Renamed files are common, so
+/// `extension` is only trusted when the contents are unclear, and to tell models from places.
+pub fn decode_file<R: BufRead>(
+    mut reader: R,
+    extension: &str,
+    options: &ConvertOptions,
+) -> Result<DecodedFile, ConvertError> {
+    if reader.fill_buf()?.starts_with(GZIP_MAGIC) {
+        info!("The file is gzipped, decompressing it");
+        let decompressed = BufReader::new(flate2::bufread::GzDecoder::new(reader));
+        decode_stream(decompressed, extension, options)
+    } else {
+        decode_stream(reader, extension, options)
+    }
+}
+
+fn decode_stream<R: BufRead>(
+    mut reader: R,
+    extension: &str,
+    options: &ConvertOptions,
+) -> Result<DecodedFile, ConvertError> {
+    let format = PlaceFormat::from_extension(extension);
+    let binary = match (sniff_binary(reader.fill_buf()?), format) {
+        (Some(sniffed), Some(format)) if sniffed != format.is_binary() => {
+            warn!("The file's contents don't match its extension, going by its contents");
+            sniffed
+        }
+        (Some(binary), _) => binary,
+        (None, Some(format)) => format.is_binary(),
+        (None, None) => return Err(ConvertError::UnrecognizedFormat),
+    };
+
+    let (tree, repairs, sanitize_time) = decode_tree(reader, binary, options)?;
+
+    let is_model = match format {
+        Some(format) => format.is_model(),
+        None => !is_place(&tree),
+    };
+
+    narrow_to_root_path(
+        DecodedFile {
+            tree,
+            is_model,
+            repairs,
+            sanitize_time,
+        },
+        options,
+    )
+}
+
+// Only the instance at `ConvertOptions::root_path` is kept, which is converted like a model of
+// just that instance
+fn narrow_to_root_path(
+    decoded: DecodedFile,
+    options: &ConvertOptions,
+) -> Result<DecodedFile, ConvertError> {
+    let path = match &options.root_path {
+        Some(path) => path,
+        None => return Ok(decoded),
+    };
+
+    let mut tree = decoded.tree;
+    let mut referent = tree.root_ref();
+    let mut parent = Vec::new();
+    for name in path.split('.') {
+        let instance = tree.get_by_ref(referent).expect("fake referent?");
+        let found = instance.children().iter().copied().find(|child| {
+            tree.get_by_ref(*child)
+                .map_or(false, |child| child.name == name)
+        });
+
+        referent = match found {
+            Some(child) => child,
+            None => {
+                return Err(ConvertError::RootPathNotFound {
+                    parent: parent.join("."),
+                    missing: name.to_string(),
+                    available: instance
+                        .children()
+                        .iter()
+                        .filter_map(|child| tree.get_by_ref(*child))
+                        .map(|child| child.name.clone())
+                        .collect(),
+                })
+            }
+        };
+        parent.push(name);
+    }
+
+    info!("Converting only {}", path);
+    let mut subtree = WeakDom::new(InstanceBuilder::new("DataModel"));
+    let subtree_root = subtree.root_ref();
+    tree.transfer(referent, &mut subtree, subtree_root);
+
+    Ok(DecodedFile {
+        tree: subtree,
+        is_model: true,
+        ..decoded
+    })
+}
+
+/// Decodes a file that's already in memory, trusting `format` rather than looking at the
+/// contents, unless a binary format turns out not to have a binary header. XML goes through the
+/// same sanitizers as `decode_file`, which is every one `options` leaves on. Gzipped data is
+/// decompressed first.
+pub fn decode_bytes(
+    data: &[u8],
+    format: PlaceFormat,
+    options: &ConvertOptions,
+) -> Result<DecodedFile, ConvertError> {
+    let (tree, repairs, sanitize_time) = if data.starts_with(GZIP_MAGIC) {
+        info!("The file is gzipped, decompressing it");
+        let decompressed = BufReader::new(flate2::bufread::GzDecoder::new(data));
+        decode_tree(decompressed, format.is_binary(), options)?
+    } else {
+        decode_tree(data, format.is_binary(), options)?
+    };
+
+    narrow_to_root_path(
+        DecodedFile {
+            tree,
+            is_model: format.is_model(),
+            repairs,
+            sanitize_time,
+        },
+        options,
+    )
+}
@@ -1,31 +1,75 @@
 use crate::structures::*;
+use log::{debug, info};
 use serde::{ser::SerializeMap, Serialize, Serializer};
 use std::{
-    collections::BTreeMap,
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet},
     fs::{self, File},
-    io::Write,
-    path::PathBuf,
+    hash::{Hash, Hasher},
+    io::{self, Write},
+    path::{Path, PathBuf},
 };
 
 const SRC: &str = "src";
 
-fn serialize_project_tree<S: Serializer>(
-    tree: &BTreeMap<String, TreePartition>,
-    serializer: S,
-) -> Result<S::Ok, S::Error> {
-    let mut map = serializer.serialize_map(Some(tree.len() + 1))?;
-    map.serialize_entry("$className", "DataModel")?;
-    for (k, v) in tree {
-        map.serialize_entry(k, v)?;
+struct PlaceTree<'a>(&'a BTreeMap<String, TreePartition>);
+
+impl Serialize for PlaceTree<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len() + 1))?;
+        map.serialize_entry("$className", "DataModel")?;
+        for (k, v) in self.0 {
+            map.serialize_entry(k, v)?;
+        }
+        map.end()
     }
-    map.end()
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Serialize)]
+struct ModelTree {
+    #[serde(rename = "$path")]
+    path: String,
+}
+
+// A model with more than one instance at the top, each listed on its own
+#[derive(Serialize)]
+struct ModelRoots<'a> {
+    #[serde(rename = "$className")]
+    class_name: &'static str,
+    #[serde(flatten)]
+    roots: BTreeMap<&'a str, ModelTree>,
+}
+
+// The name Rojo gives the instance a file or folder in the source folder stands for
+fn entry_name(entry: &str) -> &str {
+    // Longest first, so `.server.lua` isn't taken for `.lua`
+    const EXTENSIONS: &[&str] = &[
+        ".model.rbxmx",
+        ".server.luau",
+        ".client.luau",
+        ".server.lua",
+        ".client.lua",
+        ".model.json",
+        ".rbxmx",
+        ".luau",
+        ".json",
+        ".rbxm",
+        ".csv",
+        ".lua",
+        ".txt",
+    ];
+
+    EXTENSIONS
+        .iter()
+        .find_map(|extension| entry.strip_suffix(extension))
+        .unwrap_or(entry)
+}
+
+#[derive(Clone, Debug)]
 struct Project {
     name: String,
-    #[serde(serialize_with = "serialize_project_tree")]
     tree: BTreeMap<String, TreePartition>,
+    // Top level entries in the source folder, only tracked for models
+    model_entries: Option<BTreeSet<String>>,
 }
 
 impl Project {
@@ -33,33 +77,960 @@ impl Project {
         Self {
             name: "project".to_string(),
             tree: BTreeMap::new(),
+            model_entries: None,
+        }
+    }
+
+    fn track_entry(&mut self, path: &Path) {
+        if let Some(entries) = &mut self.model_entries {
+            if let Some(first) = path.components().next() {
+                let first = first.as_os_str().to_string_lossy();
+                // Meta files sit next to the instance they describe, they aren't one themselves,
+                // apart from an init one describing the source folder
+                if !first.ends_with(".meta.json") || first == "init.meta.json" {
+                    entries.insert(first.into_owned());
+                }
+            }
+        }
+    }
+
+    // Whether the project file points at `path`, which Rojo needs to exist even if it's empty
+    fn refers_to(&self, path: &Path) -> bool {
+        self.tree.values().any(|partition| {
+            partition.path.as_deref() == Some(path)
+                || partition
+                    .children
+                    .values()
+                    .any(|child| child.path.as_deref() == Some(path))
+        })
+    }
+}
+
+impl Serialize for Project {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(2))?;
+        map.serialize_entry("name", &self.name)?;
+
+        match &self.model_entries {
+            // A model with one top level instance can be that instance, and one with more is a
+            // folder listing each of them. Init files mean the source folder is the instance.
+            Some(entries) => {
+                let source = ModelTree {
+                    path: SRC.to_string(),
+                };
+
+                if entries.is_empty() || entries.iter().any(|entry| entry.starts_with("init.")) {
+                    map.serialize_entry("tree", &source)?
+                } else if entries.len() == 1 {
+                    let entry = entries.iter().next().unwrap();
+                    map.serialize_entry(
+                        "tree",
+                        &ModelTree {
+                            path: format!("{}/{}", SRC, entry),
+                        },
+                    )?
+                } else {
+                    let roots: BTreeMap<&str, ModelTree> = entries
+                        .iter()
+                        .map(|entry| {
+                            let tree = ModelTree {
+                                path: format!("{}/{}", SRC, entry),
+                            };
+                            (entry_name(entry), tree)
+                        })
+                        .collect();
+
+                    // Two entries standing for instances with the same name can't both be listed
+                    if roots.len() == entries.len() {
+                        map.serialize_entry(
+                            "tree",
+                            &ModelRoots {
+                                class_name: "Folder",
+                                roots,
+                            },
+                        )?
+                    } else {
+                        map.serialize_entry("tree", &source)?
+                    }
+                }
+            }
+
+            None => map.serialize_entry("tree", &PlaceTree(&self.tree))?,
+        }
+
+        map.end()
+    }
+}
+
+/// Where a `FileSystem` actually puts the folders and files it creates.
+pub trait Backend {
+    fn create_dir_all(&mut self, path: &Path) -> io::Result<()>;
+    fn write(&mut self, path: &Path, contents: &[u8]) -> io::Result<()>;
+
+    /// What's already in the file at `path`, for merging. Backends that can't tell say there's
+    /// nothing there.
+    fn read(&mut self, _path: &Path) -> io::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Whether `read` hands back what was written, which `FileSystem::set_verify` needs.
+    fn can_read(&self) -> bool {
+        false
+    }
+
+    /// Every file anywhere under `path`, for finding what a merge no longer writes.
+    fn files_under(&mut self, _path: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(Vec::new())
+    }
+
+    fn remove_file(&mut self, path: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("can't remove {} from this backend", path.display()),
+        ))
+    }
+}
+
+impl<B: Backend + ?Sized> Backend for Box<B> {
+    fn create_dir_all(&mut self, path: &Path) -> io::Result<()> {
+        (**self).create_dir_all(path)
+    }
+
+    fn write(&mut self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        (**self).write(path, contents)
+    }
+
+    fn read(&mut self, path: &Path) -> io::Result<Option<Vec<u8>>> {
+        (**self).read(path)
+    }
+
+    fn can_read(&self) -> bool {
+        (**self).can_read()
+    }
+
+    fn files_under(&mut self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        (**self).files_under(path)
+    }
+
+    fn remove_file(&mut self, path: &Path) -> io::Result<()> {
+        (**self).remove_file(path)
+    }
+}
+
+fn read_from_disk(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+fn files_on_disk(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            files_on_disk(&entry.path(), files)?;
+        } else {
+            files.push(entry.path());
+        }
+    }
+
+    Ok(())
+}
+
+/// Backends that can be split up for `process_instructions_parallel`.
+pub trait ParallelBackend: Backend + Send + Sized {
+    fn fork(&self) -> Self;
+    fn join(&mut self, forked: Self);
+}
+
+/// Writes straight to disk.
+#[derive(Clone, Debug, Default)]
+pub struct Disk;
+
+impl Backend for Disk {
+    fn create_dir_all(&mut self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn write(&mut self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        File::create(path)?.write_all(contents)
+    }
+
+    fn read(&mut self, path: &Path) -> io::Result<Option<Vec<u8>>> {
+        read_from_disk(path)
+    }
+
+    fn can_read(&self) -> bool {
+        true
+    }
+
+    fn files_under(&mut self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        files_on_disk(path, &mut files)?;
+        Ok(files)
+    }
+
+    fn remove_file(&mut self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+}
+
+impl ParallelBackend for Disk {
+    fn fork(&self) -> Self {
+        Disk
+    }
+
+    fn join(&mut self, _forked: Self) {}
+}
+
+/// Writes each file to a `.tmp` file next to it first, then renames it into place,
+/// so a crash never leaves a half written file behind.
+#[derive(Clone, Debug, Default)]
+pub struct Atomic;
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+impl Backend for Atomic {
+    fn create_dir_all(&mut self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn write(&mut self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let temporary = temporary_path(path);
+        let result = File::create(&temporary)
+            .and_then(|mut file| file.write_all(contents))
+            .and_then(|_| fs::rename(&temporary, path));
+
+        if result.is_err() {
+            fs::remove_file(&temporary).ok();
+        }
+
+        result
+    }
+
+    fn read(&mut self, path: &Path) -> io::Result<Option<Vec<u8>>> {
+        read_from_disk(path)
+    }
+
+    fn can_read(&self) -> bool {
+        true
+    }
+
+    fn files_under(&mut self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        files_on_disk(path, &mut files)?;
+        Ok(files)
+    }
+
+    fn remove_file(&mut self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+}
+
+impl ParallelBackend for Atomic {
+    fn fork(&self) -> Self {
+        Atomic
+    }
+
+    fn join(&mut self, _forked: Self) {}
+}
+
+/// Records every folder and file instead of touching the disk.
+#[derive(Clone, Debug, Default)]
+pub struct Memory {
+    directories: BTreeSet<PathBuf>,
+    files: BTreeMap<PathBuf, Vec<u8>>,
+}
+
+impl Backend for Memory {
+    fn create_dir_all(&mut self, path: &Path) -> io::Result<()> {
+        self.directories.insert(path.to_path_buf());
+        Ok(())
+    }
+
+    fn write(&mut self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        self.files.insert(path.to_path_buf(), contents.to_vec());
+        Ok(())
+    }
+
+    fn read(&mut self, path: &Path) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.files.get(path).cloned())
+    }
+
+    fn can_read(&self) -> bool {
+        true
+    }
+
+    fn files_under(&mut self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self
+            .files
+            .keys()
+            .filter(|file| file.starts_with(path))
+            .cloned()
+            .collect())
+    }
+
+    fn remove_file(&mut self, path: &Path) -> io::Result<()> {
+        match self.files.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::ErrorKind::NotFound.into()),
         }
     }
 }
 
+impl ParallelBackend for Memory {
+    fn fork(&self) -> Self {
+        Memory::default()
+    }
+
+    fn join(&mut self, forked: Self) {
+        self.directories.extend(forked.directories);
+        self.files.extend(forked.files);
+    }
+}
+
+// Zip members are always separated by forward slashes
+#[cfg(feature = "zip")]
+fn member_name(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Writes every folder and file into a zip archive, laid out like they would be on disk. Use a
+/// root of `PathBuf::new()` so the project file is at the top of the archive, and `finish` it
+/// once everything's written. Once adding something to the archive fails, nothing more is added
+/// and `finish` returns the error. An archive that's dropped instead is still finished, but any
+/// error is lost.
+#[cfg(feature = "zip")]
+pub struct Zip<W: Write + io::Seek> {
+    // Forks don't have the archive, so they hold on to what they're given until they're joined
+    writer: Option<zip::ZipWriter<W>>,
+    held: Memory,
+    // Zip archives can have the same folder more than once, which confuses some tools
+    directories: BTreeSet<String>,
+    // The first error adding to the archive, kept for `finish`
+    error: Option<io::Error>,
+}
+
+#[cfg(feature = "zip")]
+impl<W: Write + io::Seek> Zip<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Some(zip::ZipWriter::new(writer)),
+            held: Memory::default(),
+            directories: BTreeSet::new(),
+            error: None,
+        }
+    }
+
+    /// Writes the end of the archive, handing back what it was written to, or the first error
+    /// adding something to it.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+
+        match &mut self.writer {
+            Some(writer) => Ok(writer.finish()?),
+            None => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "only the archive that was forked from can be finished",
+            )),
+        }
+    }
+
+    // Errors are kept for `finish` instead of being returned, since the archive is no use after
+    // one anyway
+    fn keep_error(&mut self, add: impl FnOnce(&mut Self) -> io::Result<()>) {
+        if self.error.is_none() {
+            if let Err(error) = add(self) {
+                self.error = Some(error);
+            }
+        }
+    }
+
+    fn add_directories(&mut self, path: &Path) -> io::Result<()> {
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => return self.held.create_dir_all(path),
+        };
+
+        let mut folders: Vec<&Path> = path
+            .ancestors()
+            .filter(|folder| !folder.as_os_str().is_empty())
+            .collect();
+        folders.reverse();
+
+        for folder in folders {
+            let name = member_name(folder);
+            if self.directories.insert(name.clone()) {
+                writer.add_directory(name, zip::write::FileOptions::default())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_file(&mut self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => return self.held.write(path, contents),
+        };
+
+        writer.start_file(member_name(path), zip::write::FileOptions::default())?;
+        writer.write_all(contents)
+    }
+}
+
+#[cfg(feature = "zip")]
+impl<W: Write + io::Seek> Backend for Zip<W> {
+    fn create_dir_all(&mut self, path: &Path) -> io::Result<()> {
+        self.keep_error(|zip| zip.add_directories(path));
+        Ok(())
+    }
+
+    fn write(&mut self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        self.keep_error(|zip| zip.add_file(path, contents));
+        Ok(())
+    }
+}
+
+#[cfg(feature = "zip")]
+impl<W: Write + io::Seek + Send> ParallelBackend for Zip<W> {
+    fn fork(&self) -> Self {
+        Self {
+            writer: None,
+            held: Memory::default(),
+            directories: BTreeSet::new(),
+            error: None,
+        }
+    }
+
+    fn join(&mut self, forked: Self) {
+        if self.error.is_none() {
+            self.error = forked.error;
+        }
+
+        for directory in &forked.held.directories {
+            self.keep_error(|zip| zip.add_directories(directory));
+        }
+
+        for (path, contents) in &forked.held.files {
+            self.keep_error(|zip| zip.add_file(path, contents));
+        }
+    }
+}
+
+/// Logs what would be written without touching the disk.
+#[derive(Clone, Debug, Default)]
+pub struct DryRun;
+
+impl Backend for DryRun {
+    fn create_dir_all(&mut self, path: &Path) -> io::Result<()> {
+        info!("would create dir {}", path.display());
+        Ok(())
+    }
+
+    fn write(&mut self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        info!("would write file {} ({} bytes)", path.display(), contents.len());
+        Ok(())
+    }
+}
+
+impl ParallelBackend for DryRun {
+    fn fork(&self) -> Self {
+        DryRun
+    }
+
+    fn join(&mut self, _forked: Self) {}
+}
+
+// Buffered writes fail long after they were asked for, so the error has to say which one it was
+fn with_path(error: io::Error, path: &Path) -> io::Error {
+    io::Error::new(error.kind(), format!("{}: {}", path.display(), error))
+}
+
+// Only compared within one run, so the hasher doesn't need to be stable across versions
+fn checksum(contents: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Checks that a file hashes the same as what was just written to it, see
+// `FileSystem::set_verify`
+fn read_back(backend: &mut dyn Backend, path: &Path, contents: &[u8]) -> io::Result<()> {
+    match backend.read(path)? {
+        Some(written) if checksum(&written) == checksum(contents) => Ok(()),
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} doesn't have what was written to it", path.display()),
+        )),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is missing right after it was written", path.display()),
+        )),
+    }
+}
+
 #[derive(Clone, Debug)]
-pub struct FileSystem {
+pub struct FileSystem<B: Backend = Disk> {
+    backend: B,
     project: Project,
     root: PathBuf,
     source: PathBuf,
+    json_indent: String,
+    // Everything written while buffering, until it's flushed to the backend
+    buffer: Option<Memory>,
+    // Folders that have been asked for but not created yet, since nothing's been written in them
+    pending_folders: BTreeSet<PathBuf>,
+    merge: Option<Merge>,
+    verify: bool,
+}
+
+/// What merging into an existing project did, file by file.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct MergeReport {
+    pub added: usize,
+    pub changed: usize,
+    pub unchanged: usize,
+    /// Files in the source folder that nothing in the place wrote, deleted if pruning.
+    pub removed: Vec<PathBuf>,
+}
+
+#[derive(Clone, Debug)]
+struct Merge {
+    report: MergeReport,
+    // Every file the conversion wrote or would have, so the rest can be found afterwards
+    written: BTreeSet<PathBuf>,
+    prune: bool,
+    // What a fork was given to write. Forks can't always read what's already there, so comparing
+    // and writing is left to whatever they're joined back into.
+    held: Option<BTreeMap<PathBuf, Vec<u8>>>,
 }
 
 impl FileSystem {
     pub fn from_root(root: PathBuf) -> Self {
+        Self::with_backend(root, Disk)
+    }
+}
+
+impl FileSystem<Atomic> {
+    /// Like `from_root`, but every file is written atomically.
+    pub fn from_root_atomic(root: PathBuf) -> Self {
+        Self::with_backend(root, Atomic)
+    }
+}
+
+impl<B: Backend> FileSystem<B> {
+    pub fn with_backend(root: PathBuf, mut backend: B) -> Self {
         let source = root.join(SRC);
         let project = Project::new();
 
-        fs::create_dir(&source).ok(); // It'll error later if it matters
+        backend.create_dir_all(&source).ok(); // It'll error later if it matters
 
         Self {
+            backend,
             project,
             root,
             source,
+            json_indent: String::from("  "),
+            buffer: None,
+            pending_folders: BTreeSet::new(),
+            merge: None,
+            verify: false,
+        }
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Hands back the backend, for ones like `Zip` that need finishing once everything's written.
+    pub fn into_backend(self) -> B {
+        self.backend
+    }
+
+    /// Sets the `name` field of the generated project file.
+    pub fn set_project_name(&mut self, name: impl Into<String>) {
+        self.project.name = name.into();
+    }
+
+    /// What the project file and the other JSON files written here are indented with.
+    pub fn set_json_indent(&mut self, indent: impl Into<String>) {
+        self.json_indent = indent.into();
+    }
+
+    /// Models get a project file rooted at the model itself rather than a DataModel.
+    pub fn set_model(&mut self, is_model: bool) {
+        self.project.model_entries = if is_model {
+            Some(BTreeSet::new())
+        } else {
+            None
+        };
+    }
+
+    /// Holds every folder and file back until `flush`, which writes them all in one go. Slow
+    /// file systems, like network drives, handle that much better than writes spread out over
+    /// the whole conversion.
+    pub fn set_buffered(&mut self, buffered: bool) {
+        self.buffer = if buffered {
+            Some(Memory::default())
+        } else {
+            None
+        };
+    }
+
+    /// Writes over an existing project, leaving files that already have the right contents alone.
+    /// Files in the source folder the place doesn't have anymore are listed in the report, and
+    /// deleted too if `prune` is set.
+    pub fn set_merge(&mut self, merge: bool, prune: bool) {
+        self.merge = if merge {
+            Some(Merge {
+                report: MergeReport::default(),
+                written: BTreeSet::new(),
+                prune,
+                held: None,
+            })
+        } else {
+            None
+        };
+    }
+
+    /// Reads every file back once it's on the backend, failing if its hash doesn't match what
+    /// was written, for storage that can't be trusted like flaky network drives. Errors for
+    /// backends that can't read files back, like `Zip` and `DryRun`.
+    pub fn set_verify(&mut self, verify: bool) -> io::Result<()> {
+        if verify && !self.backend.can_read() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "this backend can't read files back to verify them",
+            ));
+        }
+
+        self.verify = verify;
+        Ok(())
+    }
+
+    /// What merging did, once `finish_instructions` has run.
+    pub fn merge_report(&self) -> Option<&MergeReport> {
+        self.merge.as_ref().map(|merge| &merge.report)
+    }
+
+    /// Writes out everything held back since `set_buffered`, which `finish_instructions` does
+    /// too. Only the deepest folders are created, since that creates their parents as well, and
+    /// files are written in path order.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let buffer = match &mut self.buffer {
+            Some(buffer) => std::mem::take(buffer),
+            None => return Ok(()),
+        };
+
+        let directories: Vec<&PathBuf> = buffer.directories.iter().collect();
+        for (index, directory) in directories.iter().enumerate() {
+            // Sorting puts a folder's subfolders right after it
+            let has_subfolder = directories
+                .get(index + 1)
+                .map_or(false, |next| next.starts_with(directory));
+            if !has_subfolder {
+                self.backend
+                    .create_dir_all(directory)
+                    .map_err(|error| with_path(error, directory))?;
+            }
+        }
+
+        for (path, contents) in &buffer.files {
+            self.backend
+                .write(path, contents)
+                .map_err(|error| with_path(error, path))?;
+            if self.verify {
+                read_back(&mut self.backend, path, contents)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Creates `folder` if it's still waiting for something to be written in it, along with the
+    // parents waiting with it
+    fn create_pending_folder(&mut self, folder: &Path) -> io::Result<()> {
+        if !self.pending_folders.contains(folder) {
+            return Ok(());
         }
+
+        for ancestor in folder.ancestors() {
+            self.pending_folders.remove(ancestor);
+        }
+
+        self.project.track_entry(folder);
+        let path = self.source.join(folder);
+        self.target().create_dir_all(&path)
+    }
+
+    // Writes a file, unless merging and it's already there with the same contents
+    fn write_file(&mut self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        if let Some(merge) = &mut self.merge {
+            if let Some(held) = &mut merge.held {
+                held.insert(path.to_path_buf(), contents.to_vec());
+                return Ok(());
+            }
+
+            merge.written.insert(path.to_path_buf());
+            match self.backend.read(path)? {
+                Some(existing) if existing == contents => {
+                    merge.report.unchanged += 1;
+                    return Ok(());
+                }
+                Some(_) => merge.report.changed += 1,
+                None => merge.report.added += 1,
+            }
+        }
+
+        self.target().write(path, contents)?;
+        if self.verify && self.buffer.is_none() {
+            read_back(&mut self.backend, path, contents)?;
+        }
+
+        Ok(())
+    }
+
+    // Deletes whatever's in the source folder that this conversion didn't write
+    fn remove_leftovers(&mut self) -> io::Result<()> {
+        let merge = match &mut self.merge {
+            Some(merge) => merge,
+            None => return Ok(()),
+        };
+
+        for path in self.backend.files_under(&self.source)? {
+            if merge.written.contains(&path) {
+                continue;
+            }
+
+            if merge.prune {
+                self.backend
+                    .remove_file(&path)
+                    .map_err(|error| with_path(error, &path))?;
+            }
+            merge.report.removed.push(path);
+        }
+
+        Ok(())
+    }
+
+    // Where writes go right now, which is the buffer while buffering
+    fn target(&mut self) -> &mut dyn Backend {
+        match &mut self.buffer {
+            Some(buffer) => buffer,
+            None => &mut self.backend,
+        }
+    }
+
+    /// Writes a file next to the project file, outside of the source folder.
+    pub fn write_root_file(&mut self, name: &str, contents: &[u8]) -> io::Result<()> {
+        let path = self.root.join(name);
+        self.write_file(&path, contents)
+    }
+
+    /// Like `write_root_file`, but leaves a file that's already there alone. Returns whether
+    /// it was written.
+    pub fn write_root_file_if_missing(&mut self, name: &str, contents: &[u8]) -> io::Result<bool> {
+        let path = self.root.join(name);
+        if self.backend.read(&path)?.is_some() {
+            return Ok(false);
+        }
+
+        self.write_file(&path, contents)?;
+        Ok(true)
+    }
+
+    /// Writes `sourcemap.json` next to the project file, with paths relative to it.
+    pub fn write_sourcemap(&mut self, sourcemap: &SourcemapNode) -> io::Result<()> {
+        fn rebase(node: &mut SourcemapNode) {
+            for path in &mut node.file_paths {
+                *path = format!("{}/{}", SRC, path);
+            }
+
+            for child in &mut node.children {
+                rebase(child);
+            }
+        }
+
+        let mut root = sourcemap.clone();
+        rebase(&mut root);
+
+        // Like the project file, a model with one instance in it is that instance
+        if self.project.model_entries.is_some() && root.children.len() == 1 {
+            root = root.children.remove(0);
+        } else {
+            root.name = self.project.name.clone();
+        }
+
+        root.file_paths
+            .insert(0, "default.project.json".to_string());
+
+        let contents = serde_json::to_string(&root)?;
+        self.write_root_file("sourcemap.json", contents.as_bytes())
+    }
+
+    /// Writes `assets.json` next to the project file.
+    pub fn write_assets_manifest(&mut self, assets: &AssetManifest) -> io::Result<()> {
+        let contents = to_string_indented(assets, &self.json_indent)?;
+        self.write_root_file("assets.json", contents.as_bytes())
+    }
+
+    /// Writes `unsupported.json` next to the project file, see
+    /// `ConversionSummary::unsupported_report`.
+    pub fn write_unsupported_report(
+        &mut self,
+        report: &BTreeMap<String, UnsupportedClass>,
+    ) -> io::Result<()> {
+        let contents = to_string_indented(report, &self.json_indent)?;
+        self.write_root_file("unsupported.json", contents.as_bytes())
+    }
+
+    /// Writes a starter `wally.toml` named after the project, with no dependencies yet.
+    pub fn write_wally_manifest(&mut self) -> io::Result<()> {
+        // Wally only takes lowercase letters, digits, and dashes
+        let name: String = self
+            .project
+            .name
+            .to_lowercase()
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | '0'..='9' => c,
+                _ => '-',
+            })
+            .collect();
+        let name = match name.trim_matches('-') {
+            "" => "place",
+            name => name,
+        };
+
+        let manifest = format!(
+            r#"[package]
+name = "{0}/{0}"
+version = "0.1.0"
+registry = "https://github.com/UpliftGames/wally-index"
+realm = "shared"
+
+[dependencies]
+"#,
+            name,
+        );
+        self.write_root_file("wally.toml", manifest.as_bytes())
+    }
+
+    /// Writes each `SharedString` once to `shared/<hash>.bin`, with `shared/index.json` saying
+    /// which instances use which.
+    pub fn write_shared_strings(&mut self, shared_strings: &SharedStrings) -> io::Result<()> {
+        let shared = self.root.join("shared");
+        let index = to_string_indented(&shared_strings.uses, &self.json_indent)?;
+        self.target().create_dir_all(&shared)?;
+
+        for (hash, value) in &shared_strings.payloads {
+            self.write_file(&shared.join(format!("{}.bin", hash)), value.data())?;
+        }
+
+        self.write_file(&shared.join("index.json"), index.as_bytes())
+    }
+}
+
+pub type MemoryFileSystem = FileSystem<Memory>;
+
+impl MemoryFileSystem {
+    /// Creates a file system that only records what would be written.
+    /// Paths are relative to the project root, so scripts end up under `src/`.
+    pub fn new() -> Self {
+        Self::with_backend(PathBuf::new(), Memory::default())
+    }
+
+    pub fn directories(&self) -> &BTreeSet<PathBuf> {
+        &self.backend.directories
+    }
+
+    pub fn files(&self) -> &BTreeMap<PathBuf, Vec<u8>> {
+        &self.backend.files
+    }
+}
+
+impl Default for MemoryFileSystem {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-impl InstructionReader for FileSystem {
+impl<B: ParallelBackend> ParallelInstructionReader for FileSystem<B> {
+    fn fork(&self) -> Self {
+        Self {
+            backend: self.backend.fork(),
+            project: Project {
+                name: self.project.name.clone(),
+                tree: BTreeMap::new(),
+                model_entries: self.project.model_entries.as_ref().map(|_| BTreeSet::new()),
+            },
+            root: self.root.clone(),
+            source: self.source.clone(),
+            json_indent: self.json_indent.clone(),
+            buffer: self.buffer.as_ref().map(Memory::fork),
+            pending_folders: BTreeSet::new(),
+            merge: self.merge.as_ref().map(|merge| Merge {
+                report: MergeReport::default(),
+                written: BTreeSet::new(),
+                prune: merge.prune,
+                held: Some(BTreeMap::new()),
+            }),
+            verify: self.verify,
+        }
+    }
+
+    fn join(&mut self, forked: Self) {
+        self.backend.join(forked.backend);
+        if let (Some(buffer), Some(forked_buffer)) = (&mut self.buffer, forked.buffer) {
+            buffer.join(forked_buffer);
+        }
+
+        self.pending_folders.extend(forked.pending_folders);
+
+        let held = forked.merge.and_then(|merge| merge.held);
+        for (path, contents) in held.into_iter().flatten() {
+            self.write_file(&path, &contents).unwrap_or_else(|error| {
+                panic!("can't write to file {:?} due to {:?}", path, error)
+            });
+        }
+
+        for (name, partition) in forked.project.tree {
+            assert!(
+                self.project.tree.get(&name).is_none(),
+                "Duplicate item added to tree! Instances can't have the same name: {}",
+                name
+            );
+
+            self.project.tree.insert(name, partition);
+        }
+
+        if let (Some(entries), Some(forked_entries)) = (
+            &mut self.project.model_entries,
+            forked.project.model_entries,
+        ) {
+            entries.extend(forked_entries);
+        }
+    }
+}
+
+impl<B: Backend> InstructionReader for FileSystem<B> {
     fn read_instruction<'a>(&mut self, instruction: Instruction<'a>) {
         match instruction {
             Instruction::AddToTree {
@@ -86,30 +1057,54 @@ impl InstructionReader for FileSystem {
             }
 
             Instruction::CreateFile { filename, contents } => {
-                let mut file = File::create(self.source.join(&filename)).unwrap_or_else(|error| {
-                    panic!("can't create file {:?}: {:?}", filename, error)
-                });
-                file.write_all(&contents).unwrap_or_else(|error| {
+                if let Some(folder) = filename.parent() {
+                    self.create_pending_folder(folder).unwrap_or_else(|error| {
+                        panic!("can't write to folder {:?}: {:?}", folder, error)
+                    });
+                }
+
+                self.project.track_entry(&filename);
+                let path = self.source.join(&filename);
+                self.write_file(&path, &contents).unwrap_or_else(|error| {
                     panic!("can't write to file {:?} due to {:?}", filename, error)
                 });
             }
 
+            // Filtering can leave folders with nothing in them, so they're only created once
+            // something is written inside
             Instruction::CreateFolder { folder } => {
-                fs::create_dir_all(self.source.join(&folder)).unwrap_or_else(|error| {
-                    panic!("can't write to folder {:?}: {:?}", folder, error)
-                });
+                self.pending_folders.insert(folder.into_owned());
             }
         }
     }
 
     fn finish_instructions(&mut self) {
-        let mut file = File::create(self.root.join("default.project.json"))
-            .expect("can't create default.project.json");
-        file.write_all(
-            &serde_json::to_string_pretty(&self.project)
-                .expect("couldn't serialize project")
-                .as_bytes(),
-        )
-        .expect("can't write project");
+        // Empty or not, the project file needs what it points at
+        let referenced: Vec<PathBuf> = self
+            .pending_folders
+            .iter()
+            .filter(|folder| self.project.refers_to(&PathBuf::from(SRC).join(folder)))
+            .cloned()
+            .collect();
+        for folder in referenced {
+            self.create_pending_folder(&folder)
+                .unwrap_or_else(|error| panic!("can't write to folder {:?}: {:?}", folder, error));
+        }
+
+        for folder in std::mem::take(&mut self.pending_folders) {
+            debug!(
+                "Leaving out {}, since nothing was written in it",
+                folder.display()
+            );
+        }
+
+        let project = to_string_indented(&self.project, &self.json_indent)
+            .expect("couldn't serialize project");
+        self.write_root_file("default.project.json", project.as_bytes())
+            .expect("can't write project");
+        self.flush()
+            .unwrap_or_else(|error| panic!("can't write buffered files: {}", error));
+        self.remove_leftovers()
+            .unwrap_or_else(|error| panic!("can't remove leftover files: {}", error));
     }
 }
@@ -1,17 +1,27 @@
-use log::debug;
-use rbx_dom_weak::{ustr, types::{Ref, Variant}, Instance, WeakDom};
+use log::{debug, warn};
+use rayon::prelude::*;
+use rbx_dom_weak::{ustr, types::{Attributes, Enum, Ref, SharedString, Variant}, Instance, InstanceBuilder, WeakDom};
 use rbx_reflection::ClassTag;
 use std::{
     borrow::Cow,
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::File,
+    io::BufReader,
     path::{Path, PathBuf},
 };
+use unicode_normalization::UnicodeNormalization;
 
+use decode::DecodedFile;
+use filesystem::{Backend, FileSystem};
 use structures::*;
 
+pub mod decode;
 pub mod filesystem;
+pub mod sanitize;
 pub mod structures;
 
+pub use decode::{ConvertError, PlaceFormat, XmlErrorLocation};
+
 #[cfg(test)]
 mod tests;
 
@@ -20,12 +30,23 @@ lazy_static::lazy_static! {
     static ref RESPECTED_SERVICES: HashSet<&'static str> = include_str!("./respected-services.txt").lines().collect();
 }
 
-fn sanitize_name(name: &str) -> String {
+// Roblox's own identifiers for instances, see `ConvertOptions::preserve_guids`
+const IDENTITY_PROPERTIES: &[&str] = &["HistoryId", "ScriptGuid", "UniqueId"];
+
+// Windows refuses to create these no matter what extension follows them
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Makes an instance name usable as a file name by replacing characters that aren't allowed in
+/// paths with `substitute` and escaping names Windows reserves for devices.
+pub fn sanitize_instance_name(name: &str, substitute: char) -> String {
     let mut sanitized: String = name
         .chars()
         .map(|c| match c {
-            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
-            c if c.is_control() => '_',
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => substitute,
+            c if c.is_control() => substitute,
             _ => c,
         })
         .collect();
@@ -35,10 +56,30 @@ fn sanitize_name(name: &str) -> String {
     }
 
     if sanitized.is_empty() {
-        "_".to_string()
-    } else {
-        sanitized
+        return substitute.to_string();
     }
+
+    let stem = sanitized.split('.').next().unwrap_or("");
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        let stem_length = stem.len();
+        sanitized.insert(stem_length, substitute);
+    }
+
+    sanitized
+}
+
+// What an instance is called on disk, before siblings with the same name are numbered
+fn file_name(name: &str, options: &ConvertOptions) -> String {
+    let name: Cow<str> = match options.normalize_names {
+        NameNormalization::None => Cow::Borrowed(name),
+        NameNormalization::Nfc => Cow::Owned(name.nfc().collect()),
+        NameNormalization::Nfd => Cow::Owned(name.nfd().collect()),
+    };
+
+    sanitize_instance_name(&name, options.name_substitute)
 }
 
 fn get_class_descriptor(class_name: &str) -> Option<&'static rbx_reflection::ClassDescriptor<'static>> {
@@ -51,26 +92,654 @@ fn get_class_descriptor(class_name: &str) -> Option<&'static rbx_reflection::Cla
     }
 }
 
-struct TreeIterator<'a, I: InstructionReader + ?Sized> {
+// Instances that only exist to group others, so all of their properties are worth keeping
+fn preserves_properties(class_name: &str) -> bool {
+    matches!(class_name, "Configuration" | "Folder")
+}
+
+fn is_value_class(class_name: &str) -> bool {
+    matches!(
+        class_name,
+        "BoolValue"
+            | "BrickColorValue"
+            | "CFrameValue"
+            | "Color3Value"
+            | "IntValue"
+            | "NumberValue"
+            | "ObjectValue"
+            | "RayValue"
+            | "StringValue"
+            | "Vector3Value"
+    )
+}
+
+// How instances of a class write themselves, `ConvertOptions::class_strategies` first. `None`
+// for classes that only become folders when there's something inside to write
+fn class_strategy(class: &str, options: &ConvertOptions) -> Option<ClassStrategy> {
+    if let Some(strategy) = options.class_strategies.get(class) {
+        if strategy.fits(class) {
+            return Some(*strategy);
+        }
+    }
+
+    match class {
+        "Script" | "LocalScript" | "ModuleScript" => Some(ClassStrategy::Lua),
+        "LocalizationTable" => Some(ClassStrategy::Csv),
+        "StringValue" if options.string_values_as_txt => Some(ClassStrategy::Txt),
+        class if options.json_models && is_value_class(class) => Some(ClassStrategy::JsonModel),
+        _ => None,
+    }
+}
+
+// JSON models can't hold binary data, though they can link to other instances like meta files
+fn fits_json_model(instance: &Instance) -> bool {
+    instance.properties.iter().all(|(name, value)| match value {
+        Variant::BinaryString(_) | Variant::SharedString(_) => {
+            matches!(name.as_str(), "Tags" | "AttributesSerialize")
+        }
+        _ => true,
+    })
+}
+
+fn non_default_properties(
+    instance: &Instance,
+    options: &ConvertOptions,
+) -> BTreeMap<String, Variant> {
+    let mut properties = BTreeMap::new();
+    let descriptor = match get_class_descriptor(&instance.class) {
+        Some(descriptor) => descriptor,
+        None => return properties,
+    };
+
+    for (name, value) in &instance.properties {
+        let name = name.as_str();
+        // Tags and attributes get their own fields in meta files
+        if matches!(name, "Name" | "Tags" | "Attributes" | "AttributesSerialize") {
+            continue;
+        }
+
+        if IDENTITY_PROPERTIES.contains(&name) {
+            if options.preserve_guids {
+                properties.insert(name.to_string(), value.clone());
+            }
+            continue;
+        }
+
+        match descriptor.default_properties.get(name) {
+            Some(default) if default == value => continue,
+            Some(default) if default.ty() != value.ty() => {
+                warn!(
+                    "{}.{} is a {:?} instead of a {:?}, not preserving it",
+                    instance.name,
+                    name,
+                    value.ty(),
+                    default.ty()
+                );
+                continue;
+            }
+            Some(_) => {}
+            None => {
+                debug!("{}.{} has no default, not preserving it", instance.name, name);
+                continue;
+            }
+        }
+
+        match value {
+            // Identifiers are regenerated anyways, and references are kept by `RefLinks`
+            Variant::UniqueId(_) | Variant::Ref(_) => {}
+
+            Variant::BinaryString(_) | Variant::SharedString(_) => {
+                warn!(
+                    "{}.{} can't be written to a meta file, not preserving it",
+                    instance.name, name
+                );
+            }
+
+            _ => {
+                properties.insert(name.to_string(), value.clone());
+            }
+        }
+    }
+
+    properties
+}
+
+// Tags are packed into one string, separated by null characters
+fn decode_tags(packed: &[u8]) -> Vec<String> {
+    packed
+        .split(|byte| *byte == 0)
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| String::from_utf8_lossy(tag).into_owned())
+        .collect()
+}
+
+fn tags(instance: &Instance) -> Vec<String> {
+    match instance.properties.get(&ustr("Tags")) {
+        Some(Variant::Tags(tags)) => tags.iter().map(str::to_owned).collect(),
+        Some(Variant::BinaryString(packed)) => decode_tags(packed.as_ref()),
+        _ => Vec::new(),
+    }
+}
+
+// Rojo can only write these types as attributes
+fn is_attribute_type(value: &Variant) -> bool {
+    matches!(
+        value,
+        Variant::Bool(_)
+            | Variant::BrickColor(_)
+            | Variant::CFrame(_)
+            | Variant::Color3(_)
+            | Variant::ColorSequence(_)
+            | Variant::EnumItem(_)
+            | Variant::Float32(_)
+            | Variant::Float64(_)
+            | Variant::Font(_)
+            | Variant::Int32(_)
+            | Variant::NumberRange(_)
+            | Variant::NumberSequence(_)
+            | Variant::Rect(_)
+            | Variant::String(_)
+            | Variant::UDim(_)
+            | Variant::UDim2(_)
+            | Variant::Vector2(_)
+            | Variant::Vector3(_)
+    )
+}
+
+fn attributes(instance: &Instance) -> BTreeMap<String, Variant> {
+    let decoded;
+    let attributes = match instance.properties.get(&ustr("Attributes")) {
+        Some(Variant::Attributes(attributes)) => attributes,
+        _ => match instance.properties.get(&ustr("AttributesSerialize")) {
+            Some(Variant::BinaryString(packed)) if !packed.as_ref().is_empty() => {
+                match Attributes::from_reader(packed.as_ref()) {
+                    Ok(attributes) => {
+                        decoded = attributes;
+                        &decoded
+                    }
+                    Err(error) => {
+                        warn!(
+                            "Couldn't decode the attributes of {}, not preserving them: {}",
+                            instance.name, error
+                        );
+                        return BTreeMap::new();
+                    }
+                }
+            }
+            _ => return BTreeMap::new(),
+        },
+    };
+
+    attributes
+        .iter()
+        .filter(|(name, value)| {
+            let supported = is_attribute_type(value);
+            if !supported {
+                warn!(
+                    "{} has a {:?} attribute {}, which can't be written to a meta file",
+                    instance.name,
+                    value.ty(),
+                    name
+                );
+            }
+            supported
+        })
+        .map(|(name, value)| (name.to_string(), value.clone()))
+        .collect()
+}
+
+/// How `Ref` properties are kept. Rojo points a `Rojo_Target_<property>` attribute at the
+/// instance with the matching `Rojo_Id` attribute.
+#[derive(Default)]
+struct RefLinks {
+    ids: HashMap<Ref, String>,
+    targets: HashMap<Ref, BTreeMap<String, String>>,
+}
+
+impl RefLinks {
+    // Everything has to be known up front, since a target can be written after what points at it
+    fn new(tree: &WeakDom, has_scripts: &HashMap<Ref, bool>) -> Self {
+        let mut links = RefLinks::default();
+        let mut taken_ids = HashSet::new();
+        // Services don't get meta files to put attributes in
+        let written = |instance: &Instance| {
+            has_scripts.get(&instance.referent()) == Some(&true) && !is_service(&instance.class)
+        };
+
+        for instance in tree.descendants() {
+            if !written(instance) {
+                continue;
+            }
+
+            for (name, value) in &instance.properties {
+                let target = match value {
+                    Variant::Ref(target) if target.is_some() => *target,
+                    _ => continue,
+                };
+
+                let target_instance = match tree.get_by_ref(target) {
+                    Some(target_instance) if written(target_instance) => target_instance,
+                    _ => {
+                        warn!(
+                            "{}.{} points at an instance that isn't written, not preserving it",
+                            full_name(tree, instance),
+                            name
+                        );
+                        continue;
+                    }
+                };
+
+                let id = links.ids.entry(target).or_insert_with(|| {
+                    let name = full_name(tree, target_instance);
+                    let mut id = name.clone();
+                    let mut index = 2;
+                    while !taken_ids.insert(id.clone()) {
+                        id = format!("{} ({})", name, index);
+                        index += 1;
+                    }
+                    id
+                });
+
+                links
+                    .targets
+                    .entry(instance.referent())
+                    .or_default()
+                    .insert(format!("Rojo_Target_{}", name), id.clone());
+            }
+        }
+
+        links
+    }
+
+    /// The instance's own attributes, along with whatever links it to other instances.
+    fn attributes(&self, instance: &Instance) -> BTreeMap<String, Variant> {
+        let mut attributes = attributes(instance);
+
+        if let Some(id) = self.ids.get(&instance.referent()) {
+            attributes.insert("Rojo_Id".to_string(), Variant::String(id.clone()));
+        }
+
+        if let Some(targets) = self.targets.get(&instance.referent()) {
+            for (name, id) in targets {
+                attributes.insert(name.clone(), Variant::String(id.clone()));
+            }
+        }
+
+        attributes
+    }
+}
+
+// Values of the RunContext enum
+const RUN_CONTEXT_LEGACY: u32 = 0;
+const RUN_CONTEXT_CLIENT: u32 = 2;
+
+fn run_context(instance: &Instance) -> Option<u32> {
+    match instance.properties.get(&ustr("RunContext")) {
+        Some(Variant::Enum(value)) => Some(value.to_u32()),
+        _ => None,
+    }
+}
+
+// Kept for every class, even ones whose other properties aren't
+fn common_properties(instance: &Instance, options: &ConvertOptions) -> BTreeMap<String, Variant> {
+    let mut properties = BTreeMap::new();
+
+    if let Some(Variant::Bool(false)) = instance.properties.get(&ustr("Archivable")) {
+        properties.insert("Archivable".to_string(), Variant::Bool(false));
+    }
+
+    if options.preserve_guids {
+        for name in IDENTITY_PROPERTIES {
+            if let Some(value) = instance.properties.get(&ustr(name)) {
+                properties.insert(name.to_string(), value.clone());
+            }
+        }
+    }
+
+    properties
+}
+
+fn script_properties(instance: &Instance, options: &ConvertOptions) -> BTreeMap<String, Variant> {
+    let mut properties = common_properties(instance, options);
+
+    // Scripts run unless told otherwise, so only a disabled one needs remembering
+    if let Some(Variant::Bool(true)) = instance.properties.get(&ustr("Disabled")) {
+        properties.insert("Disabled".to_string(), Variant::Bool(true));
+    }
+
+    match run_context(instance) {
+        None | Some(RUN_CONTEXT_LEGACY) => {}
+        Some(context) => {
+            properties.insert(
+                "RunContext".to_string(),
+                Variant::Enum(Enum::from_u32(context)),
+            );
+        }
+    }
+
+    properties
+}
+
+// Lua and JSON only agree on some literals, so anything else is left as a script
+fn json_module_source(source: &str) -> Option<&str> {
+    let literal = source.trim().strip_prefix("return")?;
+    if !literal.starts_with(|c: char| c.is_whitespace() || matches!(c, '{' | '[' | '"')) {
+        return None;
+    }
+
+    let literal = literal.trim();
+    let literal = literal.strip_suffix(';').unwrap_or(literal).trim_end();
+    serde_json::from_str::<serde_json::Value>(literal).ok()?;
+    Some(literal)
+}
+
+// Corrupted places can have these in their scripts, and Lua tools don't expect either
+fn has_source_junk(source: &str) -> bool {
+    source.starts_with('\u{feff}') || source.contains('\0')
+}
+
+fn script_source<'a>(source: &'a str, options: &ConvertOptions) -> Cow<'a, [u8]> {
+    let mut source = Cow::Borrowed(source);
+
+    if options.sanitize_script_sources && has_source_junk(&source) {
+        source = Cow::Owned(source.trim_start_matches('\u{feff}').replace('\0', ""));
+    }
+
+    if options.normalize_newlines && source.contains('\r') {
+        source = Cow::Owned(source.replace("\r\n", "\n").replace('\r', "\n"));
+    }
+
+    if options.trailing_newline && !source.is_empty() && !source.ends_with('\n') {
+        source.to_mut().push('\n');
+    }
+
+    match source {
+        Cow::Borrowed(source) => Cow::Borrowed(source.as_bytes()),
+        Cow::Owned(source) => Cow::Owned(source.into_bytes()),
+    }
+}
+
+fn meta_contents(meta: &MetaFile, options: &ConvertOptions) -> Cow<'static, [u8]> {
+    Cow::Owned(
+        to_string_indented(meta, &options.json_indent)
+            .expect("couldn't serialize meta")
+            .into_bytes(),
+    )
+}
+
+struct TreeIterator<'a, I, P>
+where
+    I: InstructionReader + ?Sized,
+    P: FnMut(&ProgressEvent) + ?Sized,
+{
     instruction_reader: &'a mut I,
+    options: &'a ConvertOptions,
     path: &'a Path,
+    progress: &'a mut P,
+    // Where to put sourcemap nodes for the instances visited, if one's being made
+    sourcemap: Option<&'a mut Vec<SourcemapNode>>,
+    summary: &'a mut ConversionSummary,
     tree: &'a WeakDom,
+    links: &'a RefLinks,
+    // Where `ConvertOptions::renames` sends instances, by referent
+    renames: &'a HashMap<Ref, PathBuf>,
+    // How far below the root's children this iterator's instances are
+    depth: usize,
+}
+
+/// Where each instance `ConvertOptions::renames` names is sent, by referent.
+fn rename_targets(tree: &WeakDom, options: &ConvertOptions) -> HashMap<Ref, PathBuf> {
+    let mut targets = HashMap::new();
+    if options.renames.is_empty() {
+        return targets;
+    }
+
+    // `check_tree` turns these down before anything's written, but without it renames leading
+    // outside the source folder are ignored, and the first one sent to a path keeps it
+    let mut sent_to: HashMap<&Path, String> = HashMap::new();
+
+    let mut unmatched: BTreeSet<&str> = options.renames.keys().map(String::as_str).collect();
+    for instance in tree.descendants() {
+        if instance.referent() == tree.root_ref() {
+            continue;
+        }
+
+        let name = full_name(tree, instance);
+        if let Some(path) = options.renames.get(&name) {
+            if unmatched.remove(name.as_str()) {
+                if !stays_inside(path) {
+                    warn!(
+                        "{} is renamed to {}, which isn't inside the source folder, leaving it \
+                         where it is",
+                        name,
+                        path.display()
+                    );
+                    continue;
+                }
+
+                if let Some(first) = sent_to.get(path.as_path()) {
+                    warn!(
+                        "{} and {} are both renamed to {}, leaving {} where it is",
+                        first,
+                        name,
+                        path.display(),
+                        name
+                    );
+                    continue;
+                }
+
+                sent_to.insert(path, name);
+                targets.insert(instance.referent(), path.clone());
+            } else {
+                warn!(
+                    "More than one instance is named {}, only renaming the first",
+                    name
+                );
+            }
+        }
+    }
+
+    for name in unmatched {
+        warn!("Nothing named {} was found to rename", name);
+    }
+
+    targets
+}
+
+/// The instance's path from the root, like `Workspace.Map.Part`.
+fn full_name(tree: &WeakDom, instance: &Instance) -> String {
+    let mut names = vec![instance.name.as_str()];
+    let mut parent = instance.parent();
+
+    while let Some(ancestor) = tree.get_by_ref(parent) {
+        if ancestor.parent().is_none() {
+            break;
+        }
+
+        names.push(&ancestor.name);
+        parent = ancestor.parent();
+    }
+
+    names.reverse();
+    names.join(".")
+}
+
+/// Quotes `field` for a CSV row if it has a comma, quote or line break in it.
+pub fn csv_field(field: &str) -> Cow<str> {
+    if field.contains(|c: char| matches!(c, ',' | '"' | '\n' | '\r')) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Turns a `LocalizationTable`'s `Contents` into the CSV format Rojo syncs them from.
+fn localization_csv(instance: &Instance) -> serde_json::Result<String> {
+    let entries: Vec<LocalizationEntry> = match instance.properties.get(&ustr("Contents")) {
+        Some(Variant::String(contents)) if !contents.is_empty() => serde_json::from_str(contents)?,
+        _ => Vec::new(),
+    };
+
+    let locales: BTreeSet<&str> = entries
+        .iter()
+        .flat_map(|entry| entry.values.keys().map(String::as_str))
+        .collect();
+
+    let mut header = vec!["Key", "Source", "Context", "Example"];
+    header.extend(&locales);
+
+    let mut csv = header.join(",");
+    csv.push('\n');
+
+    for entry in &entries {
+        let mut row = vec![
+            csv_field(entry.key.as_deref().unwrap_or_default()),
+            csv_field(entry.source.as_deref().unwrap_or_default()),
+            csv_field(entry.context.as_deref().unwrap_or_default()),
+            csv_field(entry.example.as_deref().unwrap_or_default()),
+        ];
+
+        for locale in &locales {
+            row.push(csv_field(
+                entry
+                    .values
+                    .get(*locale)
+                    .map(String::as_str)
+                    .unwrap_or_default(),
+            ));
+        }
+
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+
+    Ok(csv)
+}
+
+// For whatever can't be written any other way
+fn model_file(
+    tree: &WeakDom,
+    referent: Ref,
+    sanitized_name: &str,
+    options: &ConvertOptions,
+) -> (String, Vec<u8>) {
+    let deduped = if options.dedupe_shared_strings {
+        with_shared_string_paths(tree, referent)
+    } else {
+        None
+    };
+    let (tree, referent) = match &deduped {
+        Some((deduped_tree, deduped_referent)) => (deduped_tree, *deduped_referent),
+        None => (tree, referent),
+    };
+
+    let mut model = Vec::new();
+
+    if options.binary_fallback {
+        rbx_binary::to_writer(&mut model, tree, &[referent]).expect("couldn't encode model");
+        (format!("{}.rbxm", sanitized_name), model)
+    } else {
+        rbx_xml::to_writer_default(&mut model, tree, &[referent]).expect("couldn't encode model");
+        let filename = format!("{}{}", sanitized_name, options.model_extension);
+        (filename, model)
+    }
+}
+
+fn writes_model_file(instruction: &Instruction) -> bool {
+    match instruction {
+        Instruction::CreateFile { filename, .. } => matches!(
+            filename
+                .extension()
+                .and_then(|extension| extension.to_str()),
+            Some("rbxmx" | "rbxm")
+        ),
+        _ => false,
+    }
+}
+
+// An empty instance of the class in place of a model file bigger than `max_file_bytes`
+fn model_stub<'a>(
+    tree: &WeakDom,
+    instruction: &Instruction<'a>,
+    child: &Instance,
+    sanitized_name: &str,
+    max_file_bytes: usize,
+    options: &ConvertOptions,
+) -> Option<Instruction<'a>> {
+    let (filename, contents) = match instruction {
+        Instruction::CreateFile { filename, contents } if contents.len() > max_file_bytes => {
+            (filename, contents)
+        }
+        _ => return None,
+    };
+
+    let name = filename.file_name()?.to_string_lossy();
+    if !name.ends_with(".rbxmx") && !name.ends_with(".rbxm") {
+        return None;
+    }
+
+    warn!(
+        "{} would be a {} byte model, writing an empty {} instead",
+        full_name(tree, child),
+        contents.len(),
+        child.class
+    );
+
+    let stub = JsonModel {
+        class_name: child.class.to_string(),
+        properties: BTreeMap::new(),
+        attributes: BTreeMap::new(),
+        tags: Vec::new(),
+    };
+    Some(Instruction::CreateFile {
+        filename: Cow::Owned(filename.with_file_name(format!("{}.model.json", sanitized_name))),
+        contents: Cow::Owned(
+            to_string_indented(&stub, &options.json_indent)
+                .expect("couldn't serialize model")
+                .into_bytes(),
+        ),
+    })
 }
 
 fn repr_instance<'a>(
+    tree: &'a WeakDom,
     base: &'a Path,
     child: &'a Instance,
+    sanitized_name: &str,
     has_scripts: &'a HashMap<Ref, bool>,
+    links: &RefLinks,
+    options: &ConvertOptions,
 ) -> Option<(Vec<Instruction<'a>>, Cow<'a, Path>)> {
     if has_scripts.get(&child.referent()) != Some(&true) {
         return None;
     }
 
-    let sanitized_name = sanitize_name(&child.name);
+    // Keep track of the real name if it couldn't be used as is
+    let original_name = if sanitized_name != child.name {
+        Some(child.name.clone())
+    } else {
+        None
+    };
+
+    // Filtered out classes can still be in the way of what's included, so they fall through
+    // to being plain folders, as does anything in the way of scripts that isn't a script itself
+    let scripts_inside = child
+        .children()
+        .iter()
+        .any(|id| has_scripts.get(id) == Some(&true));
+    let strategy = match class_strategy(&child.class, options) {
+        _ if !options.is_included(&child.class) => None,
+        Some(ClassStrategy::Txt | ClassStrategy::JsonModel | ClassStrategy::Csv)
+            if scripts_inside =>
+        {
+            None
+        }
+        strategy => strategy,
+    };
 
-    match child.class.as_str() {
-        "Folder" => {
-            let folder_path = base.join(&sanitized_name);
+    match (strategy, child.class.as_str()) {
+        (None, "Folder") => {
+            let folder_path = base.join(sanitized_name);
             let owned: Cow<'a, Path> = Cow::Owned(folder_path);
             let clone = owned.clone();
             Some((
@@ -78,15 +747,16 @@ fn repr_instance<'a>(
                     Instruction::CreateFolder { folder: clone },
                     Instruction::CreateFile {
                         filename: Cow::Owned(owned.join("init.meta.json")),
-                        contents: Cow::Owned(
-                            serde_json::to_string_pretty(&MetaFile {
+                        contents: meta_contents(
+                            &MetaFile {
                                 class_name: None,
-                                // properties: BTreeMap::new(),
+                                name: original_name,
+                                properties: non_default_properties(child, options),
+                                tags: tags(child),
+                                attributes: links.attributes(child),
                                 ignore_unknown_instances: true,
-                            })
-                            .unwrap()
-                            .as_bytes()
-                            .into(),
+                            },
+                            options,
                         ),
                     },
                 ],
@@ -94,110 +764,229 @@ fn repr_instance<'a>(
             ))
         }
 
-        "Script" | "LocalScript" | "ModuleScript" => {
+        (Some(ClassStrategy::Lua), _) => {
             let extension = match child.class.as_str() {
+                // A Script can run on the client too, which it says with its RunContext
+                "Script" if run_context(child) == Some(RUN_CONTEXT_CLIENT) => ".client",
                 "Script" => ".server",
                 "LocalScript" => ".client",
+                // Modules have no suffix, so one with children gets a plain init.lua
                 "ModuleScript" => "",
                 _ => unreachable!(),
             };
+            let script_extension = options.script_extension();
+            let script_name = format!("{}{}.{}", sanitized_name, extension, script_extension);
+            let init_name = format!("init{}.{}", extension, script_extension);
 
             let source = match child.properties.get(&ustr("Source")).expect("no Source") {
-                Variant::String(value) => value,
+                Variant::String(value) => script_source(value, options),
                 _ => unreachable!(),
-            }
-            .as_bytes();
+            };
+
+            let meta = MetaFile {
+                class_name: None,
+                name: original_name,
+                properties: script_properties(child, options),
+                tags: tags(child),
+                attributes: links.attributes(child),
+                ignore_unknown_instances: true,
+            };
 
             if child.children().is_empty() {
-                Some((
-                    vec![Instruction::CreateFile {
-                        filename: Cow::Owned(base.join(format!("{}{}.lua", sanitized_name, extension))),
-                        contents: Cow::Borrowed(source),
-                    }],
+                let json = match child.properties.get(&ustr("Source")) {
+                    Some(Variant::String(value))
+                        if options.detect_json_modules && child.class == "ModuleScript" =>
+                    {
+                        json_module_source(value)
+                    }
+                    _ => None,
+                };
+
+                let mut instructions = vec![match json {
+                    Some(json) => Instruction::CreateFile {
+                        filename: Cow::Owned(base.join(format!("{}.json", sanitized_name))),
+                        contents: Cow::Borrowed(json.as_bytes()),
+                    },
+                    None => Instruction::CreateFile {
+                        filename: Cow::Owned(base.join(&script_name)),
+                        contents: source,
+                    },
+                }];
+
+                if meta.has_metadata() {
+                    instructions.push(Instruction::CreateFile {
+                        filename: Cow::Owned(base.join(format!("{}.meta.json", sanitized_name))),
+                        contents: meta_contents(&meta, options),
+                    });
+                }
+
+                return Some((instructions, Cow::Borrowed(base)));
+            }
+
+            let script_children_count = child
+                .children()
+                .iter()
+                .filter(|id| has_scripts.get(id) == Some(&true))
+                .count();
+
+            // Without script children there's nothing to put in a folder, so the script stays a
+            // file and its meta file keeps the other children from being deleted
+            if script_children_count == 0 {
+                return Some((
+                    vec![
+                        Instruction::CreateFile {
+                            filename: Cow::Owned(base.join(&script_name)),
+                            contents: source,
+                        },
+                        Instruction::CreateFile {
+                            filename: Cow::Owned(
+                                base.join(format!("{}.meta.json", sanitized_name)),
+                            ),
+                            contents: meta_contents(&meta, options),
+                        },
+                    ],
                     Cow::Borrowed(base),
-                ))
-            } else {
-                let meta_contents = Cow::Owned(
-                    serde_json::to_string_pretty(&MetaFile {
-                        class_name: None,
-                        // properties: BTreeMap::new(),
-                        ignore_unknown_instances: true,
-                    })
-                    .expect("couldn't serialize meta")
-                    .as_bytes()
-                    .into(),
-                );
+                ));
+            }
 
-                let script_children_count = child
-                    .children()
-                    .iter()
-                    .filter(|id| has_scripts.get(id) == Some(&true))
-                    .count();
+            // Otherwise the script becomes a folder with its source in an init file
+            let folder_path: Cow<'a, Path> = Cow::Owned(base.join(sanitized_name));
+            let mut instructions = vec![
+                Instruction::CreateFolder {
+                    folder: folder_path.clone(),
+                },
+                Instruction::CreateFile {
+                    filename: Cow::Owned(folder_path.join(&init_name)),
+                    contents: source,
+                },
+            ];
 
-                let total_children_count = child.children().len();
-            let folder_path: Cow<'a, Path> = Cow::Owned(base.join(&sanitized_name));
+            // A folder of only scripts doesn't need a meta file unless there's metadata
+            if script_children_count < child.children().len() || meta.has_metadata() {
+                instructions.push(Instruction::CreateFile {
+                    filename: Cow::Owned(folder_path.join("init.meta.json")),
+                    contents: meta_contents(&meta, options),
+                });
+            }
 
-                // If there's no script children, make a named meta file
-                // If there's some script children, make a folder with a meta file
-                // If there's only script children, don't bother with a meta file at all
-                // TODO: Lot of redundant code here
-                match script_children_count {
-                    _ if script_children_count == total_children_count => Some((
-                        vec![
-                            Instruction::CreateFolder {
-                                folder: folder_path.clone(),
-                            },
-                            Instruction::CreateFile {
-                                filename: Cow::Owned(
-                                    folder_path.join(format!("init{}.lua", extension)),
-                                ),
-                                contents: Cow::Borrowed(source),
-                            },
-                        ],
-                        folder_path,
-                    )),
-
-                    0 => Some((
-                        vec![
-                            Instruction::CreateFile {
-                                filename: Cow::Owned(
-                                    base.join(format!("{}{}.lua", sanitized_name, extension)),
-                                ),
-                                contents: Cow::Borrowed(source),
-                            },
-                            Instruction::CreateFile {
-                                filename: Cow::Owned(
-                                    base.join(format!("{}.meta.json", sanitized_name)),
-                                ),
-                                contents: meta_contents,
-                            },
-                        ],
-                        Cow::Borrowed(base),
-                    )),
-
-                    _ => Some((
-                        vec![
-                            Instruction::CreateFolder {
-                                folder: folder_path.clone(),
-                            },
-                            Instruction::CreateFile {
-                                filename: Cow::Owned(
-                                    folder_path.join(format!("init{}.lua", extension)),
-                                ),
-                                contents: Cow::Borrowed(source),
-                            },
-                            Instruction::CreateFile {
-                                filename: Cow::Owned(folder_path.join("init.meta.json")),
-                                contents: meta_contents,
-                            },
-                        ],
-                        folder_path,
-                    )),
+            Some((instructions, folder_path))
+        }
+
+        (Some(ClassStrategy::Csv), _) => {
+            let meta = MetaFile {
+                class_name: None,
+                name: original_name,
+                properties: common_properties(child, options),
+                tags: tags(child),
+                attributes: links.attributes(child),
+                ignore_unknown_instances: true,
+            };
+
+            let mut instructions = match localization_csv(child) {
+                Ok(csv) => vec![Instruction::CreateFile {
+                    filename: Cow::Owned(base.join(format!("{}.csv", sanitized_name))),
+                    contents: Cow::Owned(csv.into_bytes()),
+                }],
+
+                Err(error) => {
+                    warn!(
+                        "Couldn't decode the contents of {}, writing it as a model instead: {}",
+                        full_name(tree, child),
+                        error
+                    );
+
+                    let (filename, model) =
+                        model_file(tree, child.referent(), sanitized_name, options);
+
+                    vec![Instruction::CreateFile {
+                        filename: Cow::Owned(base.join(filename)),
+                        contents: Cow::Owned(model),
+                    }]
                 }
+            };
+
+            if meta.has_metadata() {
+                instructions.push(Instruction::CreateFile {
+                    filename: Cow::Owned(base.join(format!("{}.meta.json", sanitized_name))),
+                    contents: meta_contents(&meta, options),
+                });
             }
+
+            Some((instructions, Cow::Borrowed(base)))
+        }
+
+        (Some(ClassStrategy::Txt), _) => {
+            let value = match child.properties.get(&ustr("Value")) {
+                Some(Variant::String(value)) => value.as_str(),
+                _ => "",
+            };
+
+            Some((
+                vec![
+                    Instruction::CreateFile {
+                        filename: Cow::Owned(base.join(format!("{}.txt", sanitized_name))),
+                        contents: Cow::Borrowed(value.as_bytes()),
+                    },
+                    Instruction::CreateFile {
+                        filename: Cow::Owned(base.join(format!("{}.meta.json", sanitized_name))),
+                        contents: meta_contents(
+                            &MetaFile {
+                                class_name: Some(child.class.to_string()),
+                                name: original_name,
+                                properties: common_properties(child, options),
+                                tags: tags(child),
+                                attributes: links.attributes(child),
+                                ignore_unknown_instances: true,
+                            },
+                            options,
+                        ),
+                    },
+                ],
+                Cow::Borrowed(base),
+            ))
+        }
+
+        (Some(ClassStrategy::JsonModel), _) => {
+            let (filename, contents) = if child.children().is_empty() && fits_json_model(child) {
+                let model = JsonModel {
+                    class_name: child.class.to_string(),
+                    properties: non_default_properties(child, options),
+                    attributes: links.attributes(child),
+                    tags: tags(child),
+                };
+
+                (
+                    format!("{}.model.json", sanitized_name),
+                    to_string_indented(&model, &options.json_indent)
+                        .expect("couldn't serialize model")
+                        .into_bytes(),
+                )
+            } else {
+                model_file(tree, child.referent(), sanitized_name, options)
+            };
+
+            Some((
+                vec![Instruction::CreateFile {
+                    filename: Cow::Owned(base.join(filename)),
+                    contents: Cow::Owned(contents),
+                }],
+                Cow::Borrowed(base),
+            ))
+        }
+
+        (Some(ClassStrategy::Rbxmx), _) => {
+            let (filename, model) = model_file(tree, child.referent(), sanitized_name, options);
+
+            Some((
+                vec![Instruction::CreateFile {
+                    filename: Cow::Owned(base.join(filename)),
+                    contents: Cow::Owned(model),
+                }],
+                Cow::Borrowed(base),
+            ))
         }
 
-        other_class => {
+        (None, other_class) => {
             // When all else fails, we can make a meta folder if there's scripts in it
             match get_class_descriptor(other_class) {
                 Some(reflected) => {
@@ -215,7 +1004,7 @@ fn repr_instance<'a>(
                             return None;
                         }
 
-                        let new_base: Cow<'a, Path> = Cow::Owned(base.join(&sanitized_name));
+                        let new_base: Cow<'a, Path> = Cow::Owned(base.join(sanitized_name));
                         let mut instructions = Vec::new();
 
                         if !NON_TREE_SERVICES.contains(other_class) {
@@ -239,10 +1028,17 @@ fn repr_instance<'a>(
             }
 
             // If there are scripts, we'll need to make a .meta.json folder
-            let folder_path: Cow<'a, Path> = Cow::Owned(base.join(&sanitized_name));
+            let folder_path: Cow<'a, Path> = Cow::Owned(base.join(sanitized_name));
             let meta = MetaFile {
                 class_name: Some(child.class.to_string()),
-                // properties: properties.into_iter().collect(),
+                name: original_name,
+                properties: if preserves_properties(&child.class) {
+                    non_default_properties(child, options)
+                } else {
+                    common_properties(child, options)
+                },
+                tags: tags(child),
+                attributes: links.attributes(child),
                 ignore_unknown_instances: true,
             };
 
@@ -253,12 +1049,7 @@ fn repr_instance<'a>(
                     },
                     Instruction::CreateFile {
                         filename: Cow::Owned(folder_path.join("init.meta.json")),
-                        contents: Cow::Owned(
-                            serde_json::to_string_pretty(&meta)
-                                .expect("couldn't serialize meta")
-                                .as_bytes()
-                                .into(),
-                        ),
+                        contents: meta_contents(&meta, options),
                     },
                 ],
                 folder_path,
@@ -267,111 +1058,952 @@ fn repr_instance<'a>(
     }
 }
 
-impl<'a, I: InstructionReader + ?Sized> TreeIterator<'a, I> {
+/// A folder for a top-level service holding nothing but an `init.meta.json`, see
+/// `ConvertOptions::services_only`. `None` for anything Rojo doesn't treat as a service.
+fn service_scaffold<'a>(
+    child: &Instance,
+    base: &Path,
+    sanitized_name: &str,
+    options: &ConvertOptions,
+) -> Option<(Vec<Instruction<'a>>, Cow<'a, Path>)> {
+    if !RESPECTED_SERVICES.contains(child.class.as_str()) && child.class.as_str() != "StarterPlayer"
+    {
+        return None;
+    }
+
+    let folder_path = base.join(sanitized_name);
+    let meta = MetaFile {
+        class_name: None,
+        name: None,
+        properties: BTreeMap::new(),
+        tags: Vec::new(),
+        attributes: BTreeMap::new(),
+        ignore_unknown_instances: true,
+    };
+
+    Some((
+        vec![
+            Instruction::add_to_tree(child, folder_path.clone()),
+            Instruction::CreateFolder {
+                folder: Cow::Owned(folder_path.clone()),
+            },
+            Instruction::CreateFile {
+                filename: Cow::Owned(folder_path.join("init.meta.json")),
+                contents: meta_contents(&meta, options),
+            },
+        ],
+        Cow::Owned(folder_path),
+    ))
+}
+
+/// Picks a file name for each child, numbering siblings that would otherwise share one.
+fn child_names(
+    tree: &WeakDom,
+    instance: &Instance,
+    has_scripts: &HashMap<Ref, bool>,
+    options: &ConvertOptions,
+) -> Vec<(Ref, String)> {
+    let mut taken_names = HashSet::new();
+
+    instance
+        .children()
+        .iter()
+        .map(|child_id| {
+            let child = tree.get_by_ref(*child_id).expect("got fake child id?");
+
+            // Without a name it'd be a hidden or nameless file, so it goes by its class instead
+            let name = if child.name.trim().is_empty() {
+                child.class.as_str()
+            } else {
+                child.name.as_str()
+            };
+            let mut sanitized_name = file_name(name, options);
+
+            // Siblings with the same name would overwrite each other, so number them in order
+            if has_scripts.get(child_id) == Some(&true) {
+                if taken_names.contains(&sanitized_name) {
+                    let mut index = 2;
+                    while taken_names.contains(&format!("{} ({})", sanitized_name, index)) {
+                        index += 1;
+                    }
+
+                    let unique_name = format!("{} ({})", sanitized_name, index);
+                    warn!(
+                        "{:?} has a sibling with the same name, writing it as {:?}",
+                        child.name, unique_name
+                    );
+                    sanitized_name = unique_name;
+                }
+
+                taken_names.insert(sanitized_name.clone());
+            }
+
+            (*child_id, sanitized_name)
+        })
+        .collect()
+}
+
+impl<'a, I, P> TreeIterator<'a, I, P>
+where
+    I: InstructionReader + ?Sized,
+    P: FnMut(&ProgressEvent) + ?Sized,
+{
     fn visit_instructions(&mut self, instance: &Instance, has_scripts: &HashMap<Ref, bool>) {
-        for child_id in instance.children() {
-            let child = self.tree.get_by_ref(*child_id).expect("got fake child id?");
+        for (child_id, sanitized_name) in
+            child_names(self.tree, instance, has_scripts, self.options)
+        {
+            self.visit_child(child_id, sanitized_name, has_scripts);
+        }
+    }
 
-            let sanitized_name = sanitize_name(&child.name);
-            let (instructions_to_create_base, path) = if child.class.as_str() == "StarterPlayer" {
-                // We can't respect StarterPlayer as a service, because then Rojo
-                // tries to delete StarterPlayerScripts and whatnot, which is not valid.
-                let folder_path: Cow<'a, Path> = Cow::Owned(self.path.join(&sanitized_name));
-                let mut instructions = Vec::new();
+    fn visit_child(
+        &mut self,
+        child_id: Ref,
+        sanitized_name: String,
+        has_scripts: &HashMap<Ref, bool>,
+    ) {
+        let child = self.tree.get_by_ref(child_id).expect("got fake child id?");
 
-                if has_scripts.get(child_id) == Some(&true) {
-                    instructions.push(Instruction::CreateFolder {
-                        folder: folder_path.clone(),
-                    });
+        // A renamed instance goes wherever it was sent, under the name it was given
+        let renames = self.renames;
+        let (base, sanitized_name) = match renames.get(&child_id) {
+            Some(target) => (
+                target.parent().unwrap_or_else(|| Path::new("")),
+                target.file_name().map_or(sanitized_name, |name| {
+                    sanitize_instance_name(&name.to_string_lossy(), self.options.name_substitute)
+                }),
+            ),
+            None => (self.path, sanitized_name),
+        };
 
-                    instructions.push(Instruction::AddToTree {
-                        name: child.name.clone(),
-                        partition: TreePartition {
-                            class_name: child.class.to_string(),
-                            children: child
-                                .children()
-                                .iter()
-                                .filter(|id| has_scripts.get(id) == Some(&true))
-                                .map(|child_id| {
-                                    let child = self.tree.get_by_ref(*child_id).unwrap();
-                                    (
-                                        child.name.clone(),
-                                        Instruction::partition(
-                                            &child,
-                                            folder_path.join(&sanitize_name(&child.name)),
-                                        ),
-                                    )
-                                })
-                                .collect(),
-                            ignore_unknown_instances: true,
-                            path: None,
-                        },
-                    })
+        // Past the max depth, whatever's left to write is kept in one model instead
+        let collapse = self
+            .options
+            .max_depth
+            .map_or(false, |max_depth| self.depth >= max_depth)
+            && has_scripts.get(&child_id) == Some(&true)
+            && !is_service(&child.class)
+            && child
+                .children()
+                .iter()
+                .any(|id| has_scripts.get(id) == Some(&true));
+
+        let (instructions_to_create_base, path) = if self.options.services_only {
+            match service_scaffold(child, base, &sanitized_name, self.options) {
+                Some(scaffold) => scaffold,
+                None => return,
+            }
+        } else if collapse {
+            let (filename, model) = model_file(self.tree, child_id, &sanitized_name, self.options);
+            self.summary.collapsed += 1;
+
+            (
+                vec![Instruction::CreateFile {
+                    filename: Cow::Owned(base.join(filename)),
+                    contents: Cow::Owned(model),
+                }],
+                Cow::Borrowed(base),
+            )
+        } else if child.class.as_str() == "StarterPlayer" {
+            // We can't respect StarterPlayer as a service, because then Rojo
+            // tries to delete StarterPlayerScripts and whatnot, which is not valid.
+            let folder_path: Cow<'a, Path> = Cow::Owned(base.join(&sanitized_name));
+            let mut instructions = Vec::new();
+
+            if has_scripts.get(&child_id) == Some(&true) {
+                instructions.push(Instruction::CreateFolder {
+                    folder: folder_path.clone(),
+                });
+
+                // Named the same way as when the children are written, so the paths line up
+                let mut children = BTreeMap::new();
+                for (grandchild_id, grandchild_name) in
+                    child_names(self.tree, child, has_scripts, self.options)
+                {
+                    if has_scripts.get(&grandchild_id) != Some(&true) {
+                        continue;
+                    }
+
+                    let grandchild = self.tree.get_by_ref(grandchild_id).unwrap();
+                    let partition =
+                        Instruction::partition(grandchild, folder_path.join(&grandchild_name));
+
+                    // A sibling with the same name would replace it, so it's keyed by its file
+                    let key = if children.contains_key(&grandchild.name) {
+                        grandchild_name
+                    } else {
+                        grandchild.name.clone()
+                    };
+                    children.insert(key, partition);
                 }
 
-                (instructions, folder_path)
-            } else {
-                match repr_instance(&self.path, child, has_scripts) {
-                    Some((instructions_to_create_base, path)) => {
-                        (instructions_to_create_base, path)
+                instructions.push(Instruction::AddToTree {
+                    name: child.name.clone(),
+                    partition: TreePartition {
+                        class_name: child.class.to_string(),
+                        children,
+                        ignore_unknown_instances: true,
+                        path: None,
+                    },
+                })
+            }
+
+            (instructions, folder_path)
+        } else {
+            match repr_instance(
+                self.tree,
+                base,
+                child,
+                &sanitized_name,
+                has_scripts,
+                self.links,
+                self.options,
+            ) {
+                Some((instructions_to_create_base, path)) => (instructions_to_create_base, path),
+                None => {
+                    if has_scripts.get(&child_id) == Some(&true) {
+                        self.summary.skipped.push(child.class.to_string());
+                        self.summary
+                            .unsupported
+                            .push((child.class.to_string(), full_name(self.tree, child)));
                     }
-                    None => continue,
+
+                    return;
                 }
-            };
+            }
+        };
+
+        // A model's only instance can be the source folder itself, if it made a folder at all
+        let (mut instructions_to_create_base, path) = if self.options.flatten_services
+            && child.parent() == self.tree.root_ref()
+            && self.tree.root().children().len() == 1
+            && *path != *base
+            && !is_service(&child.class)
+        {
+            (
+                flatten_into_parent(instructions_to_create_base, &path),
+                Cow::Borrowed(base),
+            )
+        } else {
+            (instructions_to_create_base, path)
+        };
+
+        // Nothing else might make the folders a rename sends the instance into
+        if renames.contains_key(&child_id) && !base.as_os_str().is_empty() {
+            instructions_to_create_base.insert(
+                0,
+                Instruction::CreateFolder {
+                    folder: Cow::Borrowed(base),
+                },
+            );
+        }
+
+        if let Some(max_file_bytes) = self.options.max_file_bytes {
+            for instruction in &mut instructions_to_create_base {
+                if let Some(stub) = model_stub(
+                    self.tree,
+                    instruction,
+                    child,
+                    &sanitized_name,
+                    max_file_bytes,
+                    self.options,
+                ) {
+                    *instruction = stub;
+                    self.summary.too_large.push(full_name(self.tree, child));
+                }
+            }
+        }
+
+        if let Some(shared_strings) = &mut self.summary.shared_strings {
+            if instructions_to_create_base.iter().any(writes_model_file) {
+                record_shared_strings(self.tree, child, shared_strings);
+            }
+        }
+
+        // Models nobody asked for are the last resort for instances nothing else can represent
+        let asked_for_model =
+            collapse || class_strategy(&child.class, self.options) == Some(ClassStrategy::Rbxmx);
+        if !asked_for_model && instructions_to_create_base.iter().any(writes_model_file) {
+            self.summary
+                .unsupported
+                .push((child.class.to_string(), full_name(self.tree, child)));
+        }
+
+        (self.progress)(&ProgressEvent::StartedInstance {
+            name: &child.name,
+            class: &child.class,
+        });
+
+        for instruction in &instructions_to_create_base {
+            if let Instruction::CreateFile { filename, contents } = instruction {
+                (self.progress)(&ProgressEvent::WroteFile {
+                    path: filename,
+                    bytes: contents.len(),
+                });
+            }
+        }
+
+        let mut node = if self.sourcemap.is_some() {
+            Some(SourcemapNode {
+                name: child.name.clone(),
+                class_name: child.class.to_string(),
+                file_paths: instructions_to_create_base
+                    .iter()
+                    .filter_map(|instruction| match instruction {
+                        Instruction::CreateFile { filename, .. } => {
+                            Some(filename.to_string_lossy().replace("\\", "/"))
+                        }
+                        _ => None,
+                    })
+                    .collect(),
+                children: Vec::new(),
+            })
+        } else {
+            None
+        };
+
+        self.summary.record(&instructions_to_create_base);
+        if sanitized_name != child.name {
+            debug!("renamed {:?} to {:?}", child.name, sanitized_name);
+            self.summary
+                .renamed
+                .push((child.name.clone(), sanitized_name.clone()));
+        }
 
-            self.instruction_reader
-                .read_instructions(instructions_to_create_base);
+        self.instruction_reader
+            .read_instructions(instructions_to_create_base);
 
-            TreeIterator {
-                instruction_reader: self.instruction_reader,
-                path: &path,
-                tree: self.tree,
+        if collapse || self.options.services_only {
+            if let (Some(siblings), Some(node)) = (self.sourcemap.as_deref_mut(), node) {
+                siblings.push(node);
             }
-            .visit_instructions(child, has_scripts);
+
+            return;
+        }
+
+        TreeIterator {
+            instruction_reader: self.instruction_reader,
+            options: self.options,
+            path: &path,
+            progress: self.progress,
+            sourcemap: node.as_mut().map(|node| &mut node.children),
+            summary: self.summary,
+            tree: self.tree,
+            links: self.links,
+            renames: self.renames,
+            depth: self.depth + 1,
+        }
+        .visit_instructions(child, has_scripts);
+
+        if let (Some(siblings), Some(node)) = (self.sourcemap.as_deref_mut(), node) {
+            siblings.push(node);
         }
     }
 }
 
+fn is_service(class_name: &str) -> bool {
+    get_class_descriptor(class_name).map_or(false, |descriptor| {
+        descriptor.tags.contains(&ClassTag::Service)
+    })
+}
+
+// Moves what was written into `folder` up into its parent, dropping the folder itself
+fn flatten_into_parent<'a>(
+    instructions: Vec<Instruction<'a>>,
+    folder: &Path,
+) -> Vec<Instruction<'a>> {
+    let parent = folder.parent().unwrap_or_else(|| Path::new(""));
+
+    instructions
+        .into_iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::CreateFolder { folder: created } if created == folder => None,
+            Instruction::CreateFile { filename, contents } => Some(Instruction::CreateFile {
+                filename: match filename.strip_prefix(folder) {
+                    Ok(rest) => Cow::Owned(parent.join(rest)),
+                    Err(_) => filename,
+                },
+                contents,
+            }),
+            other => Some(other),
+        })
+        .collect()
+}
+
+fn root_sourcemap(root: &Instance, children: Vec<SourcemapNode>) -> SourcemapNode {
+    SourcemapNode {
+        name: root.name.clone(),
+        class_name: root.class.to_string(),
+        file_paths: Vec::new(),
+        children,
+    }
+}
+
+fn content_url(value: &Variant) -> Option<&str> {
+    match value {
+        Variant::Content(content) => content.as_uri(),
+        Variant::ContentId(content) => Some(content.as_str()),
+        _ => None,
+    }
+}
+
+fn record_assets(tree: &WeakDom, instance: &Instance, assets: &mut AssetManifest) {
+    let urls: BTreeMap<String, String> = instance
+        .properties
+        .iter()
+        .filter_map(|(name, value)| {
+            content_url(value)
+                .filter(|url| !url.is_empty())
+                .map(|url| (name.to_string(), url.to_string()))
+        })
+        .collect();
+
+    if !urls.is_empty() {
+        assets
+            .entry(full_name(tree, instance))
+            .or_default()
+            .extend(urls);
+    }
+}
+
+fn shared_string_hash(value: &SharedString) -> String {
+    value
+        .hash()
+        .as_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Where `FileSystem::write_shared_strings` puts the payload, from the project's root
+fn shared_string_path(value: &SharedString) -> String {
+    format!("shared/{}.bin", shared_string_hash(value))
+}
+
+fn has_shared_strings(instance: &Instance) -> bool {
+    instance
+        .properties
+        .values()
+        .any(|value| matches!(value, Variant::SharedString(_)))
+}
+
+// A copy of `referent` and everything in it, with each `SharedString` swapped for the path to
+// where its payload's written. `None` if there aren't any to swap.
+fn with_shared_string_paths(tree: &WeakDom, referent: Ref) -> Option<(WeakDom, Ref)> {
+    if !tree.descendants_of(referent).any(has_shared_strings) {
+        return None;
+    }
+
+    let mut deduped = WeakDom::new(InstanceBuilder::new("DataModel"));
+    let copy = tree.clone_into_external(referent, &mut deduped);
+    let referents: Vec<Ref> = deduped
+        .descendants_of(copy)
+        .map(|instance| instance.referent())
+        .collect();
+
+    for referent in referents {
+        let instance = deduped.get_by_ref_mut(referent).unwrap();
+        for value in instance.properties.values_mut() {
+            if let Variant::SharedString(shared_string) = value {
+                let path = shared_string_path(shared_string);
+                *value = Variant::SharedString(SharedString::new(path.into_bytes()));
+            }
+        }
+    }
+
+    Some((deduped, copy))
+}
+
+// Notes down the `SharedString`s inside a model file as it's written
+fn record_shared_strings(tree: &WeakDom, instance: &Instance, shared_strings: &mut SharedStrings) {
+    for descendant in tree.descendants_of(instance.referent()) {
+        for (name, value) in &descendant.properties {
+            if let Variant::SharedString(value) = value {
+                let hash = shared_string_hash(value);
+                shared_strings
+                    .uses
+                    .entry(full_name(tree, descendant))
+                    .or_default()
+                    .insert(name.to_string(), hash.clone());
+                shared_strings
+                    .payloads
+                    .entry(hash)
+                    .or_insert_with(|| value.clone());
+            }
+        }
+    }
+}
+
+// Walks the whole tree, so it also picks up the assets for the manifest if one's being made
 fn check_has_scripts(
     tree: &WeakDom,
     instance: &Instance,
+    options: &ConvertOptions,
+    inside_excluded: bool,
+    summary: &mut ConversionSummary,
     has_scripts: &mut HashMap<Ref, bool>,
 ) -> bool {
+    if let Some(assets) = &mut summary.assets {
+        record_assets(tree, instance, assets);
+    }
+
+    let class = instance.class.as_str();
+    let inside_excluded =
+        inside_excluded || (options.exclude_descendants && options.is_excluded(class));
     let mut children_have_scripts = false;
 
     for child_id in instance.children() {
         let result = check_has_scripts(
             tree,
             tree.get_by_ref(*child_id).expect("fake child id?"),
+            options,
+            inside_excluded,
+            summary,
             has_scripts,
         );
 
         children_have_scripts = children_have_scripts || result;
     }
 
-    let result = match instance.class.as_str() {
-        "Script" | "LocalScript" | "ModuleScript" => true,
-        _ => children_have_scripts,
+    let strategy = class_strategy(class, options);
+    let writes_itself = strategy.is_some();
+
+    let included = !inside_excluded && options.is_included(class);
+    if writes_itself && !included {
+        summary.filtered += 1;
+    }
+
+    let mut result = if inside_excluded {
+        false
+    } else if included && (writes_itself || !options.include_classes.is_empty()) {
+        true
+    } else {
+        children_have_scripts
     };
 
+    // Rojo projects leave these out, even with something to write inside
+    if result && options.is_skipped(class) {
+        summary.skipped.push(class.to_string());
+        for descendant in tree.descendants_of(instance.referent()) {
+            has_scripts.insert(descendant.referent(), false);
+        }
+
+        result = false;
+    }
+
+    if result && included && options.sanitize_script_sources {
+        if let ("Script" | "LocalScript" | "ModuleScript", Some(Variant::String(source))) =
+            (class, instance.properties.get(&ustr("Source")))
+        {
+            if has_source_junk(source) {
+                let name = full_name(tree, instance);
+                warn!(
+                    "{} has null bytes or a byte order mark in its source, stripping them",
+                    name
+                );
+                summary.cleaned_scripts.push(name);
+            }
+        }
+    }
+
+    // Value instances without scripts inside are written as one model, descendants and all,
+    // and models written on purpose hold everything inside them
+    let whole_model = strategy == Some(ClassStrategy::Rbxmx);
+    let value_model = strategy == Some(ClassStrategy::JsonModel)
+        || (options.json_models && is_value_class(class));
+    if included && children_have_scripts && (whole_model || value_model) {
+        let has_script_inside = !whole_model
+            && tree.descendants_of(instance.referent()).any(|descendant| {
+                matches!(
+                    descendant.class.as_str(),
+                    "Script" | "LocalScript" | "ModuleScript"
+                )
+            });
+
+        if !has_script_inside {
+            for descendant in tree.descendants_of(instance.referent()) {
+                if descendant.referent() != instance.referent() {
+                    has_scripts.insert(descendant.referent(), false);
+                }
+            }
+        }
+    }
+
     has_scripts.insert(instance.referent(), result);
     result
 }
 
-pub fn process_instructions(tree: &WeakDom, instruction_reader: &mut dyn InstructionReader) {
-    let root = tree.root_ref();
-    let root_instance = tree.get_by_ref(root).expect("fake root id?");
+// Wally installs into `Packages`, keeping the real packages in `Packages/_Index`
+fn has_wally_packages(tree: &WeakDom) -> bool {
+    let child_named = |instance: &Instance, name: &str| {
+        instance
+            .children()
+            .iter()
+            .filter_map(|id| tree.get_by_ref(*id))
+            .find(|child| child.name == name)
+            .map(|child| child.referent())
+    };
+
+    tree.root()
+        .children()
+        .iter()
+        .filter_map(|id| tree.get_by_ref(*id))
+        .filter(|service| service.class == "ReplicatedStorage")
+        .filter_map(|service| child_named(service, "Packages"))
+        .any(|packages| child_named(tree.get_by_ref(packages).unwrap(), "_Index").is_some())
+}
+
+pub fn process_instructions(
+    tree: &WeakDom,
+    instruction_reader: &mut dyn InstructionReader,
+) -> ConversionSummary {
+    process_instructions_with_options(tree, instruction_reader, &ConvertOptions::default())
+}
+
+pub fn process_instructions_with_options(
+    tree: &WeakDom,
+    instruction_reader: &mut dyn InstructionReader,
+    options: &ConvertOptions,
+) -> ConversionSummary {
+    process_tree(tree, instruction_reader, options, &mut |_: &ProgressEvent| {})
+}
+
+/// Lists the instances below the root of `tree` as `Name (ClassName)` lines, indented two
+/// spaces a level. Like `ConvertOptions::max_depth`, the root's children are at depth 0 and
+/// nothing deeper than `max_depth` is listed.
+pub fn tree_listing(tree: &WeakDom, max_depth: Option<usize>) -> String {
+    fn list(
+        tree: &WeakDom,
+        instance: &Instance,
+        depth: usize,
+        max_depth: Option<usize>,
+        listing: &mut String,
+    ) {
+        for child_id in instance.children() {
+            let child = tree.get_by_ref(*child_id).expect("got fake child id?");
+            listing.push_str(&"  ".repeat(depth));
+            listing.push_str(&format!("{} ({})\n", child.name, child.class));
+
+            if max_depth.map_or(true, |max_depth| depth < max_depth) {
+                list(tree, child, depth + 1, max_depth, listing);
+            }
+        }
+    }
+
+    let mut listing = String::new();
+    list(tree, tree.root(), 0, max_depth, &mut listing);
+    listing
+}
+
+/// Checks that `tree` can be converted with `options` before anything's written, returning how
+/// many instances it has besides the root.
+pub fn check_tree(tree: &WeakDom, options: &ConvertOptions) -> Result<usize, ConvertError> {
+    if let Some((name, path)) = options.invalid_rename() {
+        return Err(ConvertError::InvalidRename {
+            name: name.to_string(),
+            path: path.to_path_buf(),
+        });
+    }
+
+    if let Some((first, second, path)) = options.rename_conflict() {
+        return Err(ConvertError::NameCollision {
+            first: first.to_string(),
+            second: second.to_string(),
+            path: path.to_path_buf(),
+        });
+    }
+
+    let root_ref = tree.root_ref();
+    let found = tree
+        .descendants_of(root_ref)
+        .filter(|instance| instance.referent() != root_ref)
+        .count();
+    if found < options.min_instances {
+        return Err(ConvertError::EmptyTree {
+            found,
+            minimum: options.min_instances,
+        });
+    }
+
+    Ok(found)
+}
+
+/// Works out what converting `tree` would write without writing any of it. The instructions can
+/// be looked over or filtered first, then carried out by handing them to a `FileSystem` with
+/// `read_instructions`, followed by `finish_instructions`.
+pub fn plan_instructions(
+    tree: &WeakDom,
+    options: &ConvertOptions,
+) -> (Vec<Instruction<'static>>, ConversionSummary) {
+    let mut plan = Vec::new();
+    let summary = process_instructions_with_options(tree, &mut plan, options);
+    (plan, summary)
+}
+
+/// Like `process_instructions_with_options`, but calls `progress` as instances and files
+/// are processed.
+pub fn process_instructions_with_progress(
+    tree: &WeakDom,
+    instruction_reader: &mut dyn InstructionReader,
+    options: &ConvertOptions,
+    progress: &mut dyn FnMut(&ProgressEvent),
+) -> ConversionSummary {
+    process_tree(tree, instruction_reader, options, progress)
+}
+
+/// Like `process_instructions_with_options`, but converts each child of the root (the services
+/// of a place) on its own thread, using up to `jobs` threads. Each child gets a forked
+/// instruction reader which is joined back in order, so the result doesn't depend on timing.
+pub fn process_instructions_parallel<R: ParallelInstructionReader>(
+    tree: &WeakDom,
+    instruction_reader: &mut R,
+    options: &ConvertOptions,
+    jobs: usize,
+) -> ConversionSummary {
+    process_instructions_parallel_with_progress(
+        tree,
+        instruction_reader,
+        options,
+        jobs,
+        &|_: &ProgressEvent| {},
+    )
+}
+
+/// Like `process_instructions_parallel`, but calls `progress` as instances and files are
+/// processed, from whichever thread processed them.
+pub fn process_instructions_parallel_with_progress<R: ParallelInstructionReader>(
+    tree: &WeakDom,
+    instruction_reader: &mut R,
+    options: &ConvertOptions,
+    jobs: usize,
+    progress: &(dyn Fn(&ProgressEvent) + Sync),
+) -> ConversionSummary {
+    let root_instance = tree.root();
     let path = PathBuf::new();
+    let TreeSetup {
+        mut summary,
+        has_scripts,
+        links,
+        renames,
+    } = TreeSetup::new(tree, options);
+
+    let work: Vec<(Ref, String, R)> = child_names(tree, root_instance, &has_scripts, options)
+        .into_iter()
+        .map(|(child_id, sanitized_name)| (child_id, sanitized_name, instruction_reader.fork()))
+        .collect();
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .expect("couldn't create thread pool");
+
+    let results: Vec<(R, ConversionSummary, Vec<SourcemapNode>)> = pool.install(|| {
+        work.into_par_iter()
+            .map(|(child_id, sanitized_name, mut forked)| {
+                let mut summary = ConversionSummary {
+                    shared_strings: if options.dedupe_shared_strings {
+                        Some(SharedStrings::default())
+                    } else {
+                        None
+                    },
+                    ..ConversionSummary::default()
+                };
+                let mut sourcemap = Vec::new();
 
-    let mut has_scripts = HashMap::new();
-    check_has_scripts(tree, root_instance, &mut has_scripts);
+                TreeIterator {
+                    instruction_reader: &mut forked,
+                    options,
+                    path: &path,
+                    progress: &mut |event: &ProgressEvent| progress(event),
+                    sourcemap: if options.sourcemap {
+                        Some(&mut sourcemap)
+                    } else {
+                        None
+                    },
+                    summary: &mut summary,
+                    tree,
+                    links: &links,
+                    renames: &renames,
+                    depth: 0,
+                }
+                .visit_child(child_id, sanitized_name, &has_scripts);
+
+                (forked, summary, sourcemap)
+            })
+            .collect()
+    });
+
+    let mut sourcemap = Vec::new();
+    for (forked, forked_summary, forked_sourcemap) in results {
+        instruction_reader.join(forked);
+        summary.merge(forked_summary);
+        sourcemap.extend(forked_sourcemap);
+    }
+
+    finish_tree(tree, instruction_reader, options, summary, sourcemap)
+}
+
+/// Converts the place or model at `input` into a Rojo project in the `output` folder, which is
+/// also what the project is named after.
+pub fn convert_file(
+    input: &Path,
+    output: &Path,
+    options: &ConvertOptions,
+) -> Result<ConversionSummary, ConvertError> {
+    let extension = decode::without_gz_extension(input)
+        .extension()
+        .map(|extension| extension.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let decoded = decode::decode_file(BufReader::new(File::open(input)?), &extension, options)?;
+
+    let mut filesystem = FileSystem::from_root_atomic(output.to_path_buf());
+    if let Some(name) = output.file_name() {
+        filesystem.set_project_name(name.to_string_lossy());
+    }
+
+    write_decoded(decoded, &mut filesystem, options)
+}
+
+/// Like `convert_file`, but for a file that's already in memory, like a download or an entry
+/// in a zip. XML is repaired by every sanitizer `options` leaves on, which is all of them by
+/// default.
+pub fn convert_bytes<B: Backend>(
+    data: &[u8],
+    format: PlaceFormat,
+    out: &mut FileSystem<B>,
+    options: &ConvertOptions,
+) -> Result<ConversionSummary, ConvertError> {
+    let decoded = decode::decode_bytes(data, format, options)?;
+    write_decoded(decoded, out, options)
+}
+
+fn write_decoded<B: Backend>(
+    decoded: DecodedFile,
+    filesystem: &mut FileSystem<B>,
+    options: &ConvertOptions,
+) -> Result<ConversionSummary, ConvertError> {
+    check_tree(&decoded.tree, options)?;
+    filesystem.set_model(decoded.is_model);
+    filesystem.set_json_indent(options.json_indent.clone());
+
+    let mut summary = process_instructions_with_options(&decoded.tree, filesystem, options);
+    if let Some(sourcemap) = &summary.sourcemap {
+        filesystem.write_sourcemap(sourcemap)?;
+    }
+
+    if let Some(assets) = &summary.assets {
+        filesystem.write_assets_manifest(assets)?;
+    }
+
+    if let Some(shared_strings) = &summary.shared_strings {
+        filesystem.write_shared_strings(shared_strings)?;
+    }
+
+    if summary.wally_packages {
+        filesystem.write_wally_manifest()?;
+    }
+
+    filesystem.flush()?;
+    summary.repairs = decoded.repairs;
+    Ok(summary)
+}
+
+fn process_tree<P: FnMut(&ProgressEvent) + ?Sized>(
+    tree: &WeakDom,
+    instruction_reader: &mut dyn InstructionReader,
+    options: &ConvertOptions,
+    progress: &mut P,
+) -> ConversionSummary {
+    let root_instance = tree.root();
+    let path = PathBuf::new();
+    let TreeSetup {
+        mut summary,
+        has_scripts,
+        links,
+        renames,
+    } = TreeSetup::new(tree, options);
+
+    let mut sourcemap = Vec::new();
 
     TreeIterator {
         instruction_reader,
+        options,
         path: &path,
+        progress,
+        sourcemap: if options.sourcemap {
+            Some(&mut sourcemap)
+        } else {
+            None
+        },
+        summary: &mut summary,
         tree,
+        links: &links,
+        renames: &renames,
+        depth: 0,
+    }
+    .visit_instructions(root_instance, &has_scripts);
+
+    finish_tree(tree, instruction_reader, options, summary, sourcemap)
+}
+
+/// What's worked out about the whole tree before any of it is visited, the same whether it's
+/// converted on one thread or many.
+struct TreeSetup {
+    summary: ConversionSummary,
+    has_scripts: HashMap<Ref, bool>,
+    links: RefLinks,
+    renames: HashMap<Ref, PathBuf>,
+}
+
+impl TreeSetup {
+    fn new(tree: &WeakDom, options: &ConvertOptions) -> Self {
+        let mut has_scripts = HashMap::new();
+        let mut summary = ConversionSummary {
+            assets: if options.assets_manifest {
+                Some(AssetManifest::new())
+            } else {
+                None
+            },
+            shared_strings: if options.dedupe_shared_strings {
+                Some(SharedStrings::default())
+            } else {
+                None
+            },
+            wally_packages: options.wally && has_wally_packages(tree),
+            ..ConversionSummary::default()
+        };
+        check_has_scripts(
+            tree,
+            tree.root(),
+            options,
+            false,
+            &mut summary,
+            &mut has_scripts,
+        );
+        let links = RefLinks::new(tree, &has_scripts);
+        let renames = rename_targets(tree, options);
+
+        Self {
+            summary,
+            has_scripts,
+            links,
+            renames,
+        }
+    }
+}
+
+// Once every instance has been visited
+fn finish_tree<I: InstructionReader + ?Sized>(
+    tree: &WeakDom,
+    instruction_reader: &mut I,
+    options: &ConvertOptions,
+    mut summary: ConversionSummary,
+    sourcemap: Vec<SourcemapNode>,
+) -> ConversionSummary {
+    if options.sourcemap {
+        summary.sourcemap = Some(root_sourcemap(tree.root(), sourcemap));
     }
-    .visit_instructions(&root_instance, &has_scripts);
 
     instruction_reader.finish_instructions();
+    summary
 }
@@ -0,0 +1,449 @@
+//! Repairs for broken rbxlx/rbxmx documents, meant to run before handing them to rbx_xml.
+use regex::Regex;
+use serde::Serialize;
+use std::{borrow::Cow, fmt};
+
+// How much text to keep on either side of a repair
+const SNIPPET_RADIUS: usize = 20;
+
+lazy_static::lazy_static! {
+    static ref PLACEHOLDER_RE: Regex = Regex::new(r"__RBX_PROTECTED_CHUNK_(\d+)__").unwrap();
+}
+
+fn is_valid_xml_codepoint(code: u32) -> bool {
+    match code {
+        0x9 | 0xA | 0xD => true,
+        0x20..=0xD7FF => true,
+        0xE000..=0xFFFD => true,
+        0x10000..=0x10FFFF => true,
+        _ => false,
+    }
+}
+
+/// Which sanitizer made a `Repair`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RepairKind {
+    InvalidCodepoint,
+    InvalidCharRef,
+    InvalidFloat,
+    OutsideRoot,
+}
+
+/// A single change one of the sanitizers made to the document.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Repair {
+    pub kind: RepairKind,
+    /// Where the change was made, in the text as it was handed to the sanitizer. The repairs
+    /// made while decoding a file are all in the file's text, before any sanitizer ran.
+    pub byte_offset: usize,
+    /// The text around the change, from before it was made.
+    pub context_snippet: String,
+    // How much text the change replaced, for moving later repairs' offsets back
+    #[serde(skip)]
+    replaced_len: usize,
+}
+
+impl Repair {
+    fn new(kind: RepairKind, text: &str, start: usize, end: usize) -> Self {
+        Self {
+            kind,
+            byte_offset: start,
+            context_snippet: snippet(text, start, end).to_string(),
+            replaced_len: end - start,
+        }
+    }
+
+    pub(crate) fn edit(&self) -> Edit {
+        Edit {
+            start: self.byte_offset,
+            removed: self.replaced_len,
+            // Floats are replaced with `0`, everything else is just taken out
+            inserted: if self.kind == RepairKind::InvalidFloat {
+                1
+            } else {
+                0
+            },
+        }
+    }
+}
+
+/// Where a sanitizer replaced `removed` bytes at `start` with `inserted` bytes of its own.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Edit {
+    start: usize,
+    removed: usize,
+    inserted: usize,
+}
+
+/// Where `offset` in text that `edits` were made to was before them, going by edits sorted by
+/// where they start. Offsets inside something an edit put in are moved to the edit's start.
+pub(crate) fn offset_before(offset: usize, edits: &[Edit]) -> usize {
+    let (mut removed, mut inserted) = (0, 0);
+    for edit in edits {
+        let start = edit.start + inserted - removed;
+        if offset < start {
+            break;
+        }
+
+        if offset < start + edit.inserted {
+            return edit.start;
+        }
+
+        removed += edit.removed;
+        inserted += edit.inserted;
+    }
+
+    offset + removed - inserted
+}
+
+/// The text from `start` to `end`, with a little on either side.
+pub(crate) fn snippet(text: &str, start: usize, end: usize) -> &str {
+    let mut from = start.saturating_sub(SNIPPET_RADIUS);
+    while !text.is_char_boundary(from) {
+        from -= 1;
+    }
+
+    let mut to = (end + SNIPPET_RADIUS).min(text.len());
+    while !text.is_char_boundary(to) {
+        to += 1;
+    }
+
+    &text[from..to]
+}
+
+/// Removes characters that aren't allowed in XML documents.
+pub fn sanitize_xml(text: &mut String) -> Vec<Repair> {
+    let source = text.as_str();
+    let repairs: Vec<Repair> = source
+        .char_indices()
+        .filter(|(_, c)| !is_valid_xml_codepoint(*c as u32))
+        .map(|(index, c)| {
+            Repair::new(
+                RepairKind::InvalidCodepoint,
+                source,
+                index,
+                index + c.len_utf8(),
+            )
+        })
+        .collect();
+
+    if !repairs.is_empty() {
+        text.retain(|c| is_valid_xml_codepoint(c as u32));
+    }
+
+    repairs
+}
+
+/// Cuts off anything before the first `<roblox` and after the last `</roblox>`, like editor junk
+/// or null padding. Whitespace and an XML declaration in front are fine and left alone.
+pub fn trim_outside_root(text: &mut String) -> Vec<Repair> {
+    const CLOSING_TAG: &str = "</roblox>";
+
+    let (start, end) = match (text.find("<roblox"), text.rfind(CLOSING_TAG)) {
+        (Some(start), Some(end)) if start < end => (start, end + CLOSING_TAG.len()),
+        _ => return Vec::new(),
+    };
+
+    // An XML declaration right before the root belongs there
+    let leading = &text[..start];
+    let start = match leading.rfind("<?xml") {
+        Some(declaration) if leading[declaration..].trim_end().ends_with("?>") => declaration,
+        _ => start,
+    };
+
+    let leading_junk = !text[..start].trim().is_empty();
+    let trailing_junk = !text[end..].trim().is_empty();
+
+    let mut repairs = Vec::new();
+    if leading_junk {
+        let mut repair = Repair::new(RepairKind::OutsideRoot, text, 0, start.min(SNIPPET_RADIUS));
+        repair.replaced_len = start;
+        repairs.push(repair);
+    }
+
+    if trailing_junk {
+        let mut repair = Repair::new(
+            RepairKind::OutsideRoot,
+            text,
+            end,
+            text.len().min(end + SNIPPET_RADIUS),
+        );
+        repair.replaced_len = text.len() - end;
+        repairs.push(repair);
+        text.truncate(end);
+    }
+
+    if leading_junk {
+        text.replace_range(..start, "");
+    }
+
+    repairs
+}
+
+/// Removes numeric character references (`&#0;`, `&#x1;`) to characters XML doesn't allow.
+pub fn strip_invalid_numeric_char_refs(text: &mut String) -> Vec<Repair> {
+    // Matches both decimal and hex numeric character references.
+    lazy_static::lazy_static! {
+        static ref NUMERIC_CHAR_REF_RE: Regex = Regex::new(r"&#(x[0-9A-Fa-f]+|[0-9]+);").unwrap();
+    }
+
+    let source = text.as_str();
+    let mut repairs = Vec::new();
+    let replaced = NUMERIC_CHAR_REF_RE.replace_all(source, |caps: &regex::Captures| {
+        let raw = &caps[1];
+        let value = if raw.starts_with('x') || raw.starts_with('X') {
+            u32::from_str_radix(&raw[1..], 16).ok()
+        } else {
+            raw.parse::<u32>().ok()
+        };
+
+        match value {
+            Some(code) if is_valid_xml_codepoint(code) => caps[0].to_string(),
+            _ => {
+                let whole = caps.get(0).unwrap();
+                repairs.push(Repair::new(
+                    RepairKind::InvalidCharRef,
+                    source,
+                    whole.start(),
+                    whole.end(),
+                ));
+                String::new()
+            }
+        }
+    });
+
+    if !repairs.is_empty() {
+        *text = replaced.into_owned();
+    }
+
+    repairs
+}
+
+/// Replaces NaN and infinity literals that rbx_xml can't parse with `0`, including inside
+/// `NumberSequence` and `NumberRange` values.
+pub fn replace_invalid_float_literals(text: &mut String) -> Vec<Repair> {
+    lazy_static::lazy_static! {
+        // An element whose whole value is a NaN or infinity, in any of the spellings that turn up
+        static ref INVALID_FLOAT_FIELD_RE: Regex = Regex::new(
+            r"(?i)<([a-z0-9_]+)[^>]*>\s*(-?nan(?:\([^)]*\))?|[-+]?1\.#(?:inf|ind|qnan|nan)[0-9]*|[-+]?inf(?:inity)?)\s*<"
+        )
+        .unwrap();
+        static ref NUMBER_LIST_RE: Regex = Regex::new(
+            r"<(?:NumberSequence|NumberRange)[^>]*>([^<]+)</(?:NumberSequence|NumberRange)>"
+        )
+        .unwrap();
+        static ref TOKEN_RE: Regex = Regex::new(r"\S+").unwrap();
+    }
+
+    let mut invalid = Vec::new();
+
+    for caps in INVALID_FLOAT_FIELD_RE.captures_iter(text) {
+        // Strings can hold anything
+        if matches!(&caps[1], "string" | "ProtectedString" | "Content" | "url") {
+            continue;
+        }
+
+        invalid.push(caps.get(2).unwrap().range());
+    }
+
+    for caps in NUMBER_LIST_RE.captures_iter(text) {
+        let list = caps.get(1).unwrap();
+        for token in TOKEN_RE.find_iter(list.as_str()) {
+            if !token.as_str().parse::<f64>().map_or(false, f64::is_finite) {
+                invalid.push(list.start() + token.start()..list.start() + token.end());
+            }
+        }
+    }
+
+    if invalid.is_empty() {
+        return Vec::new();
+    }
+
+    invalid.sort_by_key(|range| range.start);
+
+    let mut repairs = Vec::new();
+    let mut result = String::with_capacity(text.len());
+    let mut last = 0;
+    for range in invalid {
+        if range.start < last {
+            continue;
+        }
+
+        repairs.push(Repair::new(
+            RepairKind::InvalidFloat,
+            text,
+            range.start,
+            range.end,
+        ));
+        result.push_str(&text[last..range.start]);
+        result.push('0');
+        last = range.end;
+    }
+    result.push_str(&text[last..]);
+
+    *text = result;
+    repairs
+}
+
+/// How a document's text is encoded, going by its byte order mark.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextEncoding {
+    Utf8,
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+}
+
+impl TextEncoding {
+    /// Anything without a byte order mark is taken to be UTF-8.
+    pub fn detect(bytes: &[u8]) -> Self {
+        match bytes {
+            [0xEF, 0xBB, 0xBF, ..] => TextEncoding::Utf8Bom,
+            [0xFF, 0xFE, ..] => TextEncoding::Utf16Le,
+            [0xFE, 0xFF, ..] => TextEncoding::Utf16Be,
+            _ => TextEncoding::Utf8,
+        }
+    }
+
+    pub(crate) fn bom_len(self) -> usize {
+        match self {
+            TextEncoding::Utf8 => 0,
+            TextEncoding::Utf8Bom => 3,
+            TextEncoding::Utf16Le | TextEncoding::Utf16Be => 2,
+        }
+    }
+}
+
+impl fmt::Display for TextEncoding {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(match self {
+            TextEncoding::Utf8 => "UTF-8",
+            TextEncoding::Utf8Bom => "UTF-8 with a BOM",
+            TextEncoding::Utf16Le => "UTF-16LE",
+            TextEncoding::Utf16Be => "UTF-16BE",
+        })
+    }
+}
+
+/// Turns a document into UTF-8 without its byte order mark. Invalid UTF-8 is replaced
+/// with U+FFFD, but UTF-16 that can't be decoded gives back `None`.
+pub fn decode_text(mut bytes: Vec<u8>, encoding: TextEncoding) -> Option<String> {
+    bytes.drain(..encoding.bom_len());
+
+    match encoding {
+        TextEncoding::Utf8 | TextEncoding::Utf8Bom => match String::from_utf8(bytes) {
+            Ok(text) => Some(text),
+            Err(error) => {
+                log::warn!(
+                    "Replaced invalid UTF-8 bytes while reading XML; content was lossily decoded."
+                );
+                Some(String::from_utf8_lossy(error.as_bytes()).into_owned())
+            }
+        },
+
+        TextEncoding::Utf16Le | TextEncoding::Utf16Be => {
+            if bytes.len() % 2 != 0 {
+                return None;
+            }
+
+            let units = bytes.chunks_exact(2).map(|pair| {
+                if encoding == TextEncoding::Utf16Le {
+                    u16::from_le_bytes([pair[0], pair[1]])
+                } else {
+                    u16::from_be_bytes([pair[0], pair[1]])
+                }
+            });
+
+            char::decode_utf16(units).collect::<Result<_, _>>().ok()
+        }
+    }
+}
+
+/// Swaps `SharedString` and `BinaryString` contents out for placeholders so the other
+/// sanitizers can't touch them. Put them back with `restore_shared_sections`.
+pub fn protect_shared_sections(text: &str) -> (String, Vec<String>) {
+    lazy_static::lazy_static! {
+        // Text that already looks like a placeholder is protected too, or restoring would swap
+        // a chunk in for it
+        static ref OPEN_TAG_RE: Regex = Regex::new(
+            r"<(SharedString|BinaryString)(?:\s[^>]*)?/?>|__RBX_PROTECTED_CHUNK_\d+__"
+        )
+        .unwrap();
+    }
+
+    let mut protected: Vec<String> = Vec::new();
+    let mut result = String::with_capacity(text.len());
+    let mut last = 0;
+    let mut search = 0;
+    while let Some(caps) = OPEN_TAG_RE.captures(&text[search..]) {
+        let open = caps.get(0).unwrap();
+        let start = search + open.start();
+        let open_end = search + open.end();
+
+        let end = match caps.get(1) {
+            // Self-closing elements have nothing in them to protect
+            Some(_) if open.as_str().ends_with("/>") => {
+                search = open_end;
+                continue;
+            }
+
+            // Each element ends at the first closing tag with its own name, so neighbours aren't
+            // merged and the other tag's name showing up inside doesn't end it early
+            Some(name) => {
+                let closing = format!("</{}>", name.as_str());
+                match text[open_end..].find(&closing) {
+                    Some(offset) => open_end + offset + closing.len(),
+                    None => break,
+                }
+            }
+
+            None => open_end,
+        };
+
+        result.push_str(&text[last..start]);
+        result.push_str(&format!("__RBX_PROTECTED_CHUNK_{}__", protected.len()));
+        protected.push(text[start..end].to_string());
+        last = end;
+        search = end;
+    }
+    result.push_str(&text[last..]);
+
+    (result, protected)
+}
+
+/// Where `protect_shared_sections` swapped placeholders into `text` for `protected`.
+pub(crate) fn protected_edits(text: &str, protected: &[String]) -> Vec<Edit> {
+    let mut edits = Vec::new();
+    let (mut removed, mut inserted) = (0, 0);
+    for caps in PLACEHOLDER_RE.captures_iter(text) {
+        let placeholder = caps.get(0).unwrap();
+        let chunk_len = caps[1]
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| protected.get(index))
+            .map_or(0, String::len);
+
+        edits.push(Edit {
+            start: placeholder.start() + removed - inserted,
+            removed: chunk_len,
+            inserted: placeholder.len(),
+        });
+        removed += chunk_len;
+        inserted += placeholder.len();
+    }
+
+    edits
+}
+
+/// Puts back what `protect_shared_sections` took out.
+pub fn restore_shared_sections(text: &mut String, protected: Vec<String>) {
+    let replaced = PLACEHOLDER_RE.replace_all(text, |caps: &regex::Captures| {
+        let idx: usize = caps[1].parse().unwrap_or(usize::MAX);
+        protected.get(idx).cloned().unwrap_or_default()
+    });
+    // Nothing to put back means nothing was replaced, and no copy of the text is needed
+    if let Cow::Owned(replaced) = replaced {
+        *text = replaced;
+    }
+}
@@ -1,11 +1,22 @@
-use rbx_dom_weak::Instance;
-use serde::{Deserialize, Serialize, Serializer};
+use crate::sanitize::Repair;
+use rbx_dom_weak::{
+    types::{CFrame, SharedString, Variant},
+    Instance,
+};
+use serde::{ser::SerializeMap, Deserialize, Serialize, Serializer};
 use std::{
     borrow::Cow,
-    collections::BTreeMap,
-    path::{Path, PathBuf},
+    collections::{BTreeMap, HashMap},
+    path::{Component, Path, PathBuf},
+    time::Duration,
 };
 
+// Whether a relative path can only lead somewhere inside the folder it's joined onto
+pub(crate) fn stays_inside(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
 // Windows issues!
 fn replace_backslashes<S: Serializer>(
     path: &Option<PathBuf>,
@@ -21,6 +32,149 @@ fn replace_backslashes<S: Serializer>(
     }
 }
 
+fn serialize_seconds<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+/// Like `serde_json::to_string_pretty`, but indented with `indent` rather than two spaces.
+pub fn to_string_indented<T: Serialize + ?Sized>(
+    value: &T,
+    indent: &str,
+) -> serde_json::Result<String> {
+    let mut json = Vec::new();
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes());
+    let mut serializer = serde_json::Serializer::with_formatter(&mut json, formatter);
+    value.serialize(&mut serializer)?;
+    Ok(String::from_utf8(json).expect("serde_json wrote invalid UTF-8"))
+}
+
+// Sequences in the explicit form Rojo reads, with every keypoint spelled out so they can be
+// edited by hand
+#[derive(Serialize)]
+enum Sequence {
+    NumberSequence(Keypoints<NumberKeypoint>),
+    ColorSequence(Keypoints<ColorKeypoint>),
+}
+
+#[derive(Serialize)]
+struct Keypoints<T> {
+    keypoints: Vec<T>,
+}
+
+#[derive(Serialize)]
+struct NumberKeypoint {
+    time: f32,
+    value: f32,
+    envelope: f32,
+}
+
+#[derive(Serialize)]
+struct ColorKeypoint {
+    time: f32,
+    color: [f32; 3],
+}
+
+impl Sequence {
+    fn from_variant(value: &Variant) -> Option<Self> {
+        match value {
+            Variant::NumberSequence(sequence) => Some(Sequence::NumberSequence(Keypoints {
+                keypoints: sequence
+                    .keypoints
+                    .iter()
+                    .map(|keypoint| NumberKeypoint {
+                        time: keypoint.time,
+                        value: keypoint.value,
+                        envelope: keypoint.envelope,
+                    })
+                    .collect(),
+            })),
+            Variant::ColorSequence(sequence) => Some(Sequence::ColorSequence(Keypoints {
+                keypoints: sequence
+                    .keypoints
+                    .iter()
+                    .map(|keypoint| ColorKeypoint {
+                        time: keypoint.time,
+                        color: [keypoint.color.r, keypoint.color.g, keypoint.color.b],
+                    })
+                    .collect(),
+            })),
+            _ => None,
+        }
+    }
+}
+
+// A CFrame as the 12 numbers Rojo reads it from, the position and then the rotation row by row
+fn cframe_components(cframe: &CFrame) -> [f32; 12] {
+    let CFrame {
+        position,
+        orientation,
+    } = cframe;
+
+    [
+        position.x,
+        position.y,
+        position.z,
+        orientation.x.x,
+        orientation.x.y,
+        orientation.x.z,
+        orientation.y.x,
+        orientation.y.y,
+        orientation.y.z,
+        orientation.z.x,
+        orientation.z.y,
+        orientation.z.z,
+    ]
+}
+
+// Rojo looks up the type of a property, so geometry can be written as plain arrays too
+fn serialize_properties<S: Serializer>(
+    properties: &BTreeMap<String, Variant>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serialize_values(properties, true, serializer)
+}
+
+// Attributes have no type to look up, so only the values JSON can tell apart are left plain
+fn serialize_attributes<S: Serializer>(
+    attributes: &BTreeMap<String, Variant>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serialize_values(attributes, false, serializer)
+}
+
+// Rojo reads plain values for these, everything else needs its type spelled out
+fn serialize_values<S: Serializer>(
+    values: &BTreeMap<String, Variant>,
+    plain_geometry: bool,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut map = serializer.serialize_map(Some(values.len()))?;
+    for (name, value) in values {
+        match value {
+            Variant::Bool(value) => map.serialize_entry(name, value)?,
+            Variant::String(value) => map.serialize_entry(name, value)?,
+            Variant::Float32(value) => map.serialize_entry(name, value)?,
+            Variant::Float64(value) => map.serialize_entry(name, value)?,
+            Variant::Int32(value) => map.serialize_entry(name, value)?,
+            Variant::Int64(value) => map.serialize_entry(name, value)?,
+            Variant::NumberSequence(_) | Variant::ColorSequence(_) => {
+                map.serialize_entry(name, &Sequence::from_variant(value))?
+            }
+            Variant::Vector2(value) if plain_geometry => {
+                map.serialize_entry(name, &[value.x, value.y])?
+            }
+            Variant::Vector3(value) if plain_geometry => {
+                map.serialize_entry(name, &[value.x, value.y, value.z])?
+            }
+            Variant::CFrame(value) if plain_geometry => {
+                map.serialize_entry(name, &cframe_components(value))?
+            }
+            _ => map.serialize_entry(name, value)?,
+        }
+    }
+    map.end()
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct TreePartition {
     #[serde(rename = "$className")]
@@ -48,13 +202,71 @@ pub(crate) struct MetaFile {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub class_name: Option<String>,
 
-    // #[serde(rename = "properties")]
-    // #[serde(skip_serializing_if = "BTreeMap::is_empty")]
-    // pub properties: BTreeMap<String, RbxValue>,
+    /// The instance's real name, when it had to be changed to be a valid file name.
+    #[serde(rename = "name")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    #[serde(rename = "properties")]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    #[serde(serialize_with = "serialize_properties")]
+    pub properties: BTreeMap<String, Variant>,
+
+    #[serde(rename = "tags")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+
+    #[serde(rename = "attributes")]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    #[serde(serialize_with = "serialize_attributes")]
+    pub attributes: BTreeMap<String, Variant>,
+
     #[serde(rename = "ignoreUnknownInstances")]
     pub ignore_unknown_instances: bool,
 }
 
+impl MetaFile {
+    /// Whether there's anything in here besides `ignoreUnknownInstances`.
+    pub fn has_metadata(&self) -> bool {
+        self.class_name.is_some()
+            || self.name.is_some()
+            || !self.properties.is_empty()
+            || !self.tags.is_empty()
+            || !self.attributes.is_empty()
+    }
+}
+
+/// A Rojo `.model.json` file holding a single instance.
+#[derive(Clone, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct JsonModel {
+    pub class_name: String,
+
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    #[serde(serialize_with = "serialize_properties")]
+    pub properties: BTreeMap<String, Variant>,
+
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    #[serde(serialize_with = "serialize_attributes")]
+    pub attributes: BTreeMap<String, Variant>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+/// One entry of a `LocalizationTable`'s `Contents`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LocalizationEntry {
+    pub key: Option<String>,
+    pub context: Option<String>,
+    #[serde(alias = "examples")]
+    pub example: Option<String>,
+    pub source: Option<String>,
+    #[serde(default)]
+    pub values: BTreeMap<String, String>,
+}
+
 #[derive(Clone, Debug)]
 pub enum Instruction<'a> {
     AddToTree {
@@ -88,6 +300,24 @@ impl<'a> Instruction<'a> {
             path: Some(path),
         }
     }
+
+    /// Copies whatever's borrowed, so the instruction can outlive the tree it came from.
+    pub fn into_owned(self) -> Instruction<'static> {
+        match self {
+            Instruction::AddToTree { name, partition } => {
+                Instruction::AddToTree { name, partition }
+            }
+
+            Instruction::CreateFile { filename, contents } => Instruction::CreateFile {
+                filename: Cow::Owned(filename.into_owned()),
+                contents: Cow::Owned(contents.into_owned()),
+            },
+
+            Instruction::CreateFolder { folder } => Instruction::CreateFolder {
+                folder: Cow::Owned(folder.into_owned()),
+            },
+        }
+    }
 }
 
 pub trait InstructionReader {
@@ -100,3 +330,496 @@ pub trait InstructionReader {
         }
     }
 }
+
+/// Collects the instructions instead of carrying them out, see `plan_instructions`.
+impl InstructionReader for Vec<Instruction<'static>> {
+    fn read_instruction<'a>(&mut self, instruction: Instruction<'a>) {
+        self.push(instruction.into_owned());
+    }
+}
+
+/// An `InstructionReader` that can be split up to read parts of the tree on other threads.
+pub trait ParallelInstructionReader: InstructionReader + Send + Sized {
+    /// Makes a reader for part of the tree, which is handed back to `join` once it's done.
+    fn fork(&self) -> Self;
+    fn join(&mut self, forked: Self);
+}
+
+/// The Unicode normalization form instance names are put in before they become paths.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NameNormalization {
+    /// Names are used as they are.
+    #[default]
+    None,
+    /// Composed, which Windows and Linux usually use.
+    Nfc,
+    /// Decomposed, which macOS file systems use.
+    Nfd,
+}
+
+/// How instances of a class are written, see `ConvertOptions::class_strategies`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClassStrategy {
+    /// A `.txt` file of a `StringValue`'s `Value`.
+    Txt,
+    /// A `.model.json` file, or a model file when JSON can't hold the instance.
+    JsonModel,
+    /// A model file holding the instance and everything inside it.
+    Rbxmx,
+    /// A `.csv` file of a `LocalizationTable`'s entries.
+    Csv,
+    /// A script file of a script's `Source`.
+    Lua,
+}
+
+impl ClassStrategy {
+    /// Whether instances of `class` can be written this way.
+    pub fn fits(self, class: &str) -> bool {
+        match self {
+            ClassStrategy::Txt => class == "StringValue",
+            ClassStrategy::Csv => class == "LocalizationTable",
+            ClassStrategy::Lua => matches!(class, "Script" | "LocalScript" | "ModuleScript"),
+            ClassStrategy::JsonModel | ClassStrategy::Rbxmx => true,
+        }
+    }
+}
+
+/// Knobs for how a file is decoded and how `process_instructions_with_options` writes the tree.
+#[derive(Clone, Debug)]
+pub struct ConvertOptions {
+    /// Write scripts as `.luau` instead of `.lua`.
+    pub luau: bool,
+    /// What to replace characters that can't be in file names with.
+    pub name_substitute: char,
+    /// Normalize names before they become paths, so accented names are spelled the same on
+    /// every OS. The real name is kept in a meta file when it changes.
+    pub normalize_names: NameNormalization,
+    /// Write `StringValue`s as `.txt` files instead of leaving them out.
+    pub string_values_as_txt: bool,
+    /// Build a sourcemap of the instances written, see `ConversionSummary::sourcemap`.
+    pub sourcemap: bool,
+    /// Turn `\r\n` and lone `\r` line endings in scripts into `\n`.
+    pub normalize_newlines: bool,
+    /// End every non-empty script with a newline.
+    pub trailing_newline: bool,
+    /// If not empty, only instances of these classes are written, along with the folders
+    /// needed to reach them.
+    pub include_classes: Vec<String>,
+    /// Instances of these classes are never written, even if they're also included.
+    pub exclude_classes: Vec<String>,
+    /// Leave out everything under an excluded instance too, rather than just the instance itself.
+    pub exclude_descendants: bool,
+    /// Classes a Rojo project usually leaves out, which are never written along with anything
+    /// inside them, but end up in `ConversionSummary::skipped` if there was something to write.
+    /// `Camera`, `Terrain`, and the `PackageLink` inside each package by default, so packages
+    /// are written like any other instance.
+    pub skip_classes: Vec<String>,
+    /// Replace NaN and infinity literals rbx_xml can't read when decoding XML.
+    pub sanitize_floats: bool,
+    /// Strip numeric character references to characters XML doesn't allow when decoding XML.
+    pub sanitize_char_refs: bool,
+    /// Strip characters XML doesn't allow when decoding XML.
+    pub sanitize_codepoints: bool,
+    /// Cut off anything before the first `<roblox` and after the last `</roblox>` when decoding
+    /// XML, which some exporters leave junk or null padding in.
+    pub sanitize_outside_root: bool,
+    /// Strip null bytes and leading byte order marks from script sources, which corrupted places
+    /// can have and Lua tools choke on. See `ConversionSummary::cleaned_scripts`.
+    pub sanitize_script_sources: bool,
+    /// Write plain value instances like `IntValue`s as `.model.json` files, or `.rbxmx` files
+    /// when JSON can't hold them.
+    pub json_models: bool,
+    /// When the only instance at the top of the tree isn't a service, like in most models,
+    /// write its contents straight into the source folder instead of a folder of its own.
+    pub flatten_services: bool,
+    /// List the assets `Content` properties point to, see `ConversionSummary::assets`.
+    pub assets_manifest: bool,
+    /// How deep to walk the tree, with the root's children at depth 0. Instances at this depth
+    /// that still have something to write inside are written as `.rbxmx` files, whole.
+    pub max_depth: Option<usize>,
+    /// Collect each distinct `SharedString` in a model file once, see
+    /// `ConversionSummary::shared_strings`. The models hold the path it's written to instead.
+    pub dedupe_shared_strings: bool,
+    /// Write `ModuleScript`s that only return a JSON literal as `.json` files, which Rojo turns
+    /// back into modules returning the same value.
+    pub detect_json_modules: bool,
+    /// Write the subtrees that end up as models as binary `.rbxm` files instead of `.rbxmx`.
+    /// They're smaller and quicker to write, but don't diff well.
+    pub binary_fallback: bool,
+    /// What XML models are named with, `.rbxmx` by default. Some projects use `.model.rbxmx`
+    /// to tell them apart at a glance.
+    pub model_extension: String,
+    /// Only write a folder and an `init.meta.json` for each service at the top of the tree,
+    /// leaving out everything inside them. Unlike a `max_depth` of 0, nothing is kept in models
+    /// and empty services get folders too.
+    pub services_only: bool,
+    /// What each level of the JSON files written is indented with, two spaces like Rojo by
+    /// default.
+    pub json_indent: String,
+    /// Check for packages installed by Wally, see `ConversionSummary::wally_packages`.
+    pub wally: bool,
+    /// Keep the identifiers Roblox gives instances, like `ScriptGuid` and `UniqueId`, in meta
+    /// files. They're left out by default, since Roblox makes new ones anyway.
+    pub preserve_guids: bool,
+    /// Where to write particular instances instead, by their full name like
+    /// `ReplicatedStorage.Foo`. Each path is relative to the source folder and ends in the name
+    /// to write the instance under, without an extension. No two instances can share a path, and
+    /// none can lead outside the source folder. The last part is sanitized like instance names.
+    pub renames: BTreeMap<String, PathBuf>,
+    /// Fail with `ConvertError::EmptyTree` when the file has fewer instances than this, not
+    /// counting the root, since it's probably corrupt. Nothing's checked by default.
+    pub min_instances: usize,
+    /// Models bigger than this many bytes are written as an empty instance of their class
+    /// instead, see `ConversionSummary::too_large`. There's no limit by default.
+    pub max_file_bytes: Option<usize>,
+    /// How to write instances of particular classes, by class name, in place of the usual
+    /// conventions. Strategies that don't fit their class are ignored, see `misfit_strategy`.
+    pub class_strategies: BTreeMap<String, ClassStrategy>,
+    /// Only convert the instance at this full name, like `ReplicatedStorage.Shared`, and
+    /// everything inside it, as if it were the only instance in a model.
+    pub root_path: Option<String>,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        Self {
+            luau: false,
+            name_substitute: '_',
+            normalize_names: NameNormalization::None,
+            string_values_as_txt: false,
+            sourcemap: false,
+            normalize_newlines: false,
+            trailing_newline: false,
+            include_classes: Vec::new(),
+            exclude_classes: Vec::new(),
+            exclude_descendants: true,
+            skip_classes: vec![
+                String::from("Camera"),
+                String::from("PackageLink"),
+                String::from("Terrain"),
+            ],
+            sanitize_floats: true,
+            sanitize_char_refs: true,
+            sanitize_codepoints: true,
+            sanitize_outside_root: true,
+            sanitize_script_sources: true,
+            json_models: false,
+            flatten_services: false,
+            assets_manifest: false,
+            max_depth: None,
+            dedupe_shared_strings: false,
+            detect_json_modules: false,
+            binary_fallback: false,
+            model_extension: String::from(".rbxmx"),
+            services_only: false,
+            json_indent: String::from("  "),
+            wally: false,
+            preserve_guids: false,
+            renames: BTreeMap::new(),
+            min_instances: 0,
+            max_file_bytes: None,
+            class_strategies: BTreeMap::new(),
+            root_path: None,
+        }
+    }
+}
+
+impl ConvertOptions {
+    /// Starts from the defaults, so only the options that matter need setting, like
+    /// `ConvertOptions::builder().luau(true).indent(4).build()`.
+    pub fn builder() -> ConvertOptionsBuilder {
+        ConvertOptionsBuilder::default()
+    }
+
+    pub fn script_extension(&self) -> &'static str {
+        if self.luau {
+            "luau"
+        } else {
+            "lua"
+        }
+    }
+
+    pub fn is_excluded(&self, class: &str) -> bool {
+        self.exclude_classes
+            .iter()
+            .any(|excluded| excluded == class)
+    }
+
+    pub fn is_skipped(&self, class: &str) -> bool {
+        self.skip_classes.iter().any(|skipped| skipped == class)
+    }
+
+    /// The names of two instances `renames` sends to the same path, and that path, if there are
+    /// any.
+    pub fn rename_conflict(&self) -> Option<(&str, &str, &Path)> {
+        let mut targets = HashMap::new();
+        for (name, path) in &self.renames {
+            if let Some(first) = targets.insert(path.as_path(), name.as_str()) {
+                return Some((first, name.as_str(), path.as_path()));
+            }
+        }
+
+        None
+    }
+
+    /// A name in `renames` and its path if the path could lead outside the source folder, like an
+    /// absolute path or one going up through `..`.
+    pub fn invalid_rename(&self) -> Option<(&str, &Path)> {
+        self.renames
+            .iter()
+            .find(|(_, path)| !stays_inside(path))
+            .map(|(name, path)| (name.as_str(), path.as_path()))
+    }
+
+    /// A class in `class_strategies` and the strategy that doesn't fit it, if there are any.
+    pub fn misfit_strategy(&self) -> Option<(&str, ClassStrategy)> {
+        self.class_strategies
+            .iter()
+            .find(|(class, strategy)| !strategy.fits(class))
+            .map(|(class, strategy)| (class.as_str(), *strategy))
+    }
+
+    /// Whether instances of `class` get written, excludes taking precedence over includes.
+    pub fn is_included(&self, class: &str) -> bool {
+        !self.is_excluded(class)
+            && (self.include_classes.is_empty()
+                || self
+                    .include_classes
+                    .iter()
+                    .any(|included| included == class))
+    }
+}
+
+/// Sets `ConvertOptions` one at a time, see `ConvertOptions::builder`.
+#[derive(Clone, Debug, Default)]
+pub struct ConvertOptionsBuilder {
+    options: ConvertOptions,
+}
+
+macro_rules! builder_setters {
+    ($($field:ident: $type:ty,)*) => {
+        impl ConvertOptionsBuilder {
+            $(
+                #[doc = concat!("Sets `ConvertOptions::", stringify!($field), "`.")]
+                pub fn $field(mut self, $field: $type) -> Self {
+                    self.options.$field = $field;
+                    self
+                }
+            )*
+        }
+    };
+}
+
+builder_setters! {
+    luau: bool,
+    name_substitute: char,
+    normalize_names: NameNormalization,
+    string_values_as_txt: bool,
+    sourcemap: bool,
+    normalize_newlines: bool,
+    trailing_newline: bool,
+    include_classes: Vec<String>,
+    exclude_classes: Vec<String>,
+    exclude_descendants: bool,
+    skip_classes: Vec<String>,
+    sanitize_floats: bool,
+    sanitize_char_refs: bool,
+    sanitize_codepoints: bool,
+    sanitize_outside_root: bool,
+    sanitize_script_sources: bool,
+    json_models: bool,
+    flatten_services: bool,
+    assets_manifest: bool,
+    max_depth: Option<usize>,
+    dedupe_shared_strings: bool,
+    detect_json_modules: bool,
+    binary_fallback: bool,
+    model_extension: String,
+    services_only: bool,
+    json_indent: String,
+    wally: bool,
+    preserve_guids: bool,
+    renames: BTreeMap<String, PathBuf>,
+    min_instances: usize,
+    max_file_bytes: Option<usize>,
+    class_strategies: BTreeMap<String, ClassStrategy>,
+    root_path: Option<String>,
+}
+
+impl ConvertOptionsBuilder {
+    /// Indents JSON files with this many spaces, see `json_indent` for anything else.
+    pub fn indent(mut self, spaces: usize) -> Self {
+        self.options.json_indent = " ".repeat(spaces);
+        self
+    }
+
+    pub fn build(self) -> ConvertOptions {
+        self.options
+    }
+}
+
+/// An instance in a Rojo `sourcemap.json`, which tools like luau-lsp read.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SourcemapNode {
+    pub name: String,
+    pub class_name: String,
+    /// Relative to wherever the instructions were read into, with forward slashes.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub file_paths: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<SourcemapNode>,
+}
+
+/// The URLs of every asset a tree uses, by the path of the instance using it and then by
+/// property name.
+pub type AssetManifest = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Every distinct `SharedString` written into a model file, along with what uses each one.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct SharedStrings {
+    /// The contents of each one, by its hash in hex.
+    #[serde(skip)]
+    pub payloads: BTreeMap<String, SharedString>,
+    /// The hash each `SharedString` property holds, by the path of the instance and then by
+    /// property name, like an `AssetManifest`.
+    pub uses: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+// How many full names `ConversionSummary::unsupported_report` keeps for each class
+const UNSUPPORTED_EXAMPLES: usize = 5;
+
+/// How many instances of a class couldn't be represented, see
+/// `ConversionSummary::unsupported_report`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct UnsupportedClass {
+    pub count: usize,
+    /// The full names of the first few of them.
+    pub examples: Vec<String>,
+}
+
+/// How long each part of a conversion took, in seconds when serialized.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Timings {
+    /// Reading the file into a tree, not counting the sanitizers.
+    #[serde(serialize_with = "serialize_seconds")]
+    pub decode: Duration,
+    #[serde(serialize_with = "serialize_seconds")]
+    pub sanitize: Duration,
+    /// Turning the tree into files.
+    #[serde(serialize_with = "serialize_seconds")]
+    pub process: Duration,
+}
+
+/// Fired by `process_instructions_with_progress` while it works through the tree.
+#[derive(Clone, Debug)]
+pub enum ProgressEvent<'a> {
+    StartedInstance { name: &'a str, class: &'a str },
+    WroteFile { path: &'a Path, bytes: usize },
+}
+
+/// What `process_instructions` ended up doing with the tree.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversionSummary {
+    pub scripts_written: usize,
+    pub models_written: usize,
+    pub folders_created: usize,
+    /// Class names of instances with scripts inside that couldn't be represented, and of those
+    /// left out for being in `ConvertOptions::skip_classes`.
+    pub skipped: Vec<String>,
+    /// Original and sanitized names of instances that couldn't be used as file names.
+    pub renamed: Vec<(String, String)>,
+    /// How many instances that would've been written were left out by the class filters.
+    pub filtered: usize,
+    /// The tree of instances written, if `ConvertOptions::sourcemap` was set.
+    #[serde(skip)]
+    pub sourcemap: Option<SourcemapNode>,
+    /// Every asset in the tree, if `ConvertOptions::assets_manifest` was set.
+    pub assets: Option<AssetManifest>,
+    /// Every `SharedString` in the model files written, if `ConvertOptions::dedupe_shared_strings`
+    /// was set.
+    pub shared_strings: Option<SharedStrings>,
+    /// What the sanitizers changed before the file could be decoded, filled in by `convert_file`.
+    pub repairs: Vec<Repair>,
+    /// How many subtrees were written as one model because they went past
+    /// `ConvertOptions::max_depth`.
+    pub collapsed: usize,
+    /// How long each part of the conversion took, if the caller kept track.
+    pub timings: Option<Timings>,
+    /// Whether `ReplicatedStorage` has a `Packages` folder installed by Wally, if
+    /// `ConvertOptions::wally` was set. A starter `wally.toml` is written when it does.
+    pub wally_packages: bool,
+    /// The full names of scripts whose sources had null bytes or byte order marks stripped, see
+    /// `ConvertOptions::sanitize_script_sources`.
+    pub cleaned_scripts: Vec<String>,
+    /// The full names of instances whose models went over `ConvertOptions::max_file_bytes`.
+    pub too_large: Vec<String>,
+    /// The class and full name of each instance that was written as a model file, or left out
+    /// with scripts inside, because nothing else could represent it. Models asked for with
+    /// `ConvertOptions::max_depth` or `ConvertOptions::class_strategies` aren't counted.
+    pub unsupported: Vec<(String, String)>,
+}
+
+impl ConversionSummary {
+    pub(crate) fn merge(&mut self, other: ConversionSummary) {
+        self.scripts_written += other.scripts_written;
+        self.models_written += other.models_written;
+        self.folders_created += other.folders_created;
+        self.skipped.extend(other.skipped);
+        self.renamed.extend(other.renamed);
+        self.filtered += other.filtered;
+        self.repairs.extend(other.repairs);
+        self.collapsed += other.collapsed;
+        self.cleaned_scripts.extend(other.cleaned_scripts);
+        self.too_large.extend(other.too_large);
+        self.unsupported.extend(other.unsupported);
+
+        if let (Some(shared_strings), Some(forked)) =
+            (&mut self.shared_strings, other.shared_strings)
+        {
+            shared_strings.payloads.extend(forked.payloads);
+            shared_strings.uses.extend(forked.uses);
+        }
+    }
+
+    /// `unsupported` gathered up by class, with the first few full names of each as examples.
+    pub fn unsupported_report(&self) -> BTreeMap<String, UnsupportedClass> {
+        let mut report: BTreeMap<String, UnsupportedClass> = BTreeMap::new();
+        for (class, name) in &self.unsupported {
+            let entry = report.entry(class.clone()).or_default();
+            entry.count += 1;
+            if entry.examples.len() < UNSUPPORTED_EXAMPLES {
+                entry.examples.push(name.clone());
+            }
+        }
+
+        report
+    }
+
+    pub(crate) fn record(&mut self, instructions: &[Instruction]) {
+        for instruction in instructions {
+            match instruction {
+                Instruction::CreateFile { filename, .. } => {
+                    let filename = filename.to_string_lossy();
+                    if filename.ends_with(".lua") || filename.ends_with(".luau") {
+                        self.scripts_written += 1;
+                    } else if filename.ends_with(".rbxmx")
+                        || filename.ends_with(".rbxm")
+                        || filename.ends_with(".model.json")
+                    {
+                        self.models_written += 1;
+                    } else if filename.ends_with(".json") && !filename.ends_with(".meta.json") {
+                        // A module returning JSON, see `ConvertOptions::detect_json_modules`
+                        self.scripts_written += 1;
+                    }
+                }
+
+                Instruction::CreateFolder { .. } => self.folders_created += 1,
+
+                Instruction::AddToTree { .. } => {}
+            }
+        }
+    }
+}
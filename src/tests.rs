@@ -1,12 +1,29 @@
-use crate::{filesystem::FileSystem, process_instructions, structures::*};
+use crate::{
+    check_tree, convert_bytes, convert_file,
+    decode::decode_file,
+    filesystem::{Backend, DryRun, FileSystem, MemoryFileSystem, MergeReport},
+    plan_instructions, process_instructions, process_instructions_parallel,
+    process_instructions_parallel_with_progress, process_instructions_with_options,
+    process_instructions_with_progress, sanitize_instance_name,
+    structures::*,
+    tree_listing, ConvertError, PlaceFormat, XmlErrorLocation,
+};
 use log::info;
 use pretty_assertions::assert_eq;
-use rbx_dom_weak::types::Variant;
+use rbx_dom_weak::{
+    types::{
+        Attributes, CFrame, Color3, ColorSequence, ColorSequenceKeypoint, ContentId, Enum, Matrix3,
+        NumberSequence, NumberSequenceKeypoint, SharedString, Tags, Variant, Vector2, Vector3,
+    },
+    ustr, InstanceBuilder, WeakDom,
+};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{BTreeMap, HashMap},
     fs,
     io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
     time::Instant,
 };
 
@@ -166,3 +183,2600 @@ fn run_tests() {
         process_instructions(&tree, &mut filesystem);
     }
 }
+
+fn process_in_memory(tree: &WeakDom) -> MemoryFileSystem {
+    let mut filesystem = MemoryFileSystem::new();
+    process_instructions(tree, &mut filesystem);
+    filesystem
+}
+
+fn file_contents(filesystem: &MemoryFileSystem, path: &str) -> String {
+    let contents = filesystem
+        .files()
+        .get(Path::new(path))
+        .unwrap_or_else(|| panic!("{} wasn't written", path));
+    String::from_utf8_lossy(contents).into_owned()
+}
+
+fn module_script(name: &str) -> InstanceBuilder {
+    InstanceBuilder::new("ModuleScript")
+        .with_name(name)
+        .with_property("Source", String::from("return nil\n"))
+}
+
+#[test]
+fn memory_file_system() {
+    let source = fs::read_to_string("./test-files/sword/source.rbxmx")
+        .expect("couldn't read source.rbxmx");
+    let tree = rbx_xml::from_str_default(&source).expect("couldn't deserialize source.rbxmx");
+
+    let mut filesystem = MemoryFileSystem::new();
+    process_instructions(&tree, &mut filesystem);
+
+    let files = filesystem.files();
+    assert!(files.contains_key(Path::new("default.project.json")));
+    assert_eq!(
+        String::from_utf8_lossy(&files[Path::new("src/ClassicSword/init.meta.json")]),
+        "{\n  \"className\": \"Tool\",\n  \"ignoreUnknownInstances\": true\n}",
+    );
+    assert!(files.contains_key(Path::new("src/ClassicSword/SwordScript.server.lua")));
+    assert!(files.contains_key(Path::new("src/ClassicSword/MouseIcon.client.lua")));
+    assert!(filesystem
+        .directories()
+        .contains(Path::new("src/ClassicSword")));
+}
+
+#[test]
+fn buffered_writes() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ReplicatedStorage")
+                .with_name("ReplicatedStorage")
+                .with_child(
+                    InstanceBuilder::new("Folder")
+                        .with_name("Outer")
+                        .with_child(
+                            InstanceBuilder::new("Folder")
+                                .with_name("Inner")
+                                .with_child(module_script("Module")),
+                        ),
+                ),
+        ),
+    );
+
+    let plain = process_in_memory(&tree);
+
+    let mut buffered = MemoryFileSystem::new();
+    buffered.set_buffered(true);
+    process_instructions(&tree, &mut buffered);
+
+    assert_eq!(plain.files(), buffered.files());
+    // Creating the deepest folder creates the rest too
+    assert!(buffered
+        .directories()
+        .contains(Path::new("src/ReplicatedStorage/Outer/Inner")));
+    assert!(!buffered
+        .directories()
+        .contains(Path::new("src/ReplicatedStorage/Outer")));
+}
+
+#[test]
+fn model_project_file() {
+    let source = fs::read_to_string("./test-files/sword/source.rbxmx")
+        .expect("couldn't read source.rbxmx");
+    let tree = rbx_xml::from_str_default(&source).expect("couldn't deserialize source.rbxmx");
+
+    let mut filesystem = MemoryFileSystem::new();
+    filesystem.set_project_name("sword");
+    filesystem.set_model(true);
+    process_instructions(&tree, &mut filesystem);
+
+    assert_eq!(
+        String::from_utf8_lossy(&filesystem.files()[Path::new("default.project.json")]),
+        "{\n  \"name\": \"sword\",\n  \"tree\": {\n    \"$path\": \"src/ClassicSword\"\n  }\n}",
+    );
+}
+
+#[test]
+fn tags_in_meta_files() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("Folder")
+                .with_name("Folder")
+                .with_property(
+                    "Tags",
+                    Tags::from(vec!["First".to_string(), "Second".to_string()]),
+                )
+                .with_child(module_script("ModuleScript")),
+        ),
+    );
+
+    assert_eq!(
+        file_contents(&process_in_memory(&tree), "src/Folder/init.meta.json"),
+        "{\n  \"tags\": [\n    \"First\",\n    \"Second\"\n  ],\n  \"ignoreUnknownInstances\": true\n}",
+    );
+}
+
+#[test]
+fn module_script_with_children() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            module_script("Module")
+                .with_property("Tags", Tags::from(vec!["Tagged".to_string()]))
+                .with_child(
+                    InstanceBuilder::new("Folder")
+                        .with_name("Helpers")
+                        .with_child(module_script("Helper")),
+                ),
+        ),
+    );
+
+    let filesystem = process_in_memory(&tree);
+    let files = filesystem.files();
+    assert!(files.contains_key(Path::new("src/Module/init.lua")));
+    assert!(!files.contains_key(Path::new("src/Module/init.server.lua")));
+    assert!(files.contains_key(Path::new("src/Module/Helpers/Helper.lua")));
+    assert_eq!(
+        file_contents(&filesystem, "src/Module/init.meta.json"),
+        "{\n  \"tags\": [\n    \"Tagged\"\n  ],\n  \"ignoreUnknownInstances\": true\n}",
+    );
+    assert!(!files.contains_key(Path::new("src/Module.meta.json")));
+}
+
+#[test]
+fn attributes_in_meta_files() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("Folder")
+                .with_name("Folder")
+                .with_property(
+                    "Attributes",
+                    Attributes::new()
+                        .with("Enabled", true)
+                        .with("Speed", 16.0f64)
+                        .with("Offset", Vector2::new(1.0, 2.0)),
+                )
+                .with_child(module_script("ModuleScript")),
+        ),
+    );
+
+    let meta: serde_json::Value = serde_json::from_str(&file_contents(
+        &process_in_memory(&tree),
+        "src/Folder/init.meta.json",
+    ))
+    .unwrap();
+    assert_eq!(
+        meta["attributes"],
+        serde_json::json!({
+            "Enabled": true,
+            "Offset": { "Vector2": [1.0, 2.0] },
+            "Speed": 16.0,
+        }),
+    );
+    assert!(meta.get("properties").is_none());
+}
+
+#[test]
+fn sanitize_instance_names() {
+    assert_eq!(sanitize_instance_name("Normal Name", '_'), "Normal Name");
+    assert_eq!(sanitize_instance_name("a/b\\c:d", '_'), "a_b_c_d");
+    assert_eq!(sanitize_instance_name("what?", '-'), "what-");
+    assert_eq!(sanitize_instance_name("trailing. ", '_'), "trailing");
+    assert_eq!(sanitize_instance_name("CON", '_'), "CON_");
+    assert_eq!(sanitize_instance_name("nul.txt", '_'), "nul_.txt");
+    assert_eq!(sanitize_instance_name("Console", '_'), "Console");
+}
+
+#[test]
+fn sibling_name_collisions() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("Folder")
+                .with_name("Folder")
+                .with_child(module_script("Module"))
+                .with_child(module_script("Module"))
+                .with_child(module_script("Module")),
+        ),
+    );
+
+    let filesystem = process_in_memory(&tree);
+    let files = filesystem.files();
+    assert!(files.contains_key(Path::new("src/Folder/Module.lua")));
+    assert!(files.contains_key(Path::new("src/Folder/Module (2).lua")));
+    assert!(files.contains_key(Path::new("src/Folder/Module (3).lua")));
+    assert!(!files.contains_key(Path::new("src/Folder/Module.meta.json")));
+    assert_eq!(
+        file_contents(&filesystem, "src/Folder/Module (3).meta.json"),
+        "{\n  \"name\": \"Module\",\n  \"ignoreUnknownInstances\": true\n}",
+    );
+}
+
+#[test]
+fn starter_player_partitions() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("StarterPlayer")
+                .with_child(
+                    InstanceBuilder::new("StarterPlayerScripts")
+                        .with_child(module_script("Module")),
+                )
+                .with_child(
+                    InstanceBuilder::new("Folder")
+                        .with_name("")
+                        .with_child(module_script("Module")),
+                ),
+        ),
+    );
+
+    let filesystem = process_in_memory(&tree);
+    let files = filesystem.files();
+    assert!(files.contains_key(Path::new(
+        "src/StarterPlayer/StarterPlayerScripts/Module.lua"
+    )));
+    assert!(files.contains_key(Path::new("src/StarterPlayer/Folder/Module.lua")));
+
+    let project: serde_json::Value =
+        serde_json::from_slice(&files[Path::new("default.project.json")]).unwrap();
+    let starter_player = &project["tree"]["StarterPlayer"];
+    assert_eq!(
+        starter_player["StarterPlayerScripts"]["$path"],
+        "src/StarterPlayer/StarterPlayerScripts",
+    );
+    assert_eq!(starter_player[""]["$path"], "src/StarterPlayer/Folder");
+}
+
+#[test]
+fn parallel_matches_serial() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel")
+            .with_child(
+                InstanceBuilder::new("ReplicatedStorage")
+                    .with_name("ReplicatedStorage")
+                    .with_child(module_script("Shared"))
+                    .with_child(module_script("Shared")),
+            )
+            .with_child(
+                InstanceBuilder::new("ServerScriptService")
+                    .with_name("ServerScriptService")
+                    .with_child(module_script("Server")),
+            ),
+    );
+
+    let serial = process_in_memory(&tree);
+
+    let mut parallel = MemoryFileSystem::new();
+    let summary =
+        process_instructions_parallel(&tree, &mut parallel, &ConvertOptions::default(), 4);
+
+    assert_eq!(summary.scripts_written, 3);
+    assert_eq!(serial.directories(), parallel.directories());
+    assert_eq!(serial.files(), parallel.files());
+}
+
+#[test]
+fn parallel_progress() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel")
+            .with_child(
+                InstanceBuilder::new("ReplicatedStorage")
+                    .with_child(module_script("Shared"))
+                    .with_child(module_script("Other")),
+            )
+            .with_child(
+                InstanceBuilder::new("ServerScriptService").with_child(module_script("Server")),
+            ),
+    );
+    let options = ConvertOptions::default();
+
+    let mut serial_files = 0;
+    process_instructions_with_progress(
+        &tree,
+        &mut MemoryFileSystem::new(),
+        &options,
+        &mut |event: &ProgressEvent| {
+            if let ProgressEvent::WroteFile { .. } = event {
+                serial_files += 1;
+            }
+        },
+    );
+
+    let parallel_files = AtomicUsize::new(0);
+    process_instructions_parallel_with_progress(
+        &tree,
+        &mut MemoryFileSystem::new(),
+        &options,
+        4,
+        &|event: &ProgressEvent| {
+            if let ProgressEvent::WroteFile { .. } = event {
+                parallel_files.fetch_add(1, Ordering::Relaxed);
+            }
+        },
+    );
+
+    assert!(serial_files > 0);
+    assert_eq!(parallel_files.into_inner(), serial_files);
+}
+
+#[test]
+fn string_values_as_txt() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("Folder")
+                .with_name("Config")
+                .with_child(
+                    InstanceBuilder::new("StringValue")
+                        .with_name("Settings")
+                        .with_property("Value", String::from("{\"debug\": true}")),
+                )
+                .with_child(
+                    InstanceBuilder::new("StringValue")
+                        .with_name("Empty")
+                        .with_property("Value", String::new()),
+                ),
+        ),
+    );
+
+    // Left out unless asked for
+    assert_eq!(process_in_memory(&tree).files().len(), 1);
+
+    let mut filesystem = MemoryFileSystem::new();
+    process_instructions_with_options(
+        &tree,
+        &mut filesystem,
+        &ConvertOptions {
+            string_values_as_txt: true,
+            ..ConvertOptions::default()
+        },
+    );
+
+    assert_eq!(
+        file_contents(&filesystem, "src/Config/Settings.txt"),
+        "{\"debug\": true}",
+    );
+    assert_eq!(file_contents(&filesystem, "src/Config/Empty.txt"), "");
+    assert_eq!(
+        file_contents(&filesystem, "src/Config/Empty.meta.json"),
+        "{\n  \"className\": \"StringValue\",\n  \"ignoreUnknownInstances\": true\n}",
+    );
+}
+
+#[test]
+fn localization_tables_as_csv() {
+    let contents = r#"[
+        {"key": "Greeting", "source": "Hello, world", "context": "", "examples": "", "values": {"es": "Hola, \"mundo\""}},
+        {"key": "Farewell", "source": "Bye", "values": {"fr": "Au revoir"}}
+    ]"#;
+
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("Folder")
+                .with_name("Localization")
+                .with_child(
+                    InstanceBuilder::new("LocalizationTable")
+                        .with_name("Strings")
+                        .with_property("Contents", String::from(contents)),
+                )
+                .with_child(
+                    InstanceBuilder::new("LocalizationTable")
+                        .with_name("Broken")
+                        .with_property("Contents", String::from("not json")),
+                ),
+        ),
+    );
+
+    let filesystem = process_in_memory(&tree);
+    assert_eq!(
+        file_contents(&filesystem, "src/Localization/Strings.csv"),
+        "Key,Source,Context,Example,es,fr\n\
+         Greeting,\"Hello, world\",,,\"Hola, \"\"mundo\"\"\",\n\
+         Farewell,Bye,,,,Au revoir\n",
+    );
+    assert!(filesystem
+        .files()
+        .contains_key(Path::new("src/Localization/Broken.rbxmx")));
+}
+
+#[test]
+fn atomic_writes() {
+    let root = std::env::temp_dir().join("rbxlx-to-rojo-atomic-writes");
+    fs::remove_dir_all(&root).ok();
+
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ReplicatedStorage")
+                .with_name("ReplicatedStorage")
+                .with_child(module_script("Module")),
+        ),
+    );
+
+    let mut filesystem = FileSystem::from_root_atomic(root.clone());
+    process_instructions(&tree, &mut filesystem);
+    drop(filesystem);
+
+    let module = root
+        .join("src")
+        .join("ReplicatedStorage")
+        .join("Module.lua");
+    assert_eq!(fs::read_to_string(&module).unwrap(), "return nil\n");
+    assert!(!root.join("default.project.json.tmp").exists());
+    assert!(!module.with_file_name("Module.lua.tmp").exists());
+
+    fs::remove_dir_all(&root).ok();
+}
+
+#[test]
+fn disabled_scripts_in_meta_files() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("StarterPack")
+                .with_name("StarterPack")
+                .with_child(
+                    InstanceBuilder::new("LocalScript")
+                        .with_name("Disabled")
+                        .with_property("Source", String::new())
+                        .with_property("Disabled", true),
+                )
+                .with_child(
+                    InstanceBuilder::new("LocalScript")
+                        .with_name("Enabled")
+                        .with_property("Source", String::new())
+                        .with_property("Disabled", false),
+                ),
+        ),
+    );
+
+    let filesystem = process_in_memory(&tree);
+    assert_eq!(
+        file_contents(&filesystem, "src/StarterPack/Disabled.meta.json"),
+        "{\n  \"properties\": {\n    \"Disabled\": true\n  },\n  \"ignoreUnknownInstances\": true\n}",
+    );
+    assert!(!filesystem
+        .files()
+        .contains_key(Path::new("src/StarterPack/Enabled.meta.json")));
+}
+
+#[test]
+fn non_archivable_in_meta_files() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("Workspace")
+                .with_name("Workspace")
+                .with_child(
+                    InstanceBuilder::new("Part")
+                        .with_name("Part")
+                        .with_property("Archivable", false)
+                        .with_child(
+                            InstanceBuilder::new("Script")
+                                .with_name("Script")
+                                .with_property("Source", String::new())
+                                .with_property("Archivable", false),
+                        ),
+                ),
+        ),
+    );
+
+    let filesystem = process_in_memory(&tree);
+    assert!(
+        file_contents(&filesystem, "src/Workspace/Part/init.meta.json")
+            .contains("\"Archivable\": false")
+    );
+    assert_eq!(
+        file_contents(&filesystem, "src/Workspace/Part/Script.meta.json"),
+        "{\n  \"properties\": {\n    \"Archivable\": false\n  },\n  \"ignoreUnknownInstances\": true\n}",
+    );
+}
+
+#[test]
+fn script_run_contexts() {
+    let script = |name: &str, run_context: Option<u32>| {
+        let script = InstanceBuilder::new("Script")
+            .with_name(name)
+            .with_property("Source", String::new());
+        match run_context {
+            Some(value) => script.with_property("RunContext", Enum::from_u32(value)),
+            None => script,
+        }
+    };
+
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ReplicatedStorage")
+                .with_name("ReplicatedStorage")
+                .with_child(script("Unset", None))
+                .with_child(script("Legacy", Some(0)))
+                .with_child(script("Server", Some(1)))
+                .with_child(script("Client", Some(2))),
+        ),
+    );
+
+    let filesystem = process_in_memory(&tree);
+    for (name, path) in &[
+        ("Unset", "src/ReplicatedStorage/Unset.server.lua"),
+        ("Legacy", "src/ReplicatedStorage/Legacy.server.lua"),
+        ("Server", "src/ReplicatedStorage/Server.server.lua"),
+        ("Client", "src/ReplicatedStorage/Client.client.lua"),
+    ] {
+        assert!(
+            filesystem.files().contains_key(Path::new(path)),
+            "{} wasn't written to {}",
+            name,
+            path,
+        );
+    }
+
+    // Legacy is the default, so only scripts that run somewhere specific keep their RunContext
+    for name in &["Unset", "Legacy"] {
+        assert!(!filesystem.files().contains_key(Path::new(&format!(
+            "src/ReplicatedStorage/{}.meta.json",
+            name
+        ))));
+    }
+    assert_eq!(
+        file_contents(&filesystem, "src/ReplicatedStorage/Server.meta.json"),
+        "{\n  \"properties\": {\n    \"RunContext\": {\n      \"Enum\": 1\n    }\n  },\n  \"ignoreUnknownInstances\": true\n}",
+    );
+    assert_eq!(
+        file_contents(&filesystem, "src/ReplicatedStorage/Client.meta.json"),
+        "{\n  \"properties\": {\n    \"RunContext\": {\n      \"Enum\": 2\n    }\n  },\n  \"ignoreUnknownInstances\": true\n}",
+    );
+}
+
+#[test]
+fn preserve_guids() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ReplicatedStorage")
+                .with_name("ReplicatedStorage")
+                .with_child(
+                    InstanceBuilder::new("ModuleScript")
+                        .with_name("Module")
+                        .with_property("Source", String::new())
+                        .with_property("ScriptGuid", "{5C6D5A2E-0D39-4F4B-9A55-3A4B0B0C1F2E}"),
+                ),
+        ),
+    );
+
+    // Left out by default, so there's nothing for a meta file to hold
+    let filesystem = process_in_memory(&tree);
+    assert!(!filesystem
+        .files()
+        .contains_key(Path::new("src/ReplicatedStorage/Module.meta.json")));
+
+    let mut filesystem = MemoryFileSystem::new();
+    process_instructions_with_options(
+        &tree,
+        &mut filesystem,
+        &ConvertOptions {
+            preserve_guids: true,
+            ..ConvertOptions::default()
+        },
+    );
+    assert_eq!(
+        file_contents(&filesystem, "src/ReplicatedStorage/Module.meta.json"),
+        "{\n  \"properties\": {\n    \"ScriptGuid\": \"{5C6D5A2E-0D39-4F4B-9A55-3A4B0B0C1F2E}\"\n  },\n  \"ignoreUnknownInstances\": true\n}",
+    );
+}
+
+#[test]
+fn scripts_with_children() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ServerScriptService")
+                .with_name("ServerScriptService")
+                .with_child(
+                    InstanceBuilder::new("Script")
+                        .with_name("Main")
+                        .with_property("Source", String::from("print(\"main\")\n"))
+                        .with_property("Disabled", true)
+                        .with_child(module_script("Helper"))
+                        .with_child(InstanceBuilder::new("Part").with_name("Part")),
+                ),
+        ),
+    );
+
+    let filesystem = process_in_memory(&tree);
+    assert!(filesystem
+        .directories()
+        .contains(Path::new("src/ServerScriptService/Main")));
+    assert_eq!(
+        file_contents(&filesystem, "src/ServerScriptService/Main/init.server.lua"),
+        "print(\"main\")\n",
+    );
+    assert_eq!(
+        file_contents(&filesystem, "src/ServerScriptService/Main/Helper.lua"),
+        "return nil\n",
+    );
+    assert_eq!(
+        file_contents(&filesystem, "src/ServerScriptService/Main/init.meta.json"),
+        "{\n  \"properties\": {\n    \"Disabled\": true\n  },\n  \"ignoreUnknownInstances\": true\n}",
+    );
+}
+
+#[test]
+fn class_filters() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ServerScriptService")
+                .with_name("ServerScriptService")
+                .with_child(
+                    InstanceBuilder::new("Script")
+                        .with_name("Main")
+                        .with_property("Source", String::new())
+                        .with_child(module_script("Helper")),
+                )
+                .with_child(module_script("Shared")),
+        ),
+    );
+
+    let process = |options: ConvertOptions| {
+        let mut filesystem = MemoryFileSystem::new();
+        let summary = process_instructions_with_options(&tree, &mut filesystem, &options);
+        (filesystem, summary)
+    };
+
+    // Only module scripts, but Main is kept around as a folder to reach Helper
+    let (filesystem, summary) = process(ConvertOptions {
+        include_classes: vec!["ModuleScript".to_string()],
+        ..ConvertOptions::default()
+    });
+    let files = filesystem.files();
+    assert!(files.contains_key(Path::new("src/ServerScriptService/Main/Helper.lua")));
+    assert!(files.contains_key(Path::new("src/ServerScriptService/Shared.lua")));
+    assert!(!files.contains_key(Path::new("src/ServerScriptService/Main/init.server.lua")));
+    assert_eq!(summary.filtered, 1);
+
+    // Excludes win, and take everything inside with them
+    let (filesystem, summary) = process(ConvertOptions {
+        include_classes: vec!["ModuleScript".to_string(), "Script".to_string()],
+        exclude_classes: vec!["Script".to_string()],
+        ..ConvertOptions::default()
+    });
+    let files = filesystem.files();
+    assert!(!files.contains_key(Path::new("src/ServerScriptService/Main/Helper.lua")));
+    assert!(files.contains_key(Path::new("src/ServerScriptService/Shared.lua")));
+    assert_eq!(summary.filtered, 2);
+
+    let (filesystem, _) = process(ConvertOptions {
+        exclude_classes: vec!["Script".to_string()],
+        exclude_descendants: false,
+        ..ConvertOptions::default()
+    });
+    assert!(filesystem
+        .files()
+        .contains_key(Path::new("src/ServerScriptService/Main/Helper.lua")));
+}
+
+#[test]
+fn renames() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ReplicatedStorage")
+                .with_name("ReplicatedStorage")
+                .with_child(module_script("Foo"))
+                .with_child(module_script("Bar")),
+        ),
+    );
+
+    let mut renames = BTreeMap::new();
+    renames.insert(
+        String::from("ReplicatedStorage.Foo"),
+        PathBuf::from("Shared/Utilities/Renamed"),
+    );
+
+    let mut filesystem = MemoryFileSystem::new();
+    process_instructions_with_options(
+        &tree,
+        &mut filesystem,
+        &ConvertOptions {
+            renames,
+            ..ConvertOptions::default()
+        },
+    );
+
+    let files = filesystem.files();
+    assert!(files.contains_key(Path::new("src/Shared/Utilities/Renamed.lua")));
+    assert!(!files.contains_key(Path::new("src/ReplicatedStorage/Foo.lua")));
+    assert!(files.contains_key(Path::new("src/ReplicatedStorage/Bar.lua")));
+
+    let mut options = ConvertOptions::default();
+    options
+        .renames
+        .insert(String::from("ReplicatedStorage.Foo"), PathBuf::from("Same"));
+    options
+        .renames
+        .insert(String::from("ReplicatedStorage.Bar"), PathBuf::from("Same"));
+    assert_eq!(
+        options.rename_conflict(),
+        Some((
+            "ReplicatedStorage.Bar",
+            "ReplicatedStorage.Foo",
+            Path::new("Same")
+        )),
+    );
+    assert!(matches!(
+        check_tree(&tree, &options),
+        Err(ConvertError::NameCollision { .. })
+    ));
+
+    // Without checking first, the first instance sent there keeps the path
+    let mut filesystem = MemoryFileSystem::new();
+    process_instructions_with_options(&tree, &mut filesystem, &options);
+    let files = filesystem.files();
+    assert!(files.contains_key(Path::new("src/Same.lua")));
+    assert!(files.contains_key(Path::new("src/ReplicatedStorage/Bar.lua")));
+
+    // Renames can't lead outside the source folder, and the names they give are sanitized
+    let mut options = ConvertOptions::default();
+    options.renames.insert(
+        String::from("ReplicatedStorage.Foo"),
+        PathBuf::from("../Outside"),
+    );
+    options.renames.insert(
+        String::from("ReplicatedStorage.Bar"),
+        PathBuf::from("Shared/What?"),
+    );
+    assert_eq!(
+        options.invalid_rename(),
+        Some(("ReplicatedStorage.Foo", Path::new("../Outside"))),
+    );
+    assert!(matches!(
+        check_tree(&tree, &options),
+        Err(ConvertError::InvalidRename { .. })
+    ));
+
+    let mut filesystem = MemoryFileSystem::new();
+    process_instructions_with_options(&tree, &mut filesystem, &options);
+    let files = filesystem.files();
+    assert!(files.contains_key(Path::new("src/ReplicatedStorage/Foo.lua")));
+    assert!(files.contains_key(Path::new("src/Shared/What_.lua")));
+    assert!(!files.keys().any(|path| path.starts_with("Outside")));
+}
+
+#[test]
+fn empty_names() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ReplicatedStorage")
+                .with_name("ReplicatedStorage")
+                .with_child(
+                    InstanceBuilder::new("Part")
+                        .with_name("")
+                        .with_child(module_script("Module")),
+                ),
+        ),
+    );
+
+    let mut filesystem = MemoryFileSystem::new();
+    process_instructions(&tree, &mut filesystem);
+
+    let files = filesystem.files();
+    assert!(files.contains_key(Path::new("src/ReplicatedStorage/Part/Module.lua")));
+
+    // The meta file keeps the name empty when it's read back
+    let meta: serde_json::Value =
+        serde_json::from_slice(&files[Path::new("src/ReplicatedStorage/Part/init.meta.json")])
+            .unwrap();
+    assert_eq!(meta["className"], "Part");
+    assert_eq!(meta["name"], "");
+}
+
+#[test]
+fn normalize_names() {
+    let decomposed = "Cafe\u{301}";
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ReplicatedStorage")
+                .with_name("ReplicatedStorage")
+                .with_child(module_script(decomposed)),
+        ),
+    );
+
+    let mut filesystem = MemoryFileSystem::new();
+    process_instructions(&tree, &mut filesystem);
+    assert!(filesystem
+        .files()
+        .contains_key(&Path::new("src/ReplicatedStorage").join(format!("{}.lua", decomposed))));
+
+    let mut filesystem = MemoryFileSystem::new();
+    process_instructions_with_options(
+        &tree,
+        &mut filesystem,
+        &ConvertOptions {
+            normalize_names: NameNormalization::Nfc,
+            ..ConvertOptions::default()
+        },
+    );
+    assert!(filesystem
+        .files()
+        .contains_key(Path::new("src/ReplicatedStorage/Caf\u{e9}.lua")));
+}
+
+#[test]
+fn listing() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel")
+            .with_child(
+                InstanceBuilder::new("ReplicatedStorage")
+                    .with_name("ReplicatedStorage")
+                    .with_child(
+                        InstanceBuilder::new("Folder")
+                            .with_name("Shared")
+                            .with_child(module_script("Module")),
+                    ),
+            )
+            .with_child(InstanceBuilder::new("Workspace").with_name("Workspace")),
+    );
+
+    assert_eq!(
+        tree_listing(&tree, None),
+        "ReplicatedStorage (ReplicatedStorage)\n  Shared (Folder)\n    Module (ModuleScript)\n\
+         Workspace (Workspace)\n",
+    );
+    assert_eq!(
+        tree_listing(&tree, Some(1)),
+        "ReplicatedStorage (ReplicatedStorage)\n  Shared (Folder)\nWorkspace (Workspace)\n",
+    );
+}
+
+#[test]
+fn min_instances() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ReplicatedStorage")
+                .with_name("ReplicatedStorage")
+                .with_child(module_script("Module")),
+        ),
+    );
+
+    assert_eq!(check_tree(&tree, &ConvertOptions::default()).unwrap(), 2);
+
+    let options = ConvertOptions {
+        min_instances: 3,
+        ..ConvertOptions::default()
+    };
+    assert!(matches!(
+        check_tree(&tree, &options),
+        Err(ConvertError::EmptyTree {
+            found: 2,
+            minimum: 3
+        })
+    ));
+}
+
+#[test]
+fn normalize_newlines() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ReplicatedStorage")
+                .with_name("ReplicatedStorage")
+                .with_child(
+                    InstanceBuilder::new("ModuleScript")
+                        .with_name("Mixed")
+                        .with_property(
+                            "Source",
+                            String::from("local a = 1\r\nlocal b = 2\rreturn a + b"),
+                        ),
+                ),
+        ),
+    );
+
+    // Left alone by default
+    assert_eq!(
+        file_contents(&process_in_memory(&tree), "src/ReplicatedStorage/Mixed.lua"),
+        "local a = 1\r\nlocal b = 2\rreturn a + b",
+    );
+
+    let mut filesystem = MemoryFileSystem::new();
+    process_instructions_with_options(
+        &tree,
+        &mut filesystem,
+        &ConvertOptions {
+            normalize_newlines: true,
+            trailing_newline: true,
+            ..ConvertOptions::default()
+        },
+    );
+
+    assert_eq!(
+        file_contents(&filesystem, "src/ReplicatedStorage/Mixed.lua"),
+        "local a = 1\nlocal b = 2\nreturn a + b\n",
+    );
+}
+
+#[test]
+fn script_source_junk() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ReplicatedStorage")
+                .with_name("ReplicatedStorage")
+                .with_child(
+                    InstanceBuilder::new("ModuleScript")
+                        .with_name("Corrupted")
+                        .with_property("Source", String::from("\u{feff}return\0 nil\0")),
+                )
+                .with_child(module_script("Fine")),
+        ),
+    );
+
+    let mut filesystem = MemoryFileSystem::new();
+    let summary =
+        process_instructions_with_options(&tree, &mut filesystem, &ConvertOptions::default());
+    assert_eq!(
+        file_contents(&filesystem, "src/ReplicatedStorage/Corrupted.lua"),
+        "return nil",
+    );
+    assert_eq!(
+        summary.cleaned_scripts,
+        vec![String::from("ReplicatedStorage.Corrupted")],
+    );
+
+    let mut filesystem = MemoryFileSystem::new();
+    let summary = process_instructions_with_options(
+        &tree,
+        &mut filesystem,
+        &ConvertOptions {
+            sanitize_script_sources: false,
+            ..ConvertOptions::default()
+        },
+    );
+    assert_eq!(
+        file_contents(&filesystem, "src/ReplicatedStorage/Corrupted.lua"),
+        "\u{feff}return\0 nil\0",
+    );
+    assert!(summary.cleaned_scripts.is_empty());
+}
+
+#[test]
+fn sourcemap() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ReplicatedStorage")
+                .with_name("ReplicatedStorage")
+                .with_child(module_script("Module")),
+        ),
+    );
+
+    let mut filesystem = MemoryFileSystem::new();
+    filesystem.set_project_name("Game");
+    let summary = process_instructions_with_options(
+        &tree,
+        &mut filesystem,
+        &ConvertOptions {
+            sourcemap: true,
+            ..ConvertOptions::default()
+        },
+    );
+
+    filesystem
+        .write_sourcemap(summary.sourcemap.as_ref().expect("no sourcemap"))
+        .unwrap();
+
+    assert_eq!(
+        file_contents(&filesystem, "sourcemap.json"),
+        concat!(
+            r#"{"name":"Game","className":"DataModel","filePaths":["default.project.json"],"#,
+            r#""children":[{"name":"ReplicatedStorage","className":"ReplicatedStorage","#,
+            r#""children":[{"name":"Module","className":"ModuleScript","#,
+            r#""filePaths":["src/ReplicatedStorage/Module.lua"]}]}]}"#,
+        ),
+    );
+}
+
+#[test]
+fn sanitizer_repairs() {
+    use crate::sanitize::*;
+
+    let mut text = String::from(
+        "<float name=\"X\">-nan(ind)</float><string name=\"Info\">inf</string>\
+         <NumberSequence name=\"Size\">0 1 INF 1 0 0 </NumberSequence>",
+    );
+    let repairs = replace_invalid_float_literals(&mut text);
+    assert_eq!(
+        text,
+        "<float name=\"X\">0</float><string name=\"Info\">inf</string>\
+         <NumberSequence name=\"Size\">0 1 0 1 0 0 </NumberSequence>",
+    );
+    assert_eq!(repairs.len(), 2);
+    assert_eq!(repairs[0].kind, RepairKind::InvalidFloat);
+    assert_eq!(repairs[0].byte_offset, 16);
+
+    let mut text = String::from("<string>a&#0;b&#x41;</string>");
+    let repairs = strip_invalid_numeric_char_refs(&mut text);
+    assert_eq!(text, "<string>ab&#x41;</string>");
+    assert_eq!(repairs.len(), 1);
+    assert_eq!(repairs[0].byte_offset, 9);
+    assert_eq!(repairs[0].context_snippet, "<string>a&#0;b&#x41;</string>");
+
+    let mut text = String::from("ok\u{1}ok");
+    let repairs = sanitize_xml(&mut text);
+    assert_eq!(text, "okok");
+    assert_eq!(repairs[0].kind, RepairKind::InvalidCodepoint);
+    assert_eq!(repairs[0].byte_offset, 2);
+
+    // Nothing to do shouldn't touch anything
+    let mut text = String::from("<ProtectedString name=\"Source\">information</ProtectedString>");
+    assert!(replace_invalid_float_literals(&mut text).is_empty());
+    assert!(text.contains("information"));
+}
+
+#[test]
+fn protected_sections() {
+    use crate::sanitize::*;
+
+    let text = concat!(
+        "<SharedString md5=\"a\"/><float>inf</float>",
+        "<BinaryString name=\"A\">inf</BinaryString><BinaryString name=\"B\">nan</BinaryString>",
+        "<SharedStrings><SharedString md5=\"b\">&lt;/BinaryString&gt;inf</SharedString></SharedStrings>",
+    );
+
+    let (protected_text, protected) = protect_shared_sections(text);
+    assert_eq!(
+        protected,
+        vec![
+            "<BinaryString name=\"A\">inf</BinaryString>",
+            "<BinaryString name=\"B\">nan</BinaryString>",
+            "<SharedString md5=\"b\">&lt;/BinaryString&gt;inf</SharedString>",
+        ],
+    );
+    assert_eq!(
+        protected_text,
+        concat!(
+            "<SharedString md5=\"a\"/><float>inf</float>",
+            "__RBX_PROTECTED_CHUNK_0____RBX_PROTECTED_CHUNK_1__",
+            "<SharedStrings>__RBX_PROTECTED_CHUNK_2__</SharedStrings>",
+        ),
+    );
+
+    let mut restored = protected_text;
+    restore_shared_sections(&mut restored, protected);
+    assert_eq!(restored, text);
+}
+
+#[test]
+fn protected_chunks_byte_identical() {
+    // The first decodes to `&#0;` and a null byte, the second has it as is
+    let binary = "<BinaryString name=\"A\">JiMwOwA=</BinaryString>";
+    let raw = "<BinaryString name=\"B\">&#0;nan</BinaryString>";
+    let contents = format!(
+        "<roblox>{}{}<string name=\"Source\">__RBX_PROTECTED_CHUNK_0__&#0;</string>\
+         <float name=\"X\">na&#0;n</float></roblox>",
+        binary, raw,
+    );
+
+    let mut repairs = Vec::new();
+    let sanitized =
+        crate::decode::sanitize_contents(contents, &ConvertOptions::default(), &mut repairs);
+    assert_eq!(
+        sanitized,
+        format!(
+            "<roblox>{}{}<string name=\"Source\">__RBX_PROTECTED_CHUNK_0__</string>\
+             <float name=\"X\">0</float></roblox>",
+            binary, raw,
+        ),
+    );
+    assert_eq!(repairs.len(), 3);
+}
+
+#[test]
+fn repair_offsets_in_original() {
+    use crate::sanitize::RepairKind;
+
+    let contents = String::from(
+        "junk<roblox><SharedStrings><SharedString md5=\"a\">QUFBQQ==</SharedString>\
+         </SharedStrings><string name=\"A\">a&#0;b\u{1}c</string><float name=\"X\">NaN</float>\
+         </roblox>",
+    );
+
+    let mut repairs = Vec::new();
+    crate::decode::sanitize_contents(contents.clone(), &ConvertOptions::default(), &mut repairs);
+
+    // Every offset is in the text as it was handed over, not as the sanitizers left it
+    let found: Vec<_> = repairs
+        .iter()
+        .map(|repair| (repair.kind, &contents[repair.byte_offset..]))
+        .collect();
+    assert_eq!(found.len(), 4);
+    assert!(found[0].1.starts_with("junk"));
+    assert!(found[1].1.starts_with("&#0;b"));
+    assert!(found[2].1.starts_with("\u{1}c"));
+    assert!(found[3].1.starts_with("NaN<"));
+    assert_eq!(
+        found.iter().map(|(kind, _)| *kind).collect::<Vec<_>>(),
+        vec![
+            RepairKind::OutsideRoot,
+            RepairKind::InvalidCharRef,
+            RepairKind::InvalidCodepoint,
+            RepairKind::InvalidFloat,
+        ],
+    );
+}
+
+#[test]
+fn outside_root_junk() {
+    use crate::sanitize::*;
+
+    let mut text = String::from("junk<roblox version=\"4\"></roblox>\0\0\0");
+    let repairs = trim_outside_root(&mut text);
+    assert_eq!(text, "<roblox version=\"4\"></roblox>");
+    assert_eq!(repairs.len(), 2);
+    assert_eq!(repairs[0].kind, RepairKind::OutsideRoot);
+    assert_eq!(repairs[0].byte_offset, 0);
+    assert_eq!(repairs[1].byte_offset, 33);
+
+    // A clean document is left alone, declaration and trailing newline included
+    let clean = "<?xml version=\"1.0\"?>\n<roblox version=\"4\"></roblox>\n";
+    let mut text = String::from(clean);
+    assert!(trim_outside_root(&mut text).is_empty());
+    assert_eq!(text, clean);
+}
+
+#[test]
+fn text_encodings() {
+    use crate::sanitize::*;
+
+    let text = "<roblox>\u{e9}</roblox>";
+    let utf16le: Vec<u8> = [0xFF, 0xFE]
+        .iter()
+        .copied()
+        .chain(text.encode_utf16().flat_map(u16::to_le_bytes))
+        .collect();
+    let utf16be: Vec<u8> = [0xFE, 0xFF]
+        .iter()
+        .copied()
+        .chain(text.encode_utf16().flat_map(u16::to_be_bytes))
+        .collect();
+    let utf8bom: Vec<u8> = [0xEF, 0xBB, 0xBF]
+        .iter()
+        .chain(text.as_bytes())
+        .copied()
+        .collect();
+
+    for (bytes, expected) in vec![
+        (text.as_bytes().to_vec(), TextEncoding::Utf8),
+        (utf8bom, TextEncoding::Utf8Bom),
+        (utf16le, TextEncoding::Utf16Le),
+        (utf16be, TextEncoding::Utf16Be),
+    ] {
+        let encoding = TextEncoding::detect(&bytes);
+        assert_eq!(encoding, expected);
+        assert_eq!(decode_text(bytes, encoding).as_deref(), Some(text));
+    }
+
+    // An unpaired surrogate or a stray byte can't be UTF-16
+    assert!(decode_text(vec![0xFF, 0xFE, 0x00, 0xD8], TextEncoding::Utf16Le).is_none());
+    assert!(decode_text(vec![0xFF, 0xFE, 0x3C], TextEncoding::Utf16Le).is_none());
+}
+
+#[test]
+fn convert_file_to_disk() {
+    let root = std::env::temp_dir().join("rbxlx-to-rojo-convert-file");
+    fs::remove_dir_all(&root).ok();
+    fs::create_dir_all(&root).unwrap();
+
+    let output = root.join("sword");
+    let summary = convert_file(
+        Path::new("./test-files/sword/source.rbxmx"),
+        &output,
+        &ConvertOptions::default(),
+    )
+    .unwrap();
+    assert!(summary.scripts_written > 0);
+
+    let project = fs::read_to_string(output.join("default.project.json")).unwrap();
+    assert!(project.contains("\"name\": \"sword\""));
+
+    // Neither the contents nor the extension say what this is
+    let unknown = root.join("notes.txt");
+    fs::write(&unknown, "not a place").unwrap();
+    assert!(matches!(
+        convert_file(&unknown, &root.join("notes"), &ConvertOptions::default()),
+        Err(ConvertError::UnrecognizedFormat)
+    ));
+
+    fs::remove_dir_all(&root).ok();
+}
+
+#[test]
+fn convert_bytes_in_memory() {
+    let source = fs::read("./test-files/sword/source.rbxmx").expect("couldn't read source.rbxmx");
+
+    let mut filesystem = MemoryFileSystem::new();
+    let summary = convert_bytes(
+        &source,
+        PlaceFormat::XmlModel,
+        &mut filesystem,
+        &ConvertOptions::default(),
+    )
+    .unwrap();
+
+    assert!(summary.scripts_written > 0);
+    assert!(filesystem
+        .files()
+        .contains_key(Path::new("default.project.json")));
+}
+
+#[test]
+fn root_path() {
+    let source = r#"<roblox version="4">
+    <Item class="ReplicatedStorage" referent="RBX0">
+        <Properties>
+            <string name="Name">ReplicatedStorage</string>
+        </Properties>
+        <Item class="Folder" referent="RBX1">
+            <Properties>
+                <string name="Name">Shared</string>
+            </Properties>
+            <Item class="ModuleScript" referent="RBX2">
+                <Properties>
+                    <string name="Name">Util</string>
+                    <ProtectedString name="Source"><![CDATA[return 1]]></ProtectedString>
+                </Properties>
+            </Item>
+        </Item>
+    </Item>
+    <Item class="ServerScriptService" referent="RBX3">
+        <Properties>
+            <string name="Name">ServerScriptService</string>
+        </Properties>
+        <Item class="Script" referent="RBX4">
+            <Properties>
+                <string name="Name">Main</string>
+                <ProtectedString name="Source"><![CDATA[print("hi")]]></ProtectedString>
+            </Properties>
+        </Item>
+    </Item>
+</roblox>"#;
+
+    let mut filesystem = MemoryFileSystem::new();
+    convert_bytes(
+        source.as_bytes(),
+        PlaceFormat::XmlPlace,
+        &mut filesystem,
+        &ConvertOptions::builder()
+            .root_path(Some(String::from("ReplicatedStorage.Shared")))
+            .build(),
+    )
+    .unwrap();
+
+    let files = filesystem.files();
+    assert!(files.contains_key(Path::new("src/Shared/Util.lua")));
+    assert!(!files
+        .keys()
+        .any(|path| path.to_string_lossy().contains("Main")));
+
+    let error = convert_bytes(
+        source.as_bytes(),
+        PlaceFormat::XmlPlace,
+        &mut MemoryFileSystem::new(),
+        &ConvertOptions::builder()
+            .root_path(Some(String::from("ReplicatedStorage.Missing")))
+            .build(),
+    )
+    .unwrap_err();
+    match error {
+        ConvertError::RootPathNotFound {
+            parent,
+            missing,
+            available,
+        } => {
+            assert_eq!(parent, "ReplicatedStorage");
+            assert_eq!(missing, "Missing");
+            assert_eq!(available, vec!["Shared"]);
+        }
+        other => panic!("expected RootPathNotFound, got {}", other),
+    }
+}
+
+#[test]
+fn model_with_two_roots() {
+    let source = r#"<roblox version="4">
+    <Item class="ModuleScript" referent="RBX0">
+        <Properties>
+            <string name="Name">First</string>
+            <ProtectedString name="Source"><![CDATA[return 1]]></ProtectedString>
+        </Properties>
+    </Item>
+    <Item class="Folder" referent="RBX1">
+        <Properties>
+            <string name="Name">Second</string>
+        </Properties>
+        <Item class="Script" referent="RBX2">
+            <Properties>
+                <string name="Name">Main</string>
+                <ProtectedString name="Source"><![CDATA[print("hi")]]></ProtectedString>
+            </Properties>
+        </Item>
+    </Item>
+</roblox>"#;
+
+    let mut filesystem = MemoryFileSystem::new();
+    filesystem.set_project_name("roots");
+    convert_bytes(
+        source.as_bytes(),
+        PlaceFormat::XmlModel,
+        &mut filesystem,
+        &ConvertOptions::default(),
+    )
+    .unwrap();
+
+    let files = filesystem.files();
+    assert!(files.contains_key(Path::new("src/First.lua")));
+    assert!(files.contains_key(Path::new("src/Second/Main.server.lua")));
+
+    let project: serde_json::Value =
+        serde_json::from_slice(&files[Path::new("default.project.json")]).unwrap();
+    assert_eq!(
+        project,
+        serde_json::json!({
+            "name": "roots",
+            "tree": {
+                "$className": "Folder",
+                "First": { "$path": "src/First.lua" },
+                "Second": { "$path": "src/Second" },
+            },
+        }),
+    );
+}
+
+#[test]
+fn place_format_from_extension() {
+    for (extension, format) in &[
+        ("rbxl", PlaceFormat::BinaryPlace),
+        ("rbxm", PlaceFormat::BinaryModel),
+        ("rbxlx", PlaceFormat::XmlPlace),
+        ("rbxmx", PlaceFormat::XmlModel),
+        (".rbxlx", PlaceFormat::XmlPlace),
+        ("RBXM", PlaceFormat::BinaryModel),
+    ] {
+        assert_eq!(
+            PlaceFormat::from_extension(extension),
+            Some(*format),
+            "{}",
+            extension
+        );
+    }
+
+    for extension in &["", "lua", "rbxl.gz", "rbxlxx"] {
+        assert_eq!(
+            PlaceFormat::from_extension(extension),
+            None,
+            "{}",
+            extension
+        );
+    }
+}
+
+#[test]
+fn place_format_sniff() {
+    let encode = |tree: &WeakDom| {
+        let mut bytes = Vec::new();
+        rbx_binary::to_writer(&mut bytes, tree, tree.root().children()).unwrap();
+        bytes
+    };
+
+    let place = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(InstanceBuilder::new("Workspace")),
+    );
+    assert_eq!(
+        PlaceFormat::sniff(&encode(&place)),
+        Some(PlaceFormat::BinaryPlace)
+    );
+
+    // The rest of the file doesn't need to decode
+    let mut truncated = encode(&place);
+    truncated.truncate(truncated.len() - 16);
+    assert_eq!(
+        PlaceFormat::sniff(&truncated),
+        Some(PlaceFormat::BinaryPlace)
+    );
+
+    // Only what's at the root counts, not the name showing up somewhere
+    let model = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("Folder")
+                .with_child(InstanceBuilder::new("StringValue").with_name("Workspace")),
+        ),
+    );
+    assert_eq!(
+        PlaceFormat::sniff(&encode(&model)),
+        Some(PlaceFormat::BinaryModel)
+    );
+
+    for header in &["", "<?xml version=\"1.0\"?>", "\u{feff}"] {
+        let model = format!(
+            "{}<roblox version=\"4\"><Item class=\"Folder\" referent=\"RBX0\"></Item></roblox>",
+            header
+        );
+        assert_eq!(
+            PlaceFormat::sniff(model.as_bytes()),
+            Some(PlaceFormat::XmlModel),
+            "{:?}",
+            header,
+        );
+
+        let place = format!(
+            "{}<roblox version=\"4\"><Item class=\"Workspace\" referent=\"RBX0\"></Item></roblox>",
+            header
+        );
+        assert_eq!(
+            PlaceFormat::sniff(place.as_bytes()),
+            Some(PlaceFormat::XmlPlace),
+            "{:?}",
+            header,
+        );
+    }
+
+    let broken = "<roblox version=\"4\"><Item class=\"Workspace\" referent=\"RBX0\"><Properties><";
+    assert_eq!(
+        PlaceFormat::sniff(broken.as_bytes()),
+        Some(PlaceFormat::XmlPlace)
+    );
+
+    let mut utf16 = vec![0xff, 0xfe];
+    for unit in broken.encode_utf16() {
+        utf16.extend_from_slice(&unit.to_le_bytes());
+    }
+    assert_eq!(PlaceFormat::sniff(&utf16), Some(PlaceFormat::XmlPlace));
+
+    assert_eq!(PlaceFormat::sniff(b"print(\"hello\")"), None);
+    assert_eq!(PlaceFormat::sniff(b""), None);
+}
+
+#[test]
+fn xml_error_location() {
+    let text = "<roblox version=\"4\">\n  <Item class=\"Folder\" referent=\"RBX0\">\n    \
+                <Properties></Oops>\n</roblox>";
+
+    // Where rbx_xml really gave up
+    let location = match decode_file(text.as_bytes(), "rbxmx", &ConvertOptions::default()) {
+        Err(ConvertError::XmlDecode(_, Some(location))) => location,
+        Err(error) => panic!("the wrong error came back: {}", error),
+        Ok(_) => panic!("the malformed file decoded"),
+    };
+    assert_eq!(location.line, 3);
+    assert!(location.snippet.contains("</Oops>"), "{}", location.snippet);
+    assert_eq!(
+        XmlErrorLocation::new(text, location.line, location.column),
+        Some(location),
+    );
+
+    // Positions are found wherever they are in the message, written either way
+    for message in &[
+        "Malformed XML: 3:17 Unexpected closing tag",
+        "line 3, column 17: bad tag",
+    ] {
+        let location = XmlErrorLocation::from_error(message, text).expect("no location");
+        assert_eq!(location.line, 3);
+        assert_eq!(location.column, 17);
+        assert!(text[location.byte_offset..].starts_with("</Oops>"));
+    }
+
+    assert!(XmlErrorLocation::from_error(&"no position here", text).is_none());
+    assert!(XmlErrorLocation::from_error(&"9:1 past the end", text).is_none());
+
+    // A column past the end of the line is the end of the line
+    let location = XmlErrorLocation::new(text, 1, 50).unwrap();
+    assert_eq!(location.byte_offset, "<roblox version=\"4\">".len());
+}
+
+#[test]
+fn gzipped_input() {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    let source = fs::read("./test-files/sword/source.rbxmx").expect("couldn't read source.rbxmx");
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&source).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let mut plain = MemoryFileSystem::new();
+    convert_bytes(
+        &source,
+        PlaceFormat::XmlModel,
+        &mut plain,
+        &ConvertOptions::default(),
+    )
+    .unwrap();
+
+    let mut gzipped = MemoryFileSystem::new();
+    convert_bytes(
+        &compressed,
+        PlaceFormat::XmlModel,
+        &mut gzipped,
+        &ConvertOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(plain.files(), gzipped.files());
+}
+
+#[test]
+fn xml_named_as_binary() {
+    let source = fs::read("./test-files/sword/source.rbxmx").expect("couldn't read source.rbxmx");
+
+    let mut expected = MemoryFileSystem::new();
+    convert_bytes(
+        &source,
+        PlaceFormat::XmlModel,
+        &mut expected,
+        &ConvertOptions::default(),
+    )
+    .unwrap();
+
+    let mut fallback = MemoryFileSystem::new();
+    convert_bytes(
+        &source,
+        PlaceFormat::BinaryModel,
+        &mut fallback,
+        &ConvertOptions::default(),
+    )
+    .expect("XML named as binary should still decode");
+
+    assert_eq!(expected.files(), fallback.files());
+}
+
+#[test]
+fn json_models() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ReplicatedStorage")
+                .with_name("ReplicatedStorage")
+                .with_child(
+                    InstanceBuilder::new("IntValue")
+                        .with_name("Coins")
+                        .with_property("Value", 5i64),
+                )
+                .with_child(
+                    InstanceBuilder::new("BoolValue")
+                        .with_name("Parent")
+                        .with_child(InstanceBuilder::new("BoolValue").with_name("Child")),
+                ),
+        ),
+    );
+
+    let mut filesystem = MemoryFileSystem::new();
+    let summary = process_instructions_with_options(
+        &tree,
+        &mut filesystem,
+        &ConvertOptions {
+            json_models: true,
+            ..ConvertOptions::default()
+        },
+    );
+
+    assert_eq!(
+        file_contents(&filesystem, "src/ReplicatedStorage/Coins.model.json"),
+        "{\n  \"className\": \"IntValue\",\n  \"properties\": {\n    \"Value\": 5\n  }\n}",
+    );
+
+    // Anything with children is written as a whole model instead
+    let files = filesystem.files();
+    assert!(files.contains_key(Path::new("src/ReplicatedStorage/Parent.rbxmx")));
+    assert!(!files.contains_key(Path::new("src/ReplicatedStorage/Child.model.json")));
+    assert_eq!(summary.models_written, 2);
+}
+
+#[test]
+fn sequences_in_json_models() {
+    let model = JsonModel {
+        class_name: String::from("ParticleEmitter"),
+        properties: BTreeMap::from([
+            (
+                String::from("Size"),
+                Variant::NumberSequence(NumberSequence {
+                    keypoints: vec![
+                        NumberSequenceKeypoint::new(0.0, 1.0, 0.0),
+                        NumberSequenceKeypoint::new(1.0, 0.5, 0.25),
+                    ],
+                }),
+            ),
+            (
+                String::from("Color"),
+                Variant::ColorSequence(ColorSequence {
+                    keypoints: vec![
+                        ColorSequenceKeypoint::new(0.0, Color3::new(1.0, 0.5, 0.0)),
+                        ColorSequenceKeypoint::new(1.0, Color3::new(0.0, 0.0, 1.0)),
+                    ],
+                }),
+            ),
+        ]),
+        attributes: BTreeMap::new(),
+        tags: Vec::new(),
+    };
+
+    assert_eq!(
+        serde_json::to_value(&model).unwrap(),
+        serde_json::json!({
+            "className": "ParticleEmitter",
+            "properties": {
+                "Color": {
+                    "ColorSequence": {
+                        "keypoints": [
+                            { "time": 0.0, "color": [1.0, 0.5, 0.0] },
+                            { "time": 1.0, "color": [0.0, 0.0, 1.0] },
+                        ],
+                    },
+                },
+                "Size": {
+                    "NumberSequence": {
+                        "keypoints": [
+                            { "time": 0.0, "value": 1.0, "envelope": 0.0 },
+                            { "time": 1.0, "value": 0.5, "envelope": 0.25 },
+                        ],
+                    },
+                },
+            },
+        }),
+    );
+}
+
+#[test]
+fn geometry_in_json_models() {
+    let model = JsonModel {
+        class_name: String::from("Part"),
+        properties: BTreeMap::from([
+            (
+                String::from("CFrame"),
+                Variant::CFrame(CFrame::new(
+                    Vector3::new(1.0, 2.0, 3.0),
+                    Matrix3::identity(),
+                )),
+            ),
+            (
+                String::from("Size"),
+                Variant::Vector3(Vector3::new(4.0, 1.0, 2.0)),
+            ),
+        ]),
+        attributes: BTreeMap::from([(
+            String::from("Spawn"),
+            Variant::Vector3(Vector3::new(0.0, 5.0, 0.0)),
+        )]),
+        tags: Vec::new(),
+    };
+
+    // Attributes keep their type, since Rojo has nothing to look it up from
+    assert_eq!(
+        serde_json::to_value(&model).unwrap(),
+        serde_json::json!({
+            "className": "Part",
+            "properties": {
+                "CFrame": [1.0, 2.0, 3.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0],
+                "Size": [4.0, 1.0, 2.0],
+            },
+            "attributes": {
+                "Spawn": { "Vector3": [0.0, 5.0, 0.0] },
+            },
+        }),
+    );
+}
+
+#[test]
+fn ref_links() {
+    let target = module_script("Target");
+    let target_ref = target.referent();
+    let part = InstanceBuilder::new("Part").with_name("Part");
+    let part_ref = part.referent();
+
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel")
+            .with_child(
+                InstanceBuilder::new("ReplicatedStorage")
+                    .with_name("ReplicatedStorage")
+                    .with_child(target)
+                    .with_child(
+                        InstanceBuilder::new("ObjectValue")
+                            .with_name("Link")
+                            .with_property("Value", target_ref),
+                    )
+                    .with_child(
+                        InstanceBuilder::new("ObjectValue")
+                            .with_name("Dangling")
+                            .with_property("Value", part_ref),
+                    ),
+            )
+            .with_child(
+                InstanceBuilder::new("Workspace")
+                    .with_name("Workspace")
+                    .with_child(part),
+            ),
+    );
+
+    let mut filesystem = MemoryFileSystem::new();
+    process_instructions_with_options(
+        &tree,
+        &mut filesystem,
+        &ConvertOptions {
+            json_models: true,
+            ..ConvertOptions::default()
+        },
+    );
+
+    assert!(
+        file_contents(&filesystem, "src/ReplicatedStorage/Target.meta.json")
+            .contains("\"Rojo_Id\": \"ReplicatedStorage.Target\"")
+    );
+    assert!(
+        file_contents(&filesystem, "src/ReplicatedStorage/Link.model.json")
+            .contains("\"Rojo_Target_Value\": \"ReplicatedStorage.Target\"")
+    );
+
+    // The Part isn't written, so there's nothing to link to
+    assert!(
+        !file_contents(&filesystem, "src/ReplicatedStorage/Dangling.model.json")
+            .contains("Rojo_Target_Value")
+    );
+}
+
+#[test]
+fn json_modules() {
+    let module = |name: &str, source: &str| {
+        InstanceBuilder::new("ModuleScript")
+            .with_name(name)
+            .with_property("Source", String::from(source))
+    };
+
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ReplicatedStorage")
+                .with_name("ReplicatedStorage")
+                .with_child(module("Data", "return {\"speed\": [16]}\n"))
+                .with_child(module("Number", "return 5;"))
+                .with_child(module("Table", "return { speed = 16 }"))
+                .with_child(module("Returned", "returned = 5")),
+        ),
+    );
+
+    let mut filesystem = MemoryFileSystem::new();
+    let summary = process_instructions_with_options(
+        &tree,
+        &mut filesystem,
+        &ConvertOptions {
+            detect_json_modules: true,
+            ..ConvertOptions::default()
+        },
+    );
+
+    assert_eq!(summary.scripts_written, 4);
+    assert_eq!(
+        file_contents(&filesystem, "src/ReplicatedStorage/Data.json"),
+        "{\"speed\": [16]}",
+    );
+    assert_eq!(
+        file_contents(&filesystem, "src/ReplicatedStorage/Number.json"),
+        "5",
+    );
+
+    // Lua tables and anything else that isn't JSON stay as they are
+    let files = filesystem.files();
+    assert!(files.contains_key(Path::new("src/ReplicatedStorage/Table.lua")));
+    assert!(files.contains_key(Path::new("src/ReplicatedStorage/Returned.lua")));
+}
+
+#[test]
+fn flatten_services() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("Tool")
+                .with_name("Sword")
+                .with_child(module_script("Module")),
+        ),
+    );
+
+    let mut filesystem = MemoryFileSystem::new();
+    filesystem.set_model(true);
+    process_instructions_with_options(
+        &tree,
+        &mut filesystem,
+        &ConvertOptions {
+            flatten_services: true,
+            ..ConvertOptions::default()
+        },
+    );
+
+    let files = filesystem.files();
+    assert!(files.contains_key(Path::new("src/Module.lua")));
+    assert!(file_contents(&filesystem, "src/init.meta.json").contains("\"className\": \"Tool\""));
+    assert!(!filesystem.directories().contains(Path::new("src/Sword")));
+    assert!(file_contents(&filesystem, "default.project.json").contains("\"$path\": \"src\""));
+}
+
+#[test]
+fn binary_fallback() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ReplicatedStorage")
+                .with_name("ReplicatedStorage")
+                .with_child(
+                    InstanceBuilder::new("IntValue")
+                        .with_name("Parent")
+                        .with_child(InstanceBuilder::new("IntValue").with_name("Child")),
+                ),
+        ),
+    );
+
+    let mut filesystem = MemoryFileSystem::new();
+    let summary = process_instructions_with_options(
+        &tree,
+        &mut filesystem,
+        &ConvertOptions {
+            json_models: true,
+            binary_fallback: true,
+            ..ConvertOptions::default()
+        },
+    );
+
+    assert_eq!(summary.models_written, 1);
+    let model = &filesystem.files()[Path::new("src/ReplicatedStorage/Parent.rbxm")];
+    let decoded = rbx_binary::from_reader(&model[..]).expect("couldn't decode the model");
+    assert_eq!(decoded.descendants().count(), 3);
+}
+
+#[test]
+fn model_extension() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ReplicatedStorage")
+                .with_name("ReplicatedStorage")
+                .with_child(
+                    InstanceBuilder::new("IntValue")
+                        .with_name("Parent")
+                        .with_child(InstanceBuilder::new("IntValue").with_name("Child")),
+                ),
+        ),
+    );
+
+    let mut filesystem = MemoryFileSystem::new();
+    let summary = process_instructions_with_options(
+        &tree,
+        &mut filesystem,
+        &ConvertOptions {
+            json_models: true,
+            model_extension: String::from(".model.rbxmx"),
+            ..ConvertOptions::default()
+        },
+    );
+
+    assert_eq!(summary.models_written, 1);
+    assert!(filesystem
+        .files()
+        .contains_key(Path::new("src/ReplicatedStorage/Parent.model.rbxmx")));
+}
+
+#[test]
+fn max_file_bytes() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ReplicatedStorage")
+                .with_name("ReplicatedStorage")
+                .with_child(
+                    InstanceBuilder::new("IntValue")
+                        .with_name("Parent")
+                        .with_child(InstanceBuilder::new("IntValue").with_name("Child")),
+                ),
+        ),
+    );
+
+    let mut filesystem = MemoryFileSystem::new();
+    let summary = process_instructions_with_options(
+        &tree,
+        &mut filesystem,
+        &ConvertOptions {
+            json_models: true,
+            max_file_bytes: Some(16),
+            ..ConvertOptions::default()
+        },
+    );
+
+    assert_eq!(summary.too_large, vec!["ReplicatedStorage.Parent"]);
+    assert!(!filesystem
+        .files()
+        .contains_key(Path::new("src/ReplicatedStorage/Parent.rbxmx")));
+    assert_eq!(
+        file_contents(&filesystem, "src/ReplicatedStorage/Parent.model.json"),
+        "{\n  \"className\": \"IntValue\"\n}",
+    );
+}
+
+#[test]
+fn options_builder() {
+    let options = ConvertOptions::builder()
+        .luau(true)
+        .indent(4)
+        .exclude_classes(vec![String::from("Sound")])
+        .build();
+
+    assert!(options.luau);
+    assert_eq!(options.json_indent, "    ");
+    assert!(options.is_excluded("Sound"));
+
+    // Everything else keeps its default
+    let defaults = ConvertOptions::default();
+    assert_eq!(options.skip_classes, defaults.skip_classes);
+    assert_eq!(options.model_extension, defaults.model_extension);
+    assert_eq!(options.sanitize_floats, defaults.sanitize_floats);
+}
+
+#[test]
+fn class_strategies() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ReplicatedStorage")
+                .with_name("ReplicatedStorage")
+                .with_child(
+                    InstanceBuilder::new("StringValue")
+                        .with_name("Greeting")
+                        .with_property("Value", String::from("hi")),
+                )
+                .with_child(InstanceBuilder::new("IntValue").with_name("Count"))
+                .with_child(
+                    InstanceBuilder::new("Model")
+                        .with_name("Thing")
+                        .with_child(module_script("Inner")),
+                ),
+        ),
+    );
+
+    let strategies: BTreeMap<String, ClassStrategy> = serde_json::from_str(
+        r#"{"StringValue": "txt", "IntValue": "json-model", "Model": "rbxmx"}"#,
+    )
+    .unwrap();
+    let options = ConvertOptions {
+        class_strategies: strategies,
+        ..ConvertOptions::default()
+    };
+    assert_eq!(options.misfit_strategy(), None);
+
+    let mut filesystem = MemoryFileSystem::new();
+    process_instructions_with_options(&tree, &mut filesystem, &options);
+
+    let files = filesystem.files();
+    assert_eq!(
+        file_contents(&filesystem, "src/ReplicatedStorage/Greeting.txt"),
+        "hi"
+    );
+    assert!(files.contains_key(Path::new("src/ReplicatedStorage/Count.model.json")));
+    assert!(files.contains_key(Path::new("src/ReplicatedStorage/Thing.rbxmx")));
+    assert!(!files.contains_key(Path::new("src/ReplicatedStorage/Thing/Inner.lua")));
+
+    assert!(
+        serde_json::from_str::<BTreeMap<String, ClassStrategy>>(r#"{"IntValue": "yaml"}"#).is_err()
+    );
+    let misfit = ConvertOptions {
+        class_strategies: vec![(String::from("IntValue"), ClassStrategy::Csv)]
+            .into_iter()
+            .collect(),
+        ..ConvertOptions::default()
+    };
+    assert_eq!(
+        misfit.misfit_strategy(),
+        Some(("IntValue", ClassStrategy::Csv))
+    );
+}
+
+#[test]
+fn unsupported_report() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ReplicatedStorage")
+                .with_name("ReplicatedStorage")
+                .with_child(
+                    InstanceBuilder::new("IntValue")
+                        .with_name("Parent")
+                        .with_child(InstanceBuilder::new("IntValue").with_name("Child")),
+                )
+                .with_child(InstanceBuilder::new("IntValue").with_name("Plain")),
+        ),
+    );
+
+    let summary = process_instructions_with_options(
+        &tree,
+        &mut MemoryFileSystem::new(),
+        &ConvertOptions {
+            json_models: true,
+            ..ConvertOptions::default()
+        },
+    );
+
+    let mut expected = BTreeMap::new();
+    expected.insert(
+        String::from("IntValue"),
+        UnsupportedClass {
+            count: 1,
+            examples: vec![String::from("ReplicatedStorage.Parent")],
+        },
+    );
+    assert_eq!(summary.unsupported_report(), expected);
+
+    // Models that were asked for aren't a fallback
+    let summary = process_instructions_with_options(
+        &tree,
+        &mut MemoryFileSystem::new(),
+        &ConvertOptions {
+            class_strategies: vec![(String::from("IntValue"), ClassStrategy::Rbxmx)]
+                .into_iter()
+                .collect(),
+            ..ConvertOptions::default()
+        },
+    );
+    assert!(summary.unsupported_report().is_empty());
+}
+
+#[test]
+fn dedupe_shared_strings() {
+    let mesh = SharedString::new(b"mesh data".to_vec());
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("Workspace")
+                .with_name("Workspace")
+                .with_child(
+                    InstanceBuilder::new("MeshPart")
+                        .with_name("A")
+                        .with_property("PhysicalConfigData", mesh.clone()),
+                )
+                .with_child(
+                    InstanceBuilder::new("MeshPart")
+                        .with_name("B")
+                        .with_property("PhysicalConfigData", mesh),
+                )
+                .with_child(
+                    InstanceBuilder::new("MeshPart")
+                        .with_name("C")
+                        .with_property("PhysicalConfigData", SharedString::new(b"other".to_vec())),
+                )
+                .with_child(
+                    InstanceBuilder::new("Part")
+                        .with_name("Unwritten")
+                        .with_property("PhysicalConfigData", SharedString::new(b"left".to_vec())),
+                ),
+        ),
+    );
+
+    let mut filesystem = MemoryFileSystem::new();
+    let summary = process_instructions_with_options(
+        &tree,
+        &mut filesystem,
+        &ConvertOptions {
+            dedupe_shared_strings: true,
+            class_strategies: vec![(String::from("MeshPart"), ClassStrategy::Rbxmx)]
+                .into_iter()
+                .collect(),
+            ..ConvertOptions::default()
+        },
+    );
+
+    let shared_strings = summary.shared_strings.expect("no shared strings collected");
+    assert_eq!(shared_strings.payloads.len(), 2);
+    assert!(!shared_strings.uses.contains_key("Workspace.Unwritten"));
+    assert_eq!(
+        shared_strings.uses["Workspace.A"]["PhysicalConfigData"],
+        shared_strings.uses["Workspace.B"]["PhysicalConfigData"],
+    );
+    assert_ne!(
+        shared_strings.uses["Workspace.A"]["PhysicalConfigData"],
+        shared_strings.uses["Workspace.C"]["PhysicalConfigData"],
+    );
+
+    // The model only points at the payload
+    let hash = &shared_strings.uses["Workspace.A"]["PhysicalConfigData"];
+    let model =
+        rbx_xml::from_reader_default(&filesystem.files()[Path::new("src/Workspace/A.rbxmx")][..])
+            .unwrap();
+    let part = model.get_by_ref(model.root().children()[0]).unwrap();
+    match part.properties.get(&ustr("PhysicalConfigData")) {
+        Some(Variant::SharedString(value)) => {
+            assert_eq!(value.data(), format!("shared/{}.bin", hash).as_bytes())
+        }
+        other => panic!("PhysicalConfigData is {:?}", other),
+    }
+
+    filesystem.write_shared_strings(&shared_strings).unwrap();
+    assert_eq!(
+        file_contents(&filesystem, &format!("shared/{}.bin", hash)),
+        "mesh data",
+    );
+    assert!(filesystem
+        .files()
+        .contains_key(Path::new("shared/index.json")));
+}
+
+#[test]
+fn max_depth() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ReplicatedStorage")
+                .with_name("ReplicatedStorage")
+                .with_child(
+                    InstanceBuilder::new("Folder")
+                        .with_name("Outer")
+                        .with_child(
+                            InstanceBuilder::new("Folder")
+                                .with_name("Inner")
+                                .with_child(module_script("Module")),
+                        ),
+                )
+                .with_child(module_script("Shallow")),
+        ),
+    );
+
+    let process = |max_depth: usize| {
+        let mut filesystem = MemoryFileSystem::new();
+        let summary = process_instructions_with_options(
+            &tree,
+            &mut filesystem,
+            &ConvertOptions {
+                max_depth: Some(max_depth),
+                ..ConvertOptions::default()
+            },
+        );
+        (filesystem, summary)
+    };
+
+    // Services are always walked, so it's what's inside them that gets collapsed
+    let (filesystem, summary) = process(0);
+    assert_eq!(summary.collapsed, 1);
+    let files = filesystem.files();
+    assert!(files.contains_key(Path::new("src/ReplicatedStorage/Outer.rbxmx")));
+    assert!(files.contains_key(Path::new("src/ReplicatedStorage/Shallow.lua")));
+    assert!(!filesystem
+        .directories()
+        .contains(Path::new("src/ReplicatedStorage/Outer")));
+
+    let model =
+        rbx_xml::from_reader_default(&files[Path::new("src/ReplicatedStorage/Outer.rbxmx")][..])
+            .expect("couldn't decode the collapsed model");
+    assert_eq!(model.descendants().count(), 4);
+
+    let (filesystem, summary) = process(2);
+    assert_eq!(summary.collapsed, 1);
+    assert!(filesystem
+        .files()
+        .contains_key(Path::new("src/ReplicatedStorage/Outer/Inner.rbxmx")));
+
+    let (filesystem, summary) = process(3);
+    assert_eq!(summary.collapsed, 0);
+    assert!(filesystem
+        .files()
+        .contains_key(Path::new("src/ReplicatedStorage/Outer/Inner/Module.lua")));
+}
+
+#[test]
+fn empty_folders_left_out() {
+    let mut filesystem = MemoryFileSystem::new();
+    filesystem.read_instructions(vec![
+        Instruction::CreateFolder {
+            folder: Path::new("Empty").into(),
+        },
+        Instruction::CreateFolder {
+            folder: Path::new("Outer").into(),
+        },
+        Instruction::CreateFolder {
+            folder: Path::new("Outer/Inner").into(),
+        },
+        Instruction::CreateFile {
+            filename: Path::new("Outer/Inner/init.meta.json").into(),
+            contents: b"{}"[..].into(),
+        },
+        // The project file points at this one, so it has to be there even though it's empty
+        Instruction::AddToTree {
+            name: String::from("ServerStorage"),
+            partition: TreePartition {
+                class_name: String::from("ServerStorage"),
+                children: BTreeMap::new(),
+                ignore_unknown_instances: true,
+                path: Some(PathBuf::from("ServerStorage")),
+            },
+        },
+        Instruction::CreateFolder {
+            folder: Path::new("ServerStorage").into(),
+        },
+    ]);
+    filesystem.finish_instructions();
+
+    let directories = filesystem.directories();
+    assert!(!directories.contains(Path::new("src/Empty")));
+    assert!(directories.contains(Path::new("src/Outer/Inner")));
+    assert!(directories.contains(Path::new("src/ServerStorage")));
+}
+
+#[cfg(feature = "zip")]
+#[test]
+fn zip_archive() {
+    use crate::filesystem::Zip;
+    use std::io::{Cursor, Read};
+
+    let source =
+        fs::read_to_string("./test-files/sword/source.rbxmx").expect("couldn't read source.rbxmx");
+    let tree = rbx_xml::from_str_default(&source).expect("couldn't deserialize source.rbxmx");
+
+    let folder = process_in_memory(&tree);
+
+    let mut filesystem =
+        FileSystem::with_backend(PathBuf::new(), Zip::new(Cursor::new(Vec::new())));
+    process_instructions(&tree, &mut filesystem);
+    let archive = filesystem.into_backend().finish().unwrap();
+
+    let mut archive = zip::ZipArchive::new(archive).expect("couldn't read the archive");
+    let mut files = BTreeMap::new();
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index).unwrap();
+        if file.is_file() {
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents).unwrap();
+            files.insert(PathBuf::from(file.name()), contents);
+        }
+    }
+
+    assert_eq!(&files, folder.files());
+
+    // Running out of room is kept for `finish` instead of panicking
+    let mut buffer = [0; 64];
+    let mut filesystem =
+        FileSystem::with_backend(PathBuf::new(), Zip::new(Cursor::new(&mut buffer[..])));
+    process_instructions(&tree, &mut filesystem);
+    assert!(filesystem.into_backend().finish().is_err());
+}
+
+#[test]
+fn planned_instructions() {
+    let source =
+        fs::read_to_string("./test-files/sword/source.rbxmx").expect("couldn't read source.rbxmx");
+    let tree = rbx_xml::from_str_default(&source).expect("couldn't deserialize source.rbxmx");
+
+    let (plan, summary) = plan_instructions(&tree, &ConvertOptions::default());
+    assert!(plan
+        .iter()
+        .any(|instruction| matches!(instruction, Instruction::CreateFile { .. })));
+
+    // Carrying out the plan writes just what converting straight away does
+    let mut filesystem = MemoryFileSystem::new();
+    filesystem.read_instructions(plan);
+    filesystem.finish_instructions();
+
+    let mut direct = MemoryFileSystem::new();
+    let direct_summary = process_instructions(&tree, &mut direct);
+    assert_eq!(filesystem.files(), direct.files());
+    assert_eq!(summary.scripts_written, direct_summary.scripts_written);
+}
+
+#[test]
+fn merge_into_existing() {
+    let source =
+        fs::read_to_string("./test-files/sword/source.rbxmx").expect("couldn't read source.rbxmx");
+    let tree = rbx_xml::from_str_default(&source).expect("couldn't deserialize source.rbxmx");
+
+    let mut first = MemoryFileSystem::new();
+    first.set_merge(true, false);
+    process_instructions(&tree, &mut first);
+    let report = first.merge_report().unwrap().clone();
+    assert_eq!(report.added, first.files().len());
+    assert_eq!(report.changed + report.unchanged, 0);
+
+    let written = first.files().clone();
+    let mut backend = first.into_backend();
+    let stale = PathBuf::from("src/Stale.lua");
+    backend.write(&stale, b"print('gone')").unwrap();
+
+    // Converting the same place again writes nothing, and prunes what the place doesn't have
+    let mut second = FileSystem::with_backend(PathBuf::new(), backend);
+    second.set_merge(true, true);
+    process_instructions(&tree, &mut second);
+    assert_eq!(
+        second.merge_report(),
+        Some(&MergeReport {
+            added: 0,
+            changed: 0,
+            unchanged: written.len(),
+            removed: vec![stale],
+        })
+    );
+    assert_eq!(second.files(), &written);
+
+    // Forks can't see what's already there, but what they write is still compared
+    let mut parallel = FileSystem::with_backend(PathBuf::new(), second.into_backend());
+    parallel.set_merge(true, false);
+    process_instructions_parallel(&tree, &mut parallel, &ConvertOptions::default(), 4);
+    let report = parallel.merge_report().unwrap();
+    assert_eq!((report.added, report.changed), (0, 0));
+    assert_eq!(report.unchanged, written.len());
+}
+
+#[test]
+fn verify_writes() {
+    // Drops the last byte of every file, like a flaky drive might
+    #[derive(Default)]
+    struct Truncating(BTreeMap<PathBuf, Vec<u8>>);
+
+    impl Backend for Truncating {
+        fn create_dir_all(&mut self, _path: &Path) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn write(&mut self, path: &Path, contents: &[u8]) -> std::io::Result<()> {
+            let kept = &contents[..contents.len().saturating_sub(1)];
+            self.0.insert(path.to_path_buf(), kept.to_vec());
+            Ok(())
+        }
+
+        fn read(&mut self, path: &Path) -> std::io::Result<Option<Vec<u8>>> {
+            Ok(self.0.get(path).cloned())
+        }
+
+        fn can_read(&self) -> bool {
+            true
+        }
+    }
+
+    let mut filesystem = MemoryFileSystem::new();
+    filesystem.set_verify(true).unwrap();
+    filesystem
+        .write_root_file("selene.toml", b"std = \"roblox\"")
+        .unwrap();
+
+    let mut flaky = FileSystem::with_backend(PathBuf::new(), Truncating::default());
+    flaky.set_verify(true).unwrap();
+    let error = flaky
+        .write_root_file("selene.toml", b"std = \"roblox\"")
+        .unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert!(error.to_string().contains("selene.toml"));
+
+    // Nothing looks wrong without reading it back
+    flaky.set_verify(false).unwrap();
+    assert!(flaky
+        .write_root_file("selene.toml", b"std = \"roblox\"")
+        .is_ok());
+
+    // Which some backends can't do at all
+    let mut dry_run = FileSystem::with_backend(PathBuf::new(), DryRun);
+    assert!(dry_run.set_verify(true).is_err());
+    assert!(dry_run.set_verify(false).is_ok());
+}
+
+#[test]
+fn root_file_if_missing() {
+    let mut filesystem = MemoryFileSystem::new();
+    filesystem
+        .write_root_file("selene.toml", b"std = \"custom\"")
+        .unwrap();
+
+    assert!(!filesystem
+        .write_root_file_if_missing("selene.toml", b"std = \"roblox\"")
+        .unwrap());
+    assert!(filesystem
+        .write_root_file_if_missing(".luaurc", b"{}")
+        .unwrap());
+
+    let files = filesystem.files();
+    assert_eq!(files[Path::new("selene.toml")], b"std = \"custom\"");
+    assert_eq!(files[Path::new(".luaurc")], b"{}");
+}
+
+#[test]
+fn services_only() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel")
+            .with_child(
+                InstanceBuilder::new("ReplicatedStorage")
+                    .with_name("ReplicatedStorage")
+                    .with_child(
+                        InstanceBuilder::new("Folder")
+                            .with_name("Shared")
+                            .with_child(module_script("Module")),
+                    ),
+            )
+            .with_child(InstanceBuilder::new("ServerStorage").with_name("ServerStorage"))
+            .with_child(InstanceBuilder::new("Folder").with_name("NotAService")),
+    );
+
+    let mut filesystem = MemoryFileSystem::new();
+    let summary = process_instructions_with_options(
+        &tree,
+        &mut filesystem,
+        &ConvertOptions {
+            services_only: true,
+            ..ConvertOptions::default()
+        },
+    );
+    assert_eq!(summary.scripts_written, 0);
+    assert_eq!(summary.folders_created, 2);
+
+    let files = filesystem.files();
+    for service in &["ReplicatedStorage", "ServerStorage"] {
+        let meta = &files[&Path::new("src").join(service).join("init.meta.json")];
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(meta).unwrap(),
+            serde_json::json!({ "ignoreUnknownInstances": true }),
+        );
+    }
+
+    assert!(!filesystem
+        .directories()
+        .contains(Path::new("src/ReplicatedStorage/Shared")));
+    assert!(!filesystem
+        .directories()
+        .contains(Path::new("src/NotAService")));
+}
+
+#[test]
+fn skip_classes() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("Workspace")
+                .with_name("Workspace")
+                .with_child(
+                    InstanceBuilder::new("Camera")
+                        .with_name("Camera")
+                        .with_child(module_script("CameraModule")),
+                )
+                .with_child(InstanceBuilder::new("Terrain").with_name("Terrain"))
+                .with_child(module_script("Module")),
+        ),
+    );
+
+    let mut filesystem = MemoryFileSystem::new();
+    let summary = process_instructions(&tree, &mut filesystem);
+    assert_eq!(summary.skipped, vec![String::from("Camera")]);
+    assert_eq!(summary.scripts_written, 1);
+    assert!(!filesystem
+        .directories()
+        .contains(Path::new("src/Workspace/Camera")));
+
+    let mut filesystem = MemoryFileSystem::new();
+    let summary = process_instructions_with_options(
+        &tree,
+        &mut filesystem,
+        &ConvertOptions {
+            skip_classes: Vec::new(),
+            ..ConvertOptions::default()
+        },
+    );
+    assert!(summary.skipped.is_empty());
+    assert!(filesystem
+        .files()
+        .contains_key(Path::new("src/Workspace/Camera/CameraModule.lua")));
+}
+
+#[test]
+fn package_links() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("ReplicatedStorage")
+                .with_name("ReplicatedStorage")
+                .with_child(
+                    InstanceBuilder::new("Model")
+                        .with_name("Package")
+                        .with_child(InstanceBuilder::new("PackageLink").with_name("PackageLink"))
+                        .with_child(module_script("Module")),
+                ),
+        ),
+    );
+
+    let mut filesystem = MemoryFileSystem::new();
+    let summary = process_instructions_with_options(
+        &tree,
+        &mut filesystem,
+        &ConvertOptions {
+            include_classes: vec![String::from("ModuleScript"), String::from("PackageLink")],
+            ..ConvertOptions::default()
+        },
+    );
+    assert_eq!(summary.skipped, vec![String::from("PackageLink")]);
+
+    let package_files: Vec<&Path> = filesystem
+        .files()
+        .keys()
+        .filter(|path| path.starts_with("src/ReplicatedStorage/Package"))
+        .map(|path| path.as_path())
+        .collect();
+    assert_eq!(
+        package_files,
+        vec![
+            Path::new("src/ReplicatedStorage/Package/Module.lua"),
+            Path::new("src/ReplicatedStorage/Package/init.meta.json"),
+        ],
+    );
+}
+
+#[test]
+fn wally_manifest() {
+    let tree = |index_name: &str| {
+        WeakDom::new(
+            InstanceBuilder::new("DataModel").with_child(
+                InstanceBuilder::new("ReplicatedStorage")
+                    .with_name("ReplicatedStorage")
+                    .with_child(
+                        InstanceBuilder::new("Folder")
+                            .with_name("Packages")
+                            .with_child(InstanceBuilder::new("Folder").with_name(index_name)),
+                    )
+                    .with_child(module_script("Module")),
+            ),
+        )
+    };
+    let options = ConvertOptions {
+        wally: true,
+        ..ConvertOptions::default()
+    };
+
+    let mut filesystem = MemoryFileSystem::new();
+    let summary = process_instructions_with_options(&tree("NotIndex"), &mut filesystem, &options);
+    assert!(!summary.wally_packages);
+
+    let mut filesystem = MemoryFileSystem::new();
+    filesystem.set_project_name("My Game!");
+    let summary = process_instructions_with_options(&tree("_Index"), &mut filesystem, &options);
+    assert!(summary.wally_packages);
+
+    filesystem.write_wally_manifest().unwrap();
+    assert_eq!(
+        file_contents(&filesystem, "wally.toml"),
+        "[package]\nname = \"my-game/my-game\"\nversion = \"0.1.0\"\n\
+         registry = \"https://github.com/UpliftGames/wally-index\"\nrealm = \"shared\"\n\n\
+         [dependencies]\n",
+    );
+}
+
+#[test]
+fn json_indent() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel")
+            .with_child(InstanceBuilder::new("ServerStorage").with_name("ServerStorage")),
+    );
+
+    let mut filesystem = MemoryFileSystem::new();
+    filesystem.set_json_indent("\t");
+    process_instructions_with_options(
+        &tree,
+        &mut filesystem,
+        &ConvertOptions {
+            services_only: true,
+            json_indent: String::from("\t"),
+            ..ConvertOptions::default()
+        },
+    );
+
+    assert_eq!(
+        file_contents(&filesystem, "src/ServerStorage/init.meta.json"),
+        "{\n\t\"ignoreUnknownInstances\": true\n}",
+    );
+    assert!(file_contents(&filesystem, "default.project.json").starts_with("{\n\t\"name\""));
+}
+
+#[test]
+fn assets_manifest() {
+    let tree = WeakDom::new(
+        InstanceBuilder::new("DataModel").with_child(
+            InstanceBuilder::new("Workspace")
+                .with_name("Workspace")
+                .with_child(
+                    InstanceBuilder::new("Sound")
+                        .with_name("Music")
+                        .with_property("SoundId", ContentId::from("rbxassetid://123")),
+                )
+                .with_child(
+                    InstanceBuilder::new("Sound")
+                        .with_name("Silence")
+                        .with_property("SoundId", ContentId::from("")),
+                ),
+        ),
+    );
+
+    let mut filesystem = MemoryFileSystem::new();
+    let summary = process_instructions_with_options(
+        &tree,
+        &mut filesystem,
+        &ConvertOptions {
+            assets_manifest: true,
+            ..ConvertOptions::default()
+        },
+    );
+
+    let mut expected = AssetManifest::new();
+    expected.insert(
+        "Workspace.Music".to_string(),
+        vec![("SoundId".to_string(), "rbxassetid://123".to_string())]
+            .into_iter()
+            .collect(),
+    );
+    assert_eq!(summary.assets, Some(expected));
+}